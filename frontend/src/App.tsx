@@ -1,5 +1,6 @@
 import { useState, useEffect } from 'react'
 import { invoke } from '@tauri-apps/api/tauri'
+import { listen } from '@tauri-apps/api/event'
 import ChatArea from './components/ChatArea/ChatArea'
 import Settings from './components/Settings/Settings'
 import PartyMembers from './components/PartyMembers/PartyMembers'
@@ -33,12 +34,86 @@ export interface User {
   isInCall?: boolean
 }
 
+export type RoomEvent =
+  | { kind: 'user_joined'; user_id: string; name: string; approved_by?: string }
+  | { kind: 'user_left'; user_id: string; name: string }
+  | { kind: 'join_denied'; user_id: string; name: string; denied_by: string; reason?: string }
+  | { kind: 'call_joined'; user_id: string; name: string }
+  | { kind: 'call_left'; user_id: string; name: string }
+  | { kind: 'call_started'; started_by: string }
+  | { kind: 'call_ended' }
+  | { kind: 'protocol_changed'; protocol: string; changed_by: string }
+  | { kind: 'server_elected'; user_id?: string }
+  | { kind: 'role_changed'; user_id: string; role: string; changed_by: string }
+  | { kind: 'room_renamed'; name: string; renamed_by: string }
+  | {
+      kind: 'moderated'
+      target_user_id: string
+      target_name: string
+      action: string | { [action: string]: unknown }
+      issued_by: string
+      reason?: string
+    }
+
 export interface Message {
   id: string
   userId: string
   userName: string
   content: string
   timestamp: string
+  // Set on timeline entries recording something that happened in the room
+  event?: RoomEvent
+  // Resolved by the backend against the room's members
+  mentions?: string[]
+  mentions_everyone?: boolean
+  attachment?: FileManifest
+}
+
+export interface FileManifest {
+  id: string
+  name: string
+  size: number
+  sha256: string
+  chunk_size: number
+  chunks: string[]
+  image?: ImagePreview
+}
+
+// The full image is fetched like any other file; the thumbnail comes inline
+export interface ImagePreview {
+  mime: string
+  width: number
+  height: number
+  thumbnail: string
+  thumbnail_width: number
+  thumbnail_height: number
+}
+
+export interface TransferProgress {
+  room_id: string
+  file_id: string
+  name: string
+  received_bytes: number
+  size: number
+  complete: boolean
+  path?: string
+  error?: string
+}
+
+export interface MessageNotification {
+  room_id: string
+  room_name: string
+  channel_id: string
+  message_id: string
+  user_name: string
+  body: string
+  mention: boolean
+}
+
+export interface Typist {
+  user_id: string
+  name: string
+  channel_id: string
 }
 
 function App() {
@@ -51,6 +126,7 @@ function App() {
   const [inviteCode, setInviteCode] = useState('')
   const [isMuted, setIsMuted] = useState(false)
   const [isInCall, setIsInCall] = useState(false)
+  const [typing, setTyping] = useState<{ [roomId: string]: Typist[] }>({})
 
   useEffect(() => {
     // Check for saved name on app start
@@ -60,6 +136,31 @@ function App() {
     }
   }, [])
 
+  useEffect(() => {
+    const unlisten = listen<{ room_id: string; typing: Typist[] }>('typing-changed', event => {
+      setTyping(current => ({ ...current, [event.payload.room_id]: event.payload.typing }))
+    })
+    return () => {
+      unlisten.then(stop => stop())
+    }
+  }, [])
+
+  useEffect(() => {
+    // The backend already applied the notification policy
+    const unlisten = listen<MessageNotification>('notification', event => {
+      const { room_name, user_name, body } = event.payload
+      if (!('Notification' in window)) return
+      if (Notification.permission === 'granted') {
+        new Notification(`${user_name} in ${room_name}`, { body })
+      } else if (Notification.permission === 'default') {
+        Notification.requestPermission()
+      }
+    })
+    return () => {
+      unlisten.then(stop => stop())
+    }
+  }, [])
+
   const initializeUser = async (name: string) => {
     try {
       const userId = `user-${Date.now()}-${Math.floor(Math.random() * 10000)}`
@@ -91,7 +192,8 @@ function App() {
   const handleStartParty = async () => {
     try {
       const newParty = await invoke<Party>('create_party', { name: 'Party' })
-      const inviteCode = await invoke<string>('generate_invite')
+      await invoke('focus_party', { roomId: newParty.id })
+      const inviteCode = await invoke<string>('generate_invite', { roomId: newParty.id })
 
       setCurrentParty({ ...newParty, invite_code: inviteCode })
     } catch (error) {
@@ -103,6 +205,7 @@ function App() {
   const handleJoinParty = async (inviteCode: string) => {
     try {
       const joinedParty = await invoke<Party>('join_party', { inviteCode })
+      await invoke('focus_party', { roomId: joinedParty.id })
       setCurrentParty(joinedParty)
       setShowJoinModal(false)
       setInviteCode('')
@@ -117,6 +220,7 @@ function App() {
 
     try {
       await invoke('send_message', {
+        roomId: currentParty.id,
         content,
       })
 
@@ -126,9 +230,21 @@ function App() {
     }
   }
 
+  const handleTyping = async (isTyping: boolean) => {
+    if (!currentParty) return
+
+    try {
+      await invoke('set_typing', { roomId: currentParty.id, typing: isTyping })
+    } catch (error) {
+      console.error('Failed to send typing state:', error)
+    }
+  }
+
   const refreshCurrentParty = async () => {
+    if (!currentParty) return
+
     try {
-      const updatedParty = await invoke<Party | null>('get_current_party')
+      const updatedParty = await invoke<Party>('get_party', { roomId: currentParty.id })
       if (updatedParty) {
         setCurrentParty({ ...updatedParty, invite_code: currentParty?.invite_code })
       }
@@ -138,8 +254,10 @@ function App() {
   }
 
   const handleLeaveParty = async () => {
+    if (!currentParty) return
+
     try {
-      await invoke('leave_party')
+      await invoke('leave_party', { roomId: currentParty.id })
       setCurrentParty(null)
       setIsInCall(false)
     } catch (error) {
@@ -176,7 +294,7 @@ function App() {
     if (!currentParty) return
 
     try {
-      await invoke('join_call')
+      await invoke('join_call', { roomId: currentParty.id })
       setIsInCall(true)
       await refreshCurrentParty()
     } catch (error) {
@@ -189,7 +307,7 @@ function App() {
     if (!currentParty) return
 
     try {
-      await invoke('leave_call')
+      await invoke('leave_call', { roomId: currentParty.id })
       setIsInCall(false)
       await refreshCurrentParty()
     } catch (error) {
@@ -268,6 +386,8 @@ function App() {
               onJoinCall={handleJoinCall}
               onLeaveCall={handleLeaveCall}
               onSendMessage={handleSendMessage}
+              typing={typing[currentParty.id] || []}
+              onTyping={handleTyping}
             />
           ) : (
             <div className={styles.welcomeScreen}>
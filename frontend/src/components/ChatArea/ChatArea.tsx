@@ -1,5 +1,7 @@
 import React, { useState, useEffect, useRef } from 'react'
-import { Party, User, Message } from '../../App'
+import { invoke } from '@tauri-apps/api/tauri'
+import { listen } from '@tauri-apps/api/event'
+import { Party, User, Message, RoomEvent, Typist, FileManifest, TransferProgress } from '../../App'
 import styles from './ChatArea.module.css'
 
 interface ChatAreaProps {
@@ -9,6 +11,8 @@ interface ChatAreaProps {
   onJoinCall: () => void
   onLeaveCall: () => void
   onSendMessage: (content: string) => void
+  typing: Typist[]
+  onTyping: (typing: boolean) => void
 }
 
 function ChatArea({
@@ -18,9 +22,12 @@ function ChatArea({
   onSendMessage,
   onJoinCall,
   onLeaveCall,
+  typing,
+  onTyping,
 }: ChatAreaProps) {
   const [messages, setMessages] = useState<Message[]>([])
   const [messageInput, setMessageInput] = useState('')
+  const [transfers, setTransfers] = useState<{ [fileId: string]: TransferProgress }>({})
   const messagesEndRef = useRef<HTMLDivElement>(null)
 
   useEffect(() => {
@@ -33,6 +40,63 @@ function ChatArea({
     scrollToBottom()
   }, [messages])
 
+  useEffect(() => {
+    const unlisten = listen<TransferProgress>('file-progress', event => {
+      setTransfers(current => ({ ...current, [event.payload.file_id]: event.payload }))
+    })
+    return () => {
+      unlisten.then(stop => stop())
+    }
+  }, [])
+
+  const handleDownload = async (file: FileManifest) => {
+    try {
+      const progress = await invoke<TransferProgress>('download_file', { roomId: room.id, fileId: file.id })
+      setTransfers(current => ({ ...current, [file.id]: current[file.id] ?? progress }))
+    } catch (error) {
+      console.error('Failed to download file:', error)
+    }
+  }
+
+  const formatSize = (bytes: number) => {
+    if (bytes < 1024) return `${bytes} B`
+    if (bytes < 1024 * 1024) return `${(bytes / 1024).toFixed(1)} KB`
+    return `${(bytes / (1024 * 1024)).toFixed(1)} MB`
+  }
+
+  const renderAttachment = (message: Message, file: FileManifest) => {
+    const transfer = transfers[file.id]
+    let status: React.ReactNode
+    if (message.userId === currentUser?.id) {
+      status = null
+    } else if (transfer?.error) {
+      status = <button onClick={() => handleDownload(file)}>Retry</button>
+    } else if (transfer?.complete) {
+      status = <span>Saved to {transfer.path}</span>
+    } else if (transfer) {
+      status = <span>{Math.floor((transfer.received_bytes / Math.max(transfer.size, 1)) * 100)}%</span>
+    } else {
+      status = <button onClick={() => handleDownload(file)}>Download</button>
+    }
+
+    return (
+      <div className={styles.attachment}>
+        {file.image && (
+          <img
+            className={styles.thumbnail}
+            src={`data:image/jpeg;base64,${file.image.thumbnail}`}
+            width={file.image.thumbnail_width}
+            height={file.image.thumbnail_height}
+            alt={file.name}
+          />
+        )}
+        <span className={styles.attachmentName}>{file.name}</span>
+        <span className={styles.attachmentSize}>{formatSize(file.size)}</span>
+        {status}
+      </div>
+    )
+  }
+
   const scrollToBottom = () => {
     messagesEndRef.current?.scrollIntoView({ behavior: 'smooth' })
   }
@@ -42,6 +106,7 @@ function ChatArea({
     if (messageInput.trim() && currentUser) {
       onSendMessage(messageInput.trim())
       setMessageInput('')
+      onTyping(false)
     }
   }
 
@@ -62,6 +127,51 @@ function ChatArea({
     }
   }
 
+  const nameOf = (userId?: string) => (userId && room.users[userId]?.name) || 'Someone'
+
+  const isMentioned = (message: Message) =>
+    !!message.mentions_everyone || (!!currentUser && !!message.mentions?.includes(currentUser.id))
+
+  const describeEvent = (event: RoomEvent) => {
+    switch (event.kind) {
+      case 'user_joined':
+        return event.approved_by
+          ? `${event.name} was let in by ${nameOf(event.approved_by)}`
+          : `${event.name} joined the party`
+      case 'user_left':
+        return `${event.name} left the party`
+      case 'join_denied':
+        return `${event.name} was denied entry by ${nameOf(event.denied_by)}${event.reason ? `: ${event.reason}` : ''}`
+      case 'call_joined':
+        return `${event.name} connected to call`
+      case 'call_left':
+        return `${event.name} disconnected from call`
+      case 'call_started':
+        return `${nameOf(event.started_by)} started a call`
+      case 'call_ended':
+        return 'The call ended'
+      case 'protocol_changed':
+        return `${nameOf(event.changed_by)} switched the protocol to ${event.protocol}`
+      case 'server_elected':
+        return event.user_id ? `${nameOf(event.user_id)} is now hosting` : 'Nobody is online to host'
+      case 'role_changed':
+        return `${nameOf(event.changed_by)} made ${nameOf(event.user_id)} ${event.role}`
+      case 'room_renamed':
+        return `${nameOf(event.renamed_by)} renamed the party to ${event.name}`
+      case 'moderated': {
+        const action = typeof event.action === 'string' ? event.action : Object.keys(event.action)[0]
+        const verbs: { [action: string]: string } = {
+          Kick: 'kicked',
+          Ban: 'banned',
+          Unban: 'unbanned',
+          Mute: 'muted',
+          Unmute: 'unmuted',
+        }
+        return `${event.target_name} was ${verbs[action] ?? 'moderated'} by ${nameOf(event.issued_by)}${event.reason ? `: ${event.reason}` : ''}`
+      }
+    }
+  }
+
   return (
     <div className={styles.chatArea}>
       <div className={styles.header}>
@@ -94,17 +204,20 @@ function ChatArea({
             messages.map(message => (
               <div
                 key={message.id}
-                className={`${styles.message} ${message.userName === 'System' ? styles.systemMessage : ''}`}
+                className={`${styles.message} ${message.event ? styles.systemMessage : ''} ${
+                  isMentioned(message) ? styles.mentioned : ''
+                }`}
               >
-                {message.userName === 'System' ? (
-                  <div className={styles.systemContent}>{message.content}</div>
+                {message.event ? (
+                  <div className={styles.systemContent}>{describeEvent(message.event)}</div>
                 ) : (
                   <>
                     <div className={styles.messageHeader}>
                       <span className={styles.userName}>{message.userName}</span>
                       <span className={styles.timestamp}>{formatTime(message.timestamp)}</span>
                     </div>
-                    <div className={styles.messageContent}>{message.content}</div>
+                    {message.content && <div className={styles.messageContent}>{message.content}</div>}
+                    {message.attachment && renderAttachment(message, message.attachment)}
                   </>
                 )}
               </div>
@@ -114,13 +227,24 @@ function ChatArea({
         </div>
       </div>
 
+      {typing.length > 0 && (
+        <div className={styles.typingIndicator}>
+          {typing.length === 1
+            ? `${typing[0].name} is typing...`
+            : `${typing.map(typist => typist.name).join(', ')} are typing...`}
+        </div>
+      )}
+
       <div className={styles.messageInput}>
         <form onSubmit={handleSendMessage} className={styles.inputForm}>
           <input
             type='text'
             placeholder={`Message #${room.name}`}
             value={messageInput}
-            onChange={e => setMessageInput(e.target.value)}
+            onChange={e => {
+              setMessageInput(e.target.value)
+              onTyping(e.target.value.trim().length > 0)
+            }}
             className={styles.textInput}
           />
           <button type='submit' className={styles.sendButton} disabled={!messageInput.trim()}>
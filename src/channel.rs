@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::clock::HlcTimestamp;
+use crate::retention::RetentionPolicy;
+use crate::unread::UnreadCount;
+
+/// Every room has a general channel. It uses the nil id, so messages from
+/// before channels existed land in it.
+pub const GENERAL_CHANNEL: Uuid = Uuid::nil();
+
+const MAX_CHANNEL_NAME: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Channel {
+    pub id: Uuid,
+    pub name: String,
+    pub created_by: Uuid,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// This peer's view of a channel: how far it has been read and how much
+/// of its history is kept. Only the retention policy is shared with peers.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChannelState {
+    #[serde(default)]
+    pub last_read: Option<(HlcTimestamp, Uuid)>,
+    /// Overrides the room's retention policy for this channel
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    #[serde(default)]
+    pub retention_horizon: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum ChannelAction {
+    Create { name: String },
+    Rename { name: String },
+    Delete,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelUpdate {
+    pub room_id: Uuid,
+    pub channel_id: Uuid,
+    pub action: ChannelAction,
+    pub updated_by: Uuid,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Channel as listed to the UI, with its unread and mention counts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelSummary {
+    #[serde(flatten)]
+    pub channel: Channel,
+    #[serde(flatten)]
+    pub unread: UnreadCount,
+    pub retention: Option<RetentionPolicy>,
+}
+
+impl Channel {
+    pub fn general(created_by: Uuid, created_at: chrono::DateTime<chrono::Utc>) -> Self {
+        Self {
+            id: GENERAL_CHANNEL,
+            name: "general".to_string(),
+            created_by,
+            created_at,
+        }
+    }
+}
+
+pub fn default_channels() -> Vec<Channel> {
+    vec![Channel::general(Uuid::nil(), chrono::DateTime::<chrono::Utc>::UNIX_EPOCH)]
+}
+
+/// Channel names are lowercase words joined by dashes, like "build-logs".
+pub fn normalize_name(name: &str) -> anyhow::Result<String> {
+    let name = name.trim().to_lowercase().split_whitespace().collect::<Vec<_>>().join("-");
+    if name.is_empty() {
+        return Err(anyhow::anyhow!("Channel name cannot be empty"));
+    }
+    if name.chars().count() > MAX_CHANNEL_NAME {
+        return Err(anyhow::anyhow!("Channel name is too long"));
+    }
+    if !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        return Err(anyhow::anyhow!("Channel names may only contain letters, numbers, dashes and underscores"));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_names_are_normalized() {
+        assert_eq!(normalize_name("  Build Logs ").unwrap(), "build-logs");
+        assert_eq!(normalize_name("random").unwrap(), "random");
+        assert!(normalize_name("   ").is_err());
+        assert!(normalize_name("no/slashes").is_err());
+        assert!(normalize_name(&"x".repeat(40)).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+/// How far ahead of our wall clock a peer's timestamps may run before
+/// they're refused rather than observed.
+pub const MAX_SKEW: chrono::Duration = chrono::Duration::minutes(2);
+
+/// Hybrid logical clock reading: wall-clock milliseconds plus a counter
+/// that breaks ties and carries causality when clocks disagree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct HlcTimestamp {
+    pub physical: i64,
+    pub logical: u32,
+}
+
+impl HlcTimestamp {
+    pub fn from_wall(time: chrono::DateTime<chrono::Utc>) -> Self {
+        Self {
+            physical: time.timestamp_millis(),
+            logical: 0,
+        }
+    }
+
+    pub fn is_unset(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether a peer's reading is close enough to our own time to take
+    /// in; one pushed far ahead would drag every clock it reaches along.
+    pub fn is_plausible(&self) -> bool {
+        self.physical <= (chrono::Utc::now() + MAX_SKEW).timestamp_millis()
+    }
+}
+
+/// Per-node hybrid logical clock. Every local event reads `now()`, every
+/// stamped message received goes through `observe()`, so anything sent
+/// after seeing a message is ordered after it even if our wall clock is
+/// behind the sender's.
+pub struct HybridClock {
+    last: Mutex<HlcTimestamp>,
+}
+
+/// The clock for this node, shared by chat messages and network messages.
+pub static LOCAL: HybridClock = HybridClock::new();
+
+impl HybridClock {
+    pub const fn new() -> Self {
+        Self {
+            last: Mutex::new(HlcTimestamp { physical: 0, logical: 0 }),
+        }
+    }
+
+    pub fn now(&self) -> HlcTimestamp {
+        self.tick(None)
+    }
+
+    pub fn observe(&self, remote: HlcTimestamp) -> HlcTimestamp {
+        self.tick(Some(remote))
+    }
+
+    fn tick(&self, remote: Option<HlcTimestamp>) -> HlcTimestamp {
+        let wall = chrono::Utc::now().timestamp_millis();
+        let mut last = self.last.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let mut next = HlcTimestamp { physical: wall.max(last.physical), logical: 0 };
+        if let Some(remote) = remote {
+            next.physical = next.physical.max(remote.physical);
+        }
+
+        // Counter continues from whichever reading shares the chosen physical time
+        let mut logical = None;
+        if next.physical == last.physical {
+            logical = Some(last.logical);
+        }
+        if let Some(remote) = remote.filter(|remote| remote.physical == next.physical) {
+            logical = Some(logical.map_or(remote.logical, |l| l.max(remote.logical)));
+        }
+        next.logical = match logical.map(|l| l.checked_add(1)) {
+            None => 0,
+            Some(Some(l)) => l,
+            // Counter ran out; carry into the next millisecond instead
+            Some(None) => {
+                next.physical += 1;
+                0
+            }
+        };
+
+        *last = next;
+        next
+    }
+}
+
+impl Default for HybridClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_orders_after_observed_messages() {
+        let clock = HybridClock::new();
+        let first = clock.now();
+        let second = clock.now();
+        assert!(second > first);
+
+        // A peer whose wall clock runs an hour ahead
+        let remote = HlcTimestamp {
+            physical: chrono::Utc::now().timestamp_millis() + 3_600_000,
+            logical: 7,
+        };
+        let observed = clock.observe(remote);
+        assert!(observed > remote);
+
+        // Our replies still sort after the message they answer
+        let reply = clock.now();
+        assert!(reply > remote);
+        assert_eq!(reply.physical, remote.physical);
+
+        // An exhausted counter carries into the physical time
+        let maxed = HlcTimestamp { physical: remote.physical, logical: u32::MAX };
+        let observed = clock.observe(maxed);
+        assert!(observed > maxed);
+        assert_eq!(observed, HlcTimestamp { physical: remote.physical + 1, logical: 0 });
+    }
+}
@@ -1,10 +1,30 @@
-use tauri::State;
+use tauri::{AppHandle, State};
 use uuid::Uuid;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use anyhow::Result;
 
-use crate::{AppState, room::Room, user::User, networking::Protocol, invite::InviteData, room::ChatMessage};
+use crate::{AppState, room::Room, user::User, networking::Protocol, invite::InviteData};
+use crate::join::{JoinDecision, JoinRequest, JoinResponse, PendingJoin};
+use crate::password::RoomPassword;
+use crate::permissions::{Permission, Role, RoleChange};
+use crate::room::{CallUpdate, ChatMessage, MessageEdit, MessageThread, MessageTombstone, PinUpdate, ReactionUpdate, RoomRename, RoomSetting, SettingsUpdate};
+use crate::retention::RetentionPolicy;
+use crate::direct::{self, DirectMessage};
+use crate::channel::{Channel, ChannelAction, ChannelSummary, ChannelUpdate, GENERAL_CHANNEL};
+use crate::sync::SyncMessage;
+use crate::search::{SearchFilter, SearchResult};
+use crate::pagination::{MessageCursor, MessagePage, PageDirection, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::storage::SavedRoom;
+use crate::moderation::{ModerationAction, ModerationCommand, ModerationLogEntry};
+use crate::networking::{MessageType, NetworkManager, NetworkMessage};
+use crate::session::{PartySession, SessionHandle};
+use crate::typing::TypingNotice;
+use crate::receipts::MessageReceipts;
+use crate::unread::RoomUnread;
+use crate::notify::{NotificationLevel, NotificationPolicy};
+use crate::transfer::{Download, FileManifest, TransferProgress};
+use crate::media;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateRoomRequest {
@@ -40,60 +60,76 @@ pub struct PingStats {
     pub average_ping: Option<u64>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PartySummary {
+    pub room_id: Uuid,
+    pub name: String,
+    pub member_count: usize,
+    pub hosting: bool,
+    pub awaiting_approval: bool,
+    pub focused: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NetworkDiagnostics {
+    pub connected_peers: usize,
+    pub replay: crate::replay::ReplayStats,
+    pub rejected_messages: u64,
+}
+
 #[tauri::command]
 pub async fn create_party(
+    app: AppHandle,
     state: State<'_, AppState>,
     name: String,
     protocol: Option<Protocol>,
+    password: Option<String>,
 ) -> Result<Room, String> {
     let protocol = protocol.unwrap_or(Protocol::TCP);
-    
-    // Check if there's already an active party
-    let current_party_guard = state.current_party.lock().await;
-    if current_party_guard.is_some() {
-        return Err("A party is already active. Leave the current party before creating a new one.".to_string());
-    }
-    drop(current_party_guard);
-    
+
     // Get current user
-    let current_user_guard = state.current_user.lock().await;
-    let current_user = current_user_guard.as_ref()
+    let mut local_user = state.current_user.lock().await.clone()
         .ok_or("No user configured. Please set up your profile first.")?;
 
-    let _user_addr = current_user.address;
-    let user_clone = current_user.clone();
-    drop(current_user_guard);
+    // Every hosted party listens on a port of its own
+    let port = state.parties.lock().await.free_port();
+    local_user.address.set_port(port);
 
     // Create the party
-    let party = Room::new(name, user_clone, protocol.clone());
-    
+    let mut party = Room::new(name, local_user.clone(), protocol.clone());
+    if let Some(password) = password.filter(|p| !p.is_empty()) {
+        party.password = Some(RoomPassword::new(&password)
+            .map_err(|e| format!("Failed to set room password: {}", e))?);
+        party.password_required = true;
+    }
+
     println!("✅ Created new party '{}' with ID: {}", party.name, party.id);
 
-    // Set as current party (session-based, no file persistence)
-    let mut current_party = state.current_party.lock().await;
-    *current_party = Some(party.clone());
+    // Saved right away so it shows up under recent parties
+    if let Err(e) = state.store.save_room(&mut party) {
+        eprintln!("Failed to save party: {}", e);
+    }
 
     // Start server for this party
-    let mut networking = state.networking.lock().await;
-    if let Err(e) = networking.start_server(8080, protocol).await {
+    let mut session = PartySession::new(party.clone(), local_user);
+    session.networking.set_signer(state.identity.clone());
+    if let Err(e) = session.networking.start_server(port, protocol).await {
         return Err(format!("Failed to start server: {}", e));
     }
+    session.port = Some(port);
+
+    start_session(&app, &state, session).await;
 
     Ok(party)
 }
 
 #[tauri::command]
 pub async fn join_party(
+    app: AppHandle,
     state: State<'_, AppState>,
     invite_code: String,
+    password: Option<String>,
 ) -> Result<Room, String> {
-    // Check if there's already an active party
-    let current_party_guard = state.current_party.lock().await;
-    if current_party_guard.is_some() {
-        return Err("Already in a party. Leave the current party before joining another.".to_string());
-    }
-    drop(current_party_guard);
-
     // Parse invite code
     let invite_data = InviteData::parse_invite_code(&invite_code)
         .map_err(|e| format!("Invalid invite code: {}", e))?;
@@ -103,216 +139,1668 @@ pub async fn join_party(
         return Err("Invite code has expired".to_string());
     }
 
-    // Get current user
-    let current_user_guard = state.current_user.lock().await;
-    let current_user = current_user_guard.as_ref()
-        .ok_or("No user configured. Please set up your profile first.")?;
-    let user_clone = current_user.clone();
-    drop(current_user_guard);
+    if state.parties.lock().await.contains(invite_data.room_id) {
+        return Err("Already in this party".to_string());
+    }
+
+    // Get current user
+    let local_user = state.current_user.lock().await.clone()
+        .ok_or("No user configured. Please set up your profile first.")?;
+
+    // Local stand-in until the server's snapshot arrives; saved once we're let in
+    let mut party = Room::new(invite_data.room_name.clone(), local_user.clone(), invite_data.protocol.clone());
+    party.id = invite_data.room_id;
+    party.peer_addresses = invite_data.peer_addresses.clone();
+    party.awaiting_approval = invite_data.requires_approval;
+
+    // Try to connect to peers in order
+    let mut session = PartySession::new(party.clone(), local_user.clone());
+    session.networking.set_signer(state.identity.clone());
+    let mut connected_peer = None;
+
+    // Try primary peer first
+    if let Some(primary_peer) = invite_data.get_primary_peer() {
+        if let Ok(_) = session.networking.connect_to_peer(primary_peer, invite_data.protocol.clone()).await {
+            connected_peer = Some(primary_peer);
+        }
+    }
+
+    // Try fallback peers if primary failed
+    if connected_peer.is_none() {
+        for peer_addr in invite_data.get_fallback_peers() {
+            if let Ok(_) = session.networking.connect_to_peer(peer_addr, invite_data.protocol.clone()).await {
+                connected_peer = Some(peer_addr);
+                break;
+            }
+        }
+    }
+
+    let connected_peer = connected_peer
+        .ok_or("Could not connect to any peers in the party")?;
+
+    // Introduce ourselves to the server peer, which decides whether we may enter
+    let pending_join = PendingJoin {
+        request: JoinRequest::new(invite_data.room_id, local_user, &state.identity),
+        server_addr: connected_peer,
+        password,
+        challenge: None,
+        host_key: invite_data.host_key.clone(),
+    };
+    if let Err(e) = crate::handlers::send_join_request(&session.networking, &pending_join).await {
+        return Err(format!("Failed to send join request: {}", e));
+    }
+    session.networking.server_peer = Some(connected_peer.to_string());
+    session.pending_join = Some(pending_join);
+
+    if party.awaiting_approval {
+        println!("⏳ Waiting for approval to join party '{}' with ID: {}", party.name, party.id);
+    } else {
+        println!("✅ Joined party '{}' with ID: {}", party.name, party.id);
+    }
+
+    start_session(&app, &state, session).await;
+
+    Ok(party)
+}
+
+#[tauri::command]
+pub async fn get_recent_parties(
+    state: State<'_, AppState>,
+    limit: Option<usize>,
+) -> Result<Vec<SavedRoom>, String> {
+    state.store.recent_rooms(limit.unwrap_or(20))
+        .map_err(|e| format!("Failed to list saved parties: {}", e))
+}
+
+/// Reconnects to a saved party as the member we were, trying its peers
+/// best ping first. Missed history is merged once the server lets us back
+/// in; if nobody is reachable we host the party ourselves.
+#[tauri::command]
+pub async fn rejoin_party(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    room_id: String,
+) -> Result<Room, String> {
+    let room_uuid = Uuid::parse_str(&room_id)
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    if state.parties.lock().await.contains(room_uuid) {
+        return Err("Already in this party".to_string());
+    }
+
+    let mut party = state.store.load_room(room_uuid)
+        .map_err(|e| format!("Failed to load saved party: {}", e))?;
+
+    // User ids are per session; the identity key is what ties us to our membership
+    let identity_key = state.identity.public_key();
+    let member_id = party.users.values()
+        .find(|user| user.identity_key.as_deref() == Some(identity_key.as_str()))
+        .map(|user| user.id)
+        .ok_or("You are no longer a member of this party")?;
+
+    let mut local_user = state.current_user.lock().await.clone()
+        .ok_or("No user configured. Please set up your profile first.")?;
+    local_user.id = member_id;
+
+    let peers: Vec<SocketAddr> = party.get_ordered_peer_list()
+        .into_iter()
+        .filter(|addr| *addr != local_user.address)
+        .collect();
+
+    let mut networking = NetworkManager::new();
+    networking.set_signer(state.identity.clone());
+    let mut connected_peer = None;
+    for peer_addr in peers {
+        if networking.connect_to_peer(peer_addr, party.protocol.clone()).await.is_ok() {
+            connected_peer = Some(peer_addr);
+            break;
+        }
+    }
+
+    let mut pending_join = None;
+    let mut port = None;
+    match connected_peer {
+        Some(server_addr) => {
+            // We answer to whoever we knew at that address, with the key they had then
+            let host_key = party.users.values()
+                .find(|user| user.address == server_addr)
+                .and_then(|user| user.identity_key.clone());
+            let pending = PendingJoin {
+                request: JoinRequest::new(party.id, local_user.clone(), &state.identity),
+                server_addr,
+                password: None,
+                challenge: None,
+                host_key,
+            };
+            if let Err(e) = crate::handlers::send_join_request(&networking, &pending).await {
+                return Err(format!("Failed to send join request: {}", e));
+            }
+            networking.server_peer = Some(server_addr.to_string());
+            pending_join = Some(pending);
+            println!("🔁 Rejoining party '{}' through {}", party.name, server_addr);
+        }
+        None => {
+            let free_port = state.parties.lock().await.free_port();
+            if let Err(e) = networking.start_server(free_port, party.protocol.clone()).await {
+                return Err(format!("Failed to start server: {}", e));
+            }
+            local_user.address.set_port(free_port);
+            party.server_user_id = Some(member_id);
+            port = Some(free_port);
+            println!("🔁 No peers of party '{}' are reachable, hosting it again", party.name);
+        }
+    }
+
+    if let Some(member) = party.users.get_mut(&member_id) {
+        member.address = local_user.address;
+        member.is_online = true;
+        member.update_last_seen();
+    }
+
+    let latest = party.clone_with_latest(MAX_PAGE_SIZE);
+    let session = PartySession {
+        room: party,
+        networking,
+        local_user,
+        pending_join,
+        port,
+        typing: Default::default(),
+        downloads: Default::default(),
+    };
+    start_session(&app, &state, session).await;
+
+    Ok(latest)
+}
+
+#[tauri::command]
+pub async fn leave_party(
+    state: State<'_, AppState>,
+    room_id: String,
+) -> Result<(), String> {
+    let room_uuid = Uuid::parse_str(&room_id)
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let session = state.parties.lock().await.remove(room_uuid)
+        .ok_or("Not in that party")?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, pending_join, local_user, .. } = &mut *session;
+
+    println!("✅ Left party '{}' with ID: {}", party.name, party.id);
+
+    if let Err(e) = party.user_left(local_user.id) {
+        eprintln!("Failed to record leaving: {}", e);
+    }
+    // Kept for rejoining later from recent parties
+    if let Err(e) = state.store.save_room(party) {
+        eprintln!("Failed to save party: {}", e);
+    }
+
+    let leave_message = NetworkMessage::new(
+        local_user.id.to_string(),
+        None,
+        MessageType::UserLeft,
+        serde_json::to_value(local_user.id).unwrap(),
+    );
+    if let Err(e) = networking.broadcast_message(leave_message).await {
+        eprintln!("Failed to announce leaving: {}", e);
+    }
+
+    // Stop networking for the party
+    *pending_join = None;
+    if let Err(e) = networking.disconnect_all().await {
+        eprintln!("Warning: Failed to disconnect from peers: {}", e);
+    }
+
+    Ok(())
+}
+
+/// Registers a new session and starts handling its traffic.
+async fn start_session(app: &AppHandle, state: &AppState, session: PartySession) {
+    let handle = state.parties.lock().await.insert(session);
+    tokio::spawn(crate::handlers::run_message_loop(app.clone(), handle));
+}
+
+/// Resolves a channel of the party, defaulting to the general channel.
+fn parse_channel_id(party: &Room, channel_id: Option<String>) -> Result<Uuid, String> {
+    let channel_uuid = match channel_id {
+        Some(id) => Uuid::parse_str(&id).map_err(|e| format!("Invalid channel ID: {}", e))?,
+        None => GENERAL_CHANNEL,
+    };
+    if party.channel(channel_uuid).is_none() {
+        return Err("Channel not found".to_string());
+    }
+    Ok(channel_uuid)
+}
+
+async fn party_session(state: &AppState, room_id: &str) -> Result<SessionHandle, String> {
+    let room_uuid = Uuid::parse_str(room_id)
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    state.parties.lock().await.get(room_uuid)
+        .ok_or_else(|| "Not in that party".to_string())
+}
+
+#[tauri::command]
+pub async fn send_message(
+    state: State<'_, AppState>,
+    room_id: String,
+    channel_id: Option<String>,
+    content: String,
+    reply_to: Option<String>,
+) -> Result<(), String> {
+    let parent_id = reply_to
+        .map(|id| Uuid::parse_str(&id))
+        .transpose()
+        .map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, typing, .. } = &mut *session;
+    let channel_uuid = parse_channel_id(party, channel_id)?;
+
+    let mut message = match parent_id {
+        Some(parent_id) => ChatMessage::reply(local_user.id, local_user.name.clone(), content, parent_id),
+        None => ChatMessage::new(local_user.id, local_user.name.clone(), content),
+    };
+    message.channel_id = channel_uuid;
+
+    if !party.has_permission(local_user.id, Permission::SendMessages) {
+        return Err("You don't have permission to send messages".to_string());
+    }
+    if party.is_muted(local_user.id) {
+        return Err("You are muted in this party".to_string());
+    }
+    if let Some(parent_id) = parent_id {
+        if !party.messages.iter().any(|m| m.id == parent_id && !m.is_deleted()) {
+            return Err("Message to reply to not found".to_string());
+        }
+    }
+
+    // Add message to the current party; replies get re-parented to the thread root
+    party.add_message(message.clone());
+    let message = party.messages.iter().rev()
+        .find(|m| m.id == message.id)
+        .cloned()
+        .unwrap_or(message);
+    party.mark_channel_read(message.channel_id);
+    // Receivers clear our typing state when the message arrives
+    typing.reset_sent();
+    let read_update = share_read_marker(&state, party, local_user.id, message.channel_id);
+
+    println!("✅ Message added to party '{}' (ID: {})", party.name, party.id);
+    println!("📁 Party '{}' now has {} messages", party.name, party.messages.len());
+
+    if let Err(e) = state.store.save_room(party) {
+        eprintln!("Failed to save message: {}", e);
+    }
+
+    // Send message to other peers in the party
+    let network_message = crate::networking::NetworkMessage::new(
+        local_user.id.to_string(),
+        None,
+        crate::networking::MessageType::ChatMessage,
+        serde_json::to_value(&message).unwrap(),
+    );
+
+    if let Err(e) = networking.broadcast_message(network_message).await {
+        eprintln!("Failed to broadcast message: {}", e);
+    }
+    if let Some(update) = read_update {
+        if let Err(e) = crate::handlers::send_receipt(networking, &update).await {
+            eprintln!("Failed to send read receipt: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_thread(
+    state: State<'_, AppState>,
+    room_id: String,
+    message_id: String,
+) -> Result<MessageThread, String> {
+    let message_uuid = Uuid::parse_str(&message_id)
+        .map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let session = party_session(&state, &room_id).await?;
+    let session = session.lock().await;
+    let party = &session.room;
+    party.get_thread(message_uuid)
+        .ok_or_else(|| "Thread not found".to_string())
+}
+
+#[tauri::command]
+pub async fn edit_message(
+    state: State<'_, AppState>,
+    room_id: String,
+    message_id: String,
+    content: String,
+) -> Result<ChatMessage, String> {
+    let message_uuid = Uuid::parse_str(&message_id)
+        .map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    let edit = MessageEdit {
+        room_id: party.id,
+        message_id: message_uuid,
+        content,
+        edited_by: local_user.id,
+        edited_at: chrono::Utc::now(),
+    };
+
+    let edited = party.edit_message(&edit)
+        .map_err(|e| format!("Failed to edit message: {}", e))?
+        .clone();
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    let network_message = NetworkMessage::new(
+        local_user.id.to_string(),
+        None,
+        MessageType::MessageEdit,
+        serde_json::to_value(&edit).map_err(|e| e.to_string())?,
+    );
+
+    if let Err(e) = networking.broadcast_message(network_message).await {
+        eprintln!("Failed to broadcast message edit: {}", e);
+    }
+
+    Ok(edited)
+}
+
+#[tauri::command]
+pub async fn delete_message(
+    state: State<'_, AppState>,
+    room_id: String,
+    message_id: String,
+) -> Result<(), String> {
+    let message_uuid = Uuid::parse_str(&message_id)
+        .map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    if !party.messages.iter().any(|m| m.id == message_uuid) {
+        return Err("Message not found".to_string());
+    }
+
+    let tombstone = MessageTombstone {
+        room_id: party.id,
+        message_id: message_uuid,
+        deleted_by: local_user.id,
+        deleted_at: chrono::Utc::now(),
+    };
+
+    party.delete_message(tombstone.clone())
+        .map_err(|e| format!("Failed to delete message: {}", e))?;
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    let network_message = NetworkMessage::new(
+        local_user.id.to_string(),
+        None,
+        MessageType::MessageDelete,
+        serde_json::to_value(&tombstone).map_err(|e| e.to_string())?,
+    );
+
+    if let Err(e) = networking.broadcast_message(network_message).await {
+        eprintln!("Failed to broadcast message deletion: {}", e);
+    }
+
+    Ok(())
+}
+
+async fn update_reaction(
+    state: &AppState,
+    room_id: String,
+    message_id: String,
+    emoji: String,
+    added: bool,
+) -> Result<(), String> {
+    let message_uuid = Uuid::parse_str(&message_id)
+        .map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let session = party_session(state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    let update = ReactionUpdate {
+        room_id: party.id,
+        message_id: message_uuid,
+        emoji: emoji.trim().to_string(),
+        user_id: local_user.id,
+        added,
+        hlc: crate::clock::LOCAL.now(),
+    };
+
+    let changed = party.apply_reaction(&update)
+        .map_err(|e| format!("Failed to update reaction: {}", e))?;
+    if !changed {
+        return Ok(());
+    }
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    let network_message = NetworkMessage::new(
+        local_user.id.to_string(),
+        None,
+        MessageType::Reaction,
+        serde_json::to_value(&update).map_err(|e| e.to_string())?,
+    );
+
+    if let Err(e) = networking.broadcast_message(network_message).await {
+        eprintln!("Failed to broadcast reaction: {}", e);
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn add_reaction(
+    state: State<'_, AppState>,
+    room_id: String,
+    message_id: String,
+    emoji: String,
+) -> Result<(), String> {
+    update_reaction(&state, room_id, message_id, emoji, true).await
+}
+
+#[tauri::command]
+pub async fn remove_reaction(
+    state: State<'_, AppState>,
+    room_id: String,
+    message_id: String,
+    emoji: String,
+) -> Result<(), String> {
+    update_reaction(&state, room_id, message_id, emoji, false).await
+}
+
+async fn update_pin(state: &AppState, room_id: String, message_id: String, pinned: bool) -> Result<(), String> {
+    let message_uuid = Uuid::parse_str(&message_id)
+        .map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let session = party_session(state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    let update = PinUpdate {
+        room_id: party.id,
+        message_id: message_uuid,
+        pinned,
+        pinned_by: local_user.id,
+    };
+
+    let changed = party.set_pinned(&update)
+        .map_err(|e| format!("Failed to update pin: {}", e))?;
+    if !changed {
+        return Ok(());
+    }
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    let network_message = NetworkMessage::new(
+        local_user.id.to_string(),
+        None,
+        MessageType::MessagePin,
+        serde_json::to_value(&update).map_err(|e| e.to_string())?,
+    );
+
+    if let Err(e) = networking.broadcast_message(network_message).await {
+        eprintln!("Failed to broadcast pin update: {}", e);
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn pin_message(
+    state: State<'_, AppState>,
+    room_id: String,
+    message_id: String,
+) -> Result<(), String> {
+    update_pin(&state, room_id, message_id, true).await
+}
+
+#[tauri::command]
+pub async fn unpin_message(
+    state: State<'_, AppState>,
+    room_id: String,
+    message_id: String,
+) -> Result<(), String> {
+    update_pin(&state, room_id, message_id, false).await
+}
+
+/// Sets how much of a party's history every peer keeps, for the whole
+/// party or just one channel, and applies it straight away.
+#[tauri::command]
+pub async fn set_retention_policy(
+    state: State<'_, AppState>,
+    room_id: String,
+    channel_id: Option<String>,
+    policy: RetentionPolicy,
+) -> Result<usize, String> {
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+
+    let channel_id = match channel_id {
+        Some(channel_id) => Some(parse_channel_id(party, Some(channel_id))?),
+        None => None,
+    };
+    let setting = RoomSetting::Retention { channel_id, policy };
+    party.apply_setting(local_user.id, &setting)
+        .map_err(|e| format!("Failed to set retention policy: {}", e))?;
+
+    let now = chrono::Utc::now();
+    let mut removed = party.apply_retention(now);
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+    let compactions = state.store.compact_room(party.id, now)
+        .map_err(|e| format!("Failed to compact history: {}", e))?;
+    for (channel_id, compaction) in &compactions {
+        removed += compaction.expired.len();
+        party.record_compaction(*channel_id, compaction);
+    }
+
+    println!("🧹 Retention for party '{}' set to {:?}, {} messages removed", party.name, policy, removed);
+
+    let update = SettingsUpdate {
+        room_id: party.id,
+        setting,
+        changed_by: local_user.id,
+    };
+    broadcast_settings_update(networking, &update).await?;
+    Ok(removed)
+}
+
+#[tauri::command]
+pub async fn get_party(
+    state: State<'_, AppState>,
+    room_id: String,
+) -> Result<Room, String> {
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+
+    // Only the latest page of history comes along; older messages are
+    // fetched through get_room_messages
+    Ok(session.room.clone_with_latest(MAX_PAGE_SIZE))
+}
+
+#[tauri::command]
+pub async fn list_parties(state: State<'_, AppState>) -> Result<Vec<PartySummary>, String> {
+    let (handles, focused) = {
+        let parties = state.parties.lock().await;
+        (parties.handles(), parties.focused())
+    };
+
+    let mut summaries = Vec::new();
+    for handle in handles {
+        let session = handle.lock().await;
+        summaries.push(PartySummary {
+            room_id: session.room.id,
+            name: session.room.name.clone(),
+            member_count: session.room.users.len(),
+            hosting: session.port.is_some(),
+            awaiting_approval: session.room.awaiting_approval,
+            focused: focused == Some(session.room.id),
+        });
+    }
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(summaries)
+}
+
+/// Picks the party the UI is showing; the others keep running.
+#[tauri::command]
+pub async fn focus_party(
+    state: State<'_, AppState>,
+    room_id: String,
+) -> Result<(), String> {
+    let room_uuid = Uuid::parse_str(&room_id)
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    if state.parties.lock().await.focus(room_uuid) {
+        Ok(())
+    } else {
+        Err("Not in that party".to_string())
+    }
+}
+
+#[tauri::command]
+pub async fn get_focused_party(state: State<'_, AppState>) -> Result<Option<Room>, String> {
+    let handle = {
+        let parties = state.parties.lock().await;
+        parties.focused().and_then(|room_id| parties.get(room_id))
+    };
+
+    match handle {
+        Some(handle) => Ok(Some(handle.lock().await.room.clone_with_latest(MAX_PAGE_SIZE))),
+        None => Ok(None),
+    }
+}
+
+#[tauri::command]
+pub async fn set_user_settings(
+    state: State<'_, AppState>,
+    settings: UserSettings,
+) -> Result<(), String> {
+    let local_addr = local_ip_address::local_ip()
+        .map_err(|e| format!("Failed to get local IP: {}", e))?;
+    
+    let socket_addr = SocketAddr::new(local_addr, 8080);
+    
+    let mut user = User::new(settings.name, socket_addr);
+    user.set_avatar(settings.avatar);
+    user.set_audio_devices(settings.audio_input_device, settings.audio_output_device);
+    user.identity_key = Some(state.identity.public_key());
+    user.dm_key = Some(state.identity.dm_public_key());
+    user.dm_key_signature = Some(state.identity.dm_key_signature());
+
+    let mut current_user = state.current_user.lock().await;
+    *current_user = Some(user);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_ping_stats(
+    _state: State<'_, AppState>,
+) -> Result<Vec<PingStats>, String> {
+    // This would be implemented with the ping manager
+    // For now, return empty stats
+    Ok(vec![])
+}
+
+#[tauri::command]
+pub async fn get_network_diagnostics(
+    state: State<'_, AppState>,
+    room_id: String,
+) -> Result<NetworkDiagnostics, String> {
+    let session = party_session(&state, &room_id).await?;
+    let session = session.lock().await;
+    let networking = &session.networking;
+    let replay = networking.replay_stats();
+
+    Ok(NetworkDiagnostics {
+        connected_peers: networking.connections.len(),
+        rejected_messages: replay.total_rejected(),
+        replay,
+    })
+}
+
+#[tauri::command]
+pub async fn change_protocol(
+    state: State<'_, AppState>,
+    room_id: String,
+    new_protocol: Protocol,
+) -> Result<(), String> {
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    if !party.has_permission(local_user.id, Permission::ChangeProtocol) {
+        return Err("You don't have permission to change the protocol".to_string());
+    }
+
+    let peers = party.peer_addresses.clone();
+    
+    // Switch protocol in networking layer
+    if let Err(e) = networking.switch_protocol(local_user.id.to_string(), new_protocol.clone(), peers).await {
+        return Err(format!("Failed to switch protocol: {}", e));
+    }
+
+    // Update party
+    party.switch_protocol(local_user.id, new_protocol);
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    println!("✅ Changed party protocol to {:?}", party.protocol);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn generate_invite(
+    state: State<'_, AppState>,
+    room_id: String,
+) -> Result<String, String> {
+    let session = party_session(&state, &room_id).await?;
+    let session = session.lock().await;
+    let PartySession { room: party, local_user, .. } = &*session;
+
+    if !party.has_permission(local_user.id, Permission::GenerateInvite) {
+        return Err("You don't have permission to generate invites".to_string());
+    }
+
+    let mut invite_data = InviteData::new(
+        party.id,
+        party.name.clone(),
+        local_user.name.clone(),
+        party.peer_addresses.clone(),
+        party.protocol.clone(),
+    );
+    invite_data.requires_approval = party.require_approval;
+    invite_data.requires_password = party.requires_password();
+    invite_data.host_key = party.server_user_id
+        .and_then(|server_id| party.users.get(&server_id))
+        .and_then(|host| host.identity_key.clone());
+
+    let invite_code = invite_data.generate_invite_code()
+        .map_err(|e| format!("Failed to generate invite code: {}", e))?;
+
+    Ok(invite_code)
+}
+
+#[tauri::command]
+pub async fn set_join_approval(
+    state: State<'_, AppState>,
+    room_id: String,
+    required: bool,
+) -> Result<(), String> {
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, local_user, .. } = &mut *session;
+    if !party.has_permission(local_user.id, Permission::ManageSettings) {
+        return Err("You don't have permission to change join approval".to_string());
+    }
+
+    party.require_approval = required;
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+    println!("🚪 Join approval for party '{}' is now {}", party.name, if required { "required" } else { "off" });
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_pending_joins(
+    state: State<'_, AppState>,
+    room_id: String,
+) -> Result<Vec<JoinRequest>, String> {
+    let session = party_session(&state, &room_id).await?;
+    let session = session.lock().await;
+    let party = &session.room;
+    let mut requests: Vec<JoinRequest> = party.pending_joins.values().cloned().collect();
+    requests.sort_by_key(|request| request.requested_at);
+    Ok(requests)
+}
+
+#[tauri::command]
+pub async fn approve_join(
+    state: State<'_, AppState>,
+    room_id: String,
+    user_id: String,
+) -> Result<User, String> {
+    let user_uuid = Uuid::parse_str(&user_id)
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    if !party.has_permission(local_user.id, Permission::ManageJoins) {
+        return Err("You don't have permission to approve join requests".to_string());
+    }
+
+    let user = party.approve_join(user_uuid, local_user.id)
+        .map_err(|e| format!("Failed to approve join request: {}", e))?;
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    println!("✅ Approved '{}' to join party '{}'", user.name, party.name);
+
+    let response = JoinResponse {
+        room_id: party.id,
+        user_id: user.id,
+        decision: JoinDecision::Approved,
+        decided_by: Some(local_user.id),
+        room: Some(party.snapshot_for_peer()),
+    };
+    crate::handlers::send_join_response(networking, local_user.id, user.address, response).await;
+
+    Ok(user)
+}
+
+#[tauri::command]
+pub async fn deny_join(
+    state: State<'_, AppState>,
+    room_id: String,
+    user_id: String,
+    reason: Option<String>,
+) -> Result<(), String> {
+    let user_uuid = Uuid::parse_str(&user_id)
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    if !party.has_permission(local_user.id, Permission::ManageJoins) {
+        return Err("You don't have permission to deny join requests".to_string());
+    }
+
+    let request = party.deny_join(user_uuid, local_user.id, reason.clone())
+        .map_err(|e| format!("Failed to deny join request: {}", e))?;
+
+    println!("🚫 Denied '{}' from joining party '{}'", request.user.name, party.name);
+
+    let response = JoinResponse {
+        room_id: party.id,
+        user_id: request.user.id,
+        decision: JoinDecision::Denied(reason),
+        decided_by: Some(local_user.id),
+        room: None,
+    };
+    crate::handlers::send_join_response(networking, local_user.id, request.user.address, response).await;
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_room_password(
+    state: State<'_, AppState>,
+    room_id: String,
+    password: Option<String>,
+) -> Result<(), String> {
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    if !party.has_permission(local_user.id, Permission::ManageSettings) {
+        return Err("You don't have permission to change the room password".to_string());
+    }
+
+    party.password = match password.filter(|p| !p.is_empty()) {
+        Some(password) => Some(RoomPassword::new(&password)
+            .map_err(|e| format!("Failed to set room password: {}", e))?),
+        None => None,
+    };
+    party.password_required = party.password.is_some();
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    println!("🔒 Password protection for party '{}' is now {}", party.name, if party.password.is_some() { "on" } else { "off" });
+
+    // Peers only learn that a password is needed, never the verifier
+    let update = SettingsUpdate {
+        room_id: party.id,
+        setting: RoomSetting::PasswordRequired(party.password_required),
+        changed_by: local_user.id,
+    };
+    broadcast_settings_update(networking, &update).await
+}
+
+async fn broadcast_settings_update(networking: &NetworkManager, update: &SettingsUpdate) -> Result<(), String> {
+    let network_message = NetworkMessage::new(
+        update.changed_by.to_string(),
+        None,
+        MessageType::RoomSettings,
+        serde_json::to_value(update).map_err(|e| e.to_string())?,
+    );
+
+    if let Err(e) = networking.broadcast_message(network_message).await {
+        eprintln!("Failed to broadcast settings change: {}", e);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn submit_join_password(
+    state: State<'_, AppState>,
+    room_id: String,
+    password: String,
+) -> Result<(), String> {
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { networking, pending_join, .. } = &mut *session;
+    let pending = pending_join.as_mut()
+        .ok_or("No join request in progress")?;
+
+    pending.password = Some(password);
+
+    crate::handlers::answer_password_challenge(networking, &state.identity, pending).await
+        .map_err(|e| format!("Failed to answer password challenge: {}", e))
+}
+
+#[tauri::command]
+pub async fn rename_room(
+    state: State<'_, AppState>,
+    room_id: String,
+    name: String,
+) -> Result<(), String> {
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    party.rename(local_user.id, name)
+        .map_err(|e| format!("Failed to rename room: {}", e))?;
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    println!("✏️ Renamed party {} to '{}'", party.id, party.name);
+
+    let rename = RoomRename {
+        room_id: party.id,
+        name: party.name.clone(),
+        renamed_by: local_user.id,
+    };
+    let network_message = NetworkMessage::new(
+        local_user.id.to_string(),
+        None,
+        MessageType::RoomRename,
+        serde_json::to_value(&rename).map_err(|e| e.to_string())?,
+    );
+
+    if let Err(e) = networking.broadcast_message(network_message).await {
+        eprintln!("Failed to broadcast room rename: {}", e);
+    }
+
+    Ok(())
+}
+
+async fn update_channel(
+    state: &AppState,
+    room_id: String,
+    channel_id: Uuid,
+    action: ChannelAction,
+) -> Result<Option<Channel>, String> {
+    let session = party_session(state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+
+    // Names are only checked for clashes here; updates from peers are
+    // taken as they come so that everyone ends up with the same channels
+    if let ChannelAction::Create { name } | ChannelAction::Rename { name } = &action {
+        let name = crate::channel::normalize_name(name)
+            .map_err(|e| format!("Invalid channel name: {}", e))?;
+        if party.channel_named(&name).is_some_and(|channel| channel.id != channel_id) {
+            return Err(format!("A channel named '{}' already exists", name));
+        }
+    }
+
+    let update = ChannelUpdate {
+        room_id: party.id,
+        channel_id,
+        action,
+        updated_by: local_user.id,
+        updated_at: chrono::Utc::now(),
+    };
+    let changed = party.apply_channel_update(&update)
+        .map_err(|e| format!("Failed to update channel: {}", e))?;
+    if !changed {
+        return Ok(party.channel(channel_id).cloned());
+    }
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    println!("#️⃣ Channel {} in party '{}' updated: {:?}", channel_id, party.name, update.action);
+
+    let network_message = NetworkMessage::new(
+        local_user.id.to_string(),
+        None,
+        MessageType::ChannelUpdate,
+        serde_json::to_value(&update).map_err(|e| e.to_string())?,
+    );
+
+    if let Err(e) = networking.broadcast_message(network_message).await {
+        eprintln!("Failed to broadcast channel update: {}", e);
+    }
+
+    Ok(party.channel(channel_id).cloned())
+}
+
+#[tauri::command]
+pub async fn create_channel(
+    state: State<'_, AppState>,
+    room_id: String,
+    name: String,
+) -> Result<Channel, String> {
+    update_channel(&state, room_id, Uuid::new_v4(), ChannelAction::Create { name }).await?
+        .ok_or_else(|| "Channel not found".to_string())
+}
+
+#[tauri::command]
+pub async fn rename_channel(
+    state: State<'_, AppState>,
+    room_id: String,
+    channel_id: String,
+    name: String,
+) -> Result<Channel, String> {
+    let channel_uuid = Uuid::parse_str(&channel_id)
+        .map_err(|e| format!("Invalid channel ID: {}", e))?;
+    update_channel(&state, room_id, channel_uuid, ChannelAction::Rename { name }).await?
+        .ok_or_else(|| "Channel not found".to_string())
+}
+
+#[tauri::command]
+pub async fn delete_channel(
+    state: State<'_, AppState>,
+    room_id: String,
+    channel_id: String,
+) -> Result<(), String> {
+    let channel_uuid = Uuid::parse_str(&channel_id)
+        .map_err(|e| format!("Invalid channel ID: {}", e))?;
+    update_channel(&state, room_id, channel_uuid, ChannelAction::Delete).await?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn list_channels(
+    state: State<'_, AppState>,
+    room_id: String,
+) -> Result<Vec<ChannelSummary>, String> {
+    let session = party_session(&state, &room_id).await?;
+    let session = session.lock().await;
+    Ok(session.room.channel_summaries(session.local_user.id))
+}
+
+#[tauri::command]
+pub async fn mark_channel_read(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    room_id: String,
+    channel_id: String,
+) -> Result<(), String> {
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    let channel_uuid = parse_channel_id(party, Some(channel_id))?;
+
+    party.mark_channel_read(channel_uuid);
+    let read_update = share_read_marker(&state, party, local_user.id, channel_uuid);
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+    crate::handlers::emit_unread(&app, party, local_user.id);
+
+    if let Some(update) = read_update {
+        crate::handlers::send_receipt(networking, &update).await
+            .map_err(|e| format!("Failed to send read receipt: {}", e))?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn mark_party_read(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    room_id: String,
+) -> Result<(), String> {
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+
+    party.mark_all_read();
+    let channel_ids: Vec<Uuid> = party.channels.iter().map(|channel| channel.id).collect();
+    let read_updates: Vec<_> = channel_ids.into_iter()
+        .filter_map(|channel_id| share_read_marker(&state, party, local_user.id, channel_id))
+        .collect();
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+    crate::handlers::emit_unread(&app, party, local_user.id);
+
+    for update in read_updates {
+        if let Err(e) = crate::handlers::send_receipt(networking, &update).await {
+            eprintln!("Failed to send read receipt: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Unread and mention counts for every party we're in or have saved,
+/// including ones not currently active.
+#[tauri::command]
+pub async fn get_unread_counts(
+    state: State<'_, AppState>,
+) -> Result<Vec<RoomUnread>, String> {
+    let mut counts = Vec::new();
+    let (active_ids, handles) = {
+        let parties = state.parties.lock().await;
+        (parties.room_ids(), parties.handles())
+    };
+
+    for handle in handles {
+        let session = handle.lock().await;
+        counts.push(session.room.unread_summary(session.local_user.id));
+    }
+
+    // In saved rooms we're whichever member joined with our identity key
+    let identity_key = state.identity.public_key();
+    let saved_rooms = state.store.list_rooms()
+        .map_err(|e| format!("Failed to list saved rooms: {}", e))?;
+    for saved_id in saved_rooms {
+        if active_ids.contains(&saved_id) {
+            continue;
+        }
+        let room = match state.store.load_room(saved_id) {
+            Ok(room) => room,
+            Err(e) => {
+                eprintln!("Failed to load room {} for unread counts: {}", saved_id, e);
+                continue;
+            }
+        };
+        let member_id = room.users.values()
+            .find(|user| user.identity_key.as_deref() == Some(identity_key.as_str()))
+            .map(|user| user.id);
+        if let Some(member_id) = member_id {
+            counts.push(room.unread_summary(member_id));
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Moves our read marker forward unless read receipts are turned off, in
+/// which case we keep no marker that could be synced to others.
+fn share_read_marker(state: &AppState, party: &mut Room, user_id: Uuid, channel_id: Uuid) -> Option<crate::receipts::ReceiptUpdate> {
+    match state.store.read_receipts_enabled() {
+        Ok(true) => party.record_own_read(user_id, channel_id),
+        Ok(false) => None,
+        Err(e) => {
+            eprintln!("Failed to read receipt settings: {}", e);
+            None
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn get_receipts(
+    state: State<'_, AppState>,
+    room_id: String,
+    message_id: String,
+) -> Result<MessageReceipts, String> {
+    let message_uuid = Uuid::parse_str(&message_id)
+        .map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let session = party_session(&state, &room_id).await?;
+    let session = session.lock().await;
+    session.room.message_receipts(message_uuid)
+        .ok_or_else(|| "Message not found".to_string())
+}
+
+#[tauri::command]
+pub async fn set_read_receipts(
+    state: State<'_, AppState>,
+    enabled: bool,
+) -> Result<(), String> {
+    state.store.set_read_receipts_enabled(enabled)
+        .map_err(|e| format!("Failed to save receipt settings: {}", e))?;
+
+    // Markers already shared stay where they are; we just stop moving them
+    println!("👁️ Read receipts {}", if enabled { "enabled" } else { "disabled" });
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_read_receipts(
+    state: State<'_, AppState>,
+) -> Result<bool, String> {
+    state.store.read_receipts_enabled()
+        .map_err(|e| format!("Failed to load receipt settings: {}", e))
+}
+
+#[tauri::command]
+pub async fn get_notification_policy(
+    state: State<'_, AppState>,
+) -> Result<NotificationPolicy, String> {
+    state.store.notification_policy()
+        .map_err(|e| format!("Failed to load notification settings: {}", e))
+}
+
+#[tauri::command]
+pub async fn set_notification_policy(
+    state: State<'_, AppState>,
+    policy: NotificationPolicy,
+) -> Result<(), String> {
+    state.store.set_notification_policy(&policy)
+        .map_err(|e| format!("Failed to save notification settings: {}", e))?;
+
+    println!("🔔 Notification level set to {:?} ({} room overrides)", policy.level, policy.rooms.len());
+    Ok(())
+}
+
+/// Overrides the notification level for one room; `None` goes back to
+/// the default level.
+#[tauri::command]
+pub async fn set_room_notifications(
+    state: State<'_, AppState>,
+    room_id: String,
+    level: Option<NotificationLevel>,
+) -> Result<NotificationPolicy, String> {
+    let room_uuid = Uuid::parse_str(&room_id)
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    let mut policy = state.store.notification_policy()
+        .map_err(|e| format!("Failed to load notification settings: {}", e))?;
+    match level {
+        Some(level) => policy.rooms.insert(room_uuid, level),
+        None => policy.rooms.remove(&room_uuid),
+    };
+    state.store.set_notification_policy(&policy)
+        .map_err(|e| format!("Failed to save notification settings: {}", e))?;
+    Ok(policy)
+}
+
+/// Offers a file to the room. The file stays where it is and chunks are
+/// read from it as members ask for them.
+#[tauri::command]
+pub async fn share_file(
+    state: State<'_, AppState>,
+    room_id: String,
+    channel_id: Option<String>,
+    path: String,
+    caption: Option<String>,
+) -> Result<ChatMessage, String> {
+    let path = std::path::PathBuf::from(path);
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    let channel_uuid = parse_channel_id(party, channel_id)?;
+
+    if !party.has_permission(local_user.id, Permission::SendMessages) {
+        return Err("You don't have permission to send messages".to_string());
+    }
+    if party.is_muted(local_user.id) {
+        return Err("You are muted in this party".to_string());
+    }
+
+    let size = std::fs::metadata(&path)
+        .map_err(|e| format!("Failed to read file: {}", e))?
+        .len();
+    if size > party.file_size_limit() {
+        return Err(format!("Can't share file: larger than this room allows ({} bytes)", party.file_size_limit()));
+    }
 
-    // Try to connect to peers in order
-    let mut networking = state.networking.lock().await;
-    let mut connected = false;
+    // Images are shared as a cleaned copy with a thumbnail for the message
+    let copies_dir = state.store.shared_copies_dir()
+        .map_err(|e| format!("Failed to find shared files directory: {}", e))?;
+    let (path, image) = match media::prepare_image(&path, &copies_dir)
+        .map_err(|e| format!("Can't share image: {}", e))?
+    {
+        Some((copy, preview)) => (copy, Some(preview)),
+        None => (path, None),
+    };
 
-    // Try primary peer first
-    if let Some(primary_peer) = invite_data.get_primary_peer() {
-        if let Ok(_) = networking.connect_to_peer(primary_peer, invite_data.protocol.clone()).await {
-            connected = true;
-        }
+    let mut manifest = FileManifest::from_file(&path)
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+    manifest.image = image;
+    manifest.validate(party.file_size_limit())
+        .map_err(|e| format!("Can't share file: {}", e))?;
+    state.store.save_shared_file(party.id, manifest.id, &path)
+        .map_err(|e| format!("Failed to save shared file: {}", e))?;
+
+    let mut message = ChatMessage::new(local_user.id, local_user.name.clone(), caption.unwrap_or_default());
+    message.channel_id = channel_uuid;
+    message.attachment = Some(manifest);
+    party.add_message(message.clone());
+    party.mark_channel_read(channel_uuid);
+
+    if let Err(e) = state.store.save_room(party) {
+        eprintln!("Failed to save message: {}", e);
     }
 
-    // Try fallback peers if primary failed
-    if !connected {
-        for peer_addr in invite_data.get_fallback_peers() {
-            if let Ok(_) = networking.connect_to_peer(peer_addr, invite_data.protocol.clone()).await {
-                connected = true;
-                break;
-            }
-        }
+    let network_message = NetworkMessage::new(
+        local_user.id.to_string(),
+        None,
+        MessageType::ChatMessage,
+        serde_json::to_value(&message).map_err(|e| e.to_string())?,
+    );
+    if let Err(e) = networking.broadcast_message(network_message).await {
+        eprintln!("Failed to broadcast file offer: {}", e);
     }
 
-    if !connected {
-        return Err("Could not connect to any peers in the party".to_string());
-    }
+    println!("📤 Shared '{}' ({} bytes) in party '{}'", path.display(), message.attachment.as_ref().map_or(0, |f| f.size), party.name);
+    Ok(message)
+}
 
-    // Create party representation (session-based, no persistence)
-    let mut party = Room::new(invite_data.room_name, user_clone, invite_data.protocol);
-    party.id = invite_data.room_id;
-    party.peer_addresses = invite_data.peer_addresses;
+/// Starts fetching a file offered in the room, or picks up where an
+/// earlier attempt left off. Progress arrives as "file-progress" events.
+#[tauri::command]
+pub async fn download_file(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    room_id: String,
+    file_id: String,
+) -> Result<TransferProgress, String> {
+    let file_uuid = Uuid::parse_str(&file_id)
+        .map_err(|e| format!("Invalid file ID: {}", e))?;
+
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    if let Some(download) = session.downloads.get(&file_uuid) {
+        return Ok(download.progress());
+    }
 
-    println!("✅ Joined party '{}' with ID: {}", party.name, party.id);
+    let party = &session.room;
+    let offer = party.file_offer(file_uuid)
+        .ok_or_else(|| "File not found".to_string())?;
+    let manifest = offer.attachment.clone()
+        .ok_or_else(|| "File not found".to_string())?;
+    if offer.user_id == session.local_user.id {
+        return Err("You shared this file".to_string());
+    }
+    manifest.validate(party.file_size_limit())
+        .map_err(|e| format!("Can't download file: {}", e))?;
+
+    let dir = state.store.downloads_dir()
+        .map_err(|e| format!("Failed to find downloads directory: {}", e))?;
+    let download = Download::start(party.id, offer.user_id, manifest, &dir)
+        .map_err(|e| format!("Failed to start download: {}", e))?;
+    let progress = download.progress();
+    println!("📥 Downloading '{}' ({} of {} bytes already here)", progress.name, progress.received_bytes, progress.size);
+
+    let complete = download.is_complete();
+    session.downloads.insert(file_uuid, download);
+    if complete {
+        crate::handlers::finish_download(&app, &mut session, file_uuid);
+    } else {
+        crate::handlers::request_chunks(&mut session, file_uuid).await;
+    }
+    Ok(progress)
+}
 
-    // Set as current party
-    let mut current_party = state.current_party.lock().await;
-    *current_party = Some(party.clone());
+#[tauri::command]
+pub async fn cancel_download(
+    state: State<'_, AppState>,
+    room_id: String,
+    file_id: String,
+) -> Result<(), String> {
+    let file_uuid = Uuid::parse_str(&file_id)
+        .map_err(|e| format!("Invalid file ID: {}", e))?;
+
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let download = session.downloads.remove(&file_uuid)
+        .ok_or_else(|| "No download in progress for that file".to_string())?;
+    download.cancel()
+        .map_err(|e| format!("Failed to remove partial download: {}", e))
+}
 
-    Ok(party)
+#[tauri::command]
+pub async fn get_downloads_dir(
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    state.store.downloads_dir()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .map_err(|e| format!("Failed to find downloads directory: {}", e))
 }
 
 #[tauri::command]
-pub async fn leave_party(
+pub async fn set_downloads_dir(
     state: State<'_, AppState>,
+    path: String,
 ) -> Result<(), String> {
-    let mut current_party = state.current_party.lock().await;
-    
-    if current_party.is_some() {
-        let party = current_party.take().unwrap();
-        
-        println!("✅ Left party '{}' with ID: {}", party.name, party.id);
-        
-        // Notify other peers about leaving
-        // This would be implemented with the networking layer
-        
-        // Stop networking for the party
-        let mut networking = state.networking.lock().await;
-        if let Err(e) = networking.disconnect_all().await {
-            eprintln!("Warning: Failed to disconnect from peers: {}", e);
-        }
-        
-        Ok(())
-    } else {
-        Err("Not currently in a party".to_string())
+    let dir = std::path::PathBuf::from(path);
+    if !dir.is_absolute() {
+        return Err("Downloads directory must be an absolute path".to_string());
     }
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| format!("Failed to create downloads directory: {}", e))?;
+    state.store.set_downloads_dir(&dir)
+        .map_err(|e| format!("Failed to save downloads directory: {}", e))?;
+
+    println!("📁 Downloads now go to {}", dir.display());
+    Ok(())
 }
 
+/// Sets the largest file shared or fetched in this room; `None` goes back
+/// to the default.
 #[tauri::command]
-pub async fn send_message(
+pub async fn set_max_file_size(
     state: State<'_, AppState>,
-    content: String,
+    room_id: String,
+    bytes: Option<u64>,
+) -> Result<u64, String> {
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+
+    let setting = RoomSetting::MaxFileSize(bytes);
+    party.apply_setting(local_user.id, &setting)
+        .map_err(|e| format!("Failed to set maximum file size: {}", e))?;
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    println!("📏 Maximum file size for party '{}' set to {} bytes", party.name, party.file_size_limit());
+
+    let update = SettingsUpdate {
+        room_id: party.id,
+        setting,
+        changed_by: local_user.id,
+    };
+    broadcast_settings_update(networking, &update).await?;
+    Ok(party.file_size_limit())
+}
+
+/// Tells the party whether we're typing. Repeated calls while typing are
+/// throttled, so the UI can call this on every keystroke.
+#[tauri::command]
+pub async fn set_typing(
+    state: State<'_, AppState>,
+    room_id: String,
+    channel_id: Option<String>,
+    typing: bool,
 ) -> Result<(), String> {
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let channel_uuid = parse_channel_id(&session.room, channel_id)?;
 
-    let current_user = {
-        let current_user_guard = state.current_user.lock().await;
-        current_user_guard.as_ref()
-            .ok_or("No user configured")?
-            .clone()
+    if !session.typing.should_send(channel_uuid, typing, std::time::Instant::now()) {
+        return Ok(());
+    }
+
+    let notice = TypingNotice {
+        room_id: session.room.id,
+        channel_id: channel_uuid,
+        user_id: session.local_user.id,
+        typing,
     };
+    let message = NetworkMessage::new(
+        notice.user_id.to_string(),
+        None,
+        MessageType::Typing,
+        serde_json::to_value(&notice).unwrap(),
+    );
+    session.networking.broadcast_message(message).await
+        .map_err(|e| format!("Failed to send typing state: {}", e))
+}
+
+#[tauri::command]
+pub async fn send_direct_message(
+    state: State<'_, AppState>,
+    room_id: String,
+    user_id: String,
+    content: String,
+) -> Result<DirectMessage, String> {
+    let user_uuid = Uuid::parse_str(&user_id)
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+
+    let session = party_session(&state, &room_id).await?;
+    let session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &*session;
+
+    if user_uuid == local_user.id {
+        return Err("You can't send a direct message to yourself".to_string());
+    }
+    let recipient = party.users.get(&user_uuid)
+        .ok_or_else(|| "User not found in this party".to_string())?;
+
+    // The member list comes from the host, which could swap in a key of its own
+    let identity_key = recipient.identity_key.as_deref()
+        .ok_or_else(|| format!("{} has no identity key", recipient.name))?;
+    let pinned = state.store.pin_identity_key(party.id, user_uuid, identity_key)
+        .map_err(|e| format!("Failed to check identity key: {}", e))?;
+    if !pinned {
+        return Err(format!("{}'s identity key has changed since you first saw them", recipient.name));
+    }
 
-    let message = crate::room::ChatMessage {
+    let message = DirectMessage {
         id: Uuid::new_v4(),
-        user_id: current_user.id,
-        user_name: current_user.name.clone(),
+        room_id: party.id,
+        from: local_user.id,
+        to: user_uuid,
         content,
         timestamp: chrono::Utc::now(),
+        hlc: crate::clock::LOCAL.now(),
     };
+    let sealed = direct::seal(&state.identity, &message, recipient)
+        .map_err(|e| format!("Failed to encrypt direct message: {}", e))?;
 
-    // Update the current party
-    let mut current_party = state.current_party.lock().await;
-    if let Some(party) = current_party.as_mut() {
-        // Add message to the current party
-        party.add_message(message.clone());
-        
-        println!("✅ Message added to party '{}' (ID: {})", party.name, party.id);
-        println!("📁 Party '{}' now has {} messages", party.name, party.messages.len());
-
-        // Send message to other peers in the party
-        let networking = state.networking.lock().await;
-        let network_message = crate::networking::NetworkMessage {
-            id: Uuid::new_v4(),
-            from: current_user.id.to_string(),
-            to: None,
-            message_type: crate::networking::MessageType::ChatMessage,
-            payload: serde_json::to_value(&message).unwrap(),
-            timestamp: chrono::Utc::now(),
-        };
-
-        if let Err(e) = networking.broadcast_message(network_message).await {
-            eprintln!("Failed to broadcast message: {}", e);
-        }
+    state.store.save_direct_message(user_uuid, &message)
+        .map_err(|e| format!("Failed to save direct message: {}", e))?;
+    crate::handlers::send_direct_message(networking, &sealed).await
+        .map_err(|e| format!("Failed to send direct message: {}", e))?;
 
-        Ok(())
-    } else {
-        Err("Not currently in a party".to_string())
-    }
+    println!("✉️ Sent direct message to {} in party '{}'", recipient.name, party.name);
+    Ok(message)
 }
 
 #[tauri::command]
-pub async fn get_current_party(state: State<'_, AppState>) -> Result<Option<Room>, String> {
-    let current_party = state.current_party.lock().await;
-    Ok(current_party.clone())
+pub async fn get_direct_messages(
+    state: State<'_, AppState>,
+    room_id: String,
+    user_id: String,
+    limit: Option<usize>,
+) -> Result<Vec<DirectMessage>, String> {
+    let room_uuid = Uuid::parse_str(&room_id)
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let user_uuid = Uuid::parse_str(&user_id)
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    state.store.direct_messages(room_uuid, user_uuid, limit)
+        .map_err(|e| format!("Failed to load direct messages: {}", e))
 }
 
 #[tauri::command]
-pub async fn set_user_settings(
+pub async fn set_user_role(
     state: State<'_, AppState>,
-    settings: UserSettings,
+    room_id: String,
+    user_id: String,
+    role: Role,
 ) -> Result<(), String> {
-    let local_addr = local_ip_address::local_ip()
-        .map_err(|e| format!("Failed to get local IP: {}", e))?;
-    
-    let socket_addr = SocketAddr::new(local_addr, 8080);
-    
-    let mut user = User::new(settings.name, socket_addr);
-    user.set_avatar(settings.avatar);
-    user.set_audio_devices(settings.audio_input_device, settings.audio_output_device);
+    let user_uuid = Uuid::parse_str(&user_id)
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
 
-    let mut current_user = state.current_user.lock().await;
-    *current_user = Some(user);
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    party.set_role(local_user.id, user_uuid, role)
+        .map_err(|e| format!("Failed to change role: {}", e))?;
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    let change = RoleChange {
+        room_id: party.id,
+        user_id: user_uuid,
+        role,
+        changed_by: local_user.id,
+    };
+    let network_message = NetworkMessage::new(
+        local_user.id.to_string(),
+        None,
+        MessageType::RoleChange,
+        serde_json::to_value(&change).map_err(|e| e.to_string())?,
+    );
+
+    if let Err(e) = networking.broadcast_message(network_message).await {
+        eprintln!("Failed to broadcast role change: {}", e);
+    }
 
     Ok(())
 }
 
-#[tauri::command]
-pub async fn get_ping_stats(
-    _state: State<'_, AppState>,
-) -> Result<Vec<PingStats>, String> {
-    // This would be implemented with the ping manager
-    // For now, return empty stats
-    Ok(vec![])
+async fn moderate_user(
+    state: &AppState,
+    room_id: String,
+    user_id: String,
+    action: ModerationAction,
+    reason: Option<String>,
+) -> Result<(), String> {
+    let user_uuid = Uuid::parse_str(&user_id)
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+
+    let session = party_session(state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+    let command = ModerationCommand::new(party.id, user_uuid, action, reason, local_user.id, &state.identity)
+        .map_err(|e| format!("Failed to sign moderation command: {}", e))?;
+
+    party.apply_moderation(&command)
+        .map_err(|e| format!("Moderation failed: {}", e))?;
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    let network_message = NetworkMessage::new(
+        local_user.id.to_string(),
+        None,
+        MessageType::Moderation,
+        serde_json::to_value(&command).map_err(|e| e.to_string())?,
+    );
+
+    if let Err(e) = networking.broadcast_message(network_message).await {
+        eprintln!("Failed to broadcast moderation command: {}", e);
+    }
+
+    Ok(())
+}
+
+fn expiry_from_minutes(duration_minutes: Option<i64>) -> Option<chrono::DateTime<chrono::Utc>> {
+    duration_minutes.map(|minutes| chrono::Utc::now() + chrono::Duration::minutes(minutes))
 }
 
 #[tauri::command]
-pub async fn change_protocol(
+pub async fn kick_user(
     state: State<'_, AppState>,
-    new_protocol: Protocol,
+    room_id: String,
+    user_id: String,
+    reason: Option<String>,
 ) -> Result<(), String> {
-    let mut current_party = state.current_party.lock().await;
-    if let Some(party) = current_party.as_mut() {
-        let _old_protocol = party.protocol.clone();
-        let peers = party.peer_addresses.clone();
-        
-        // Switch protocol in networking layer
-        let mut networking = state.networking.lock().await;
-        if let Err(e) = networking.switch_protocol(new_protocol.clone(), peers).await {
-            return Err(format!("Failed to switch protocol: {}", e));
-        }
-
-        // Update party
-        party.switch_protocol(new_protocol);
-        
-        println!("✅ Changed party protocol to {:?}", party.protocol);
+    moderate_user(&state, room_id, user_id, ModerationAction::Kick, reason).await
+}
 
-        Ok(())
-    } else {
-        Err("Not currently in a party".to_string())
-    }
+#[tauri::command]
+pub async fn ban_user(
+    state: State<'_, AppState>,
+    room_id: String,
+    user_id: String,
+    duration_minutes: Option<i64>,
+    reason: Option<String>,
+) -> Result<(), String> {
+    let until = expiry_from_minutes(duration_minutes);
+    moderate_user(&state, room_id, user_id, ModerationAction::Ban { until }, reason).await
 }
 
 #[tauri::command]
-pub async fn generate_invite(
+pub async fn unban_user(
     state: State<'_, AppState>,
-) -> Result<String, String> {
-    let current_party = state.current_party.lock().await;
-    if let Some(party) = current_party.as_ref() {
-        let current_user_guard = state.current_user.lock().await;
-        let current_user = current_user_guard.as_ref()
-            .ok_or("No user configured")?;
+    room_id: String,
+    user_id: String,
+) -> Result<(), String> {
+    moderate_user(&state, room_id, user_id, ModerationAction::Unban, None).await
+}
 
-        let invite_data = InviteData::new(
-            party.id,
-            party.name.clone(),
-            current_user.name.clone(),
-            party.peer_addresses.clone(),
-            party.protocol.clone(),
-        );
+#[tauri::command]
+pub async fn mute_user(
+    state: State<'_, AppState>,
+    room_id: String,
+    user_id: String,
+    duration_minutes: Option<i64>,
+    reason: Option<String>,
+) -> Result<(), String> {
+    let until = expiry_from_minutes(duration_minutes);
+    moderate_user(&state, room_id, user_id, ModerationAction::Mute { until }, reason).await
+}
 
-        let invite_code = invite_data.generate_invite_code()
-            .map_err(|e| format!("Failed to generate invite code: {}", e))?;
+#[tauri::command]
+pub async fn unmute_user(
+    state: State<'_, AppState>,
+    room_id: String,
+    user_id: String,
+) -> Result<(), String> {
+    moderate_user(&state, room_id, user_id, ModerationAction::Unmute, None).await
+}
 
-        Ok(invite_code)
-    } else {
-        Err("Not currently in a party".to_string())
-    }
+#[tauri::command]
+pub async fn get_moderation_log(
+    state: State<'_, AppState>,
+    room_id: String,
+) -> Result<Vec<ModerationLogEntry>, String> {
+    let session = party_session(&state, &room_id).await?;
+    let session = session.lock().await;
+    let party = &session.room;
+    Ok(party.moderation_log.clone())
 }
 
 #[tauri::command]
@@ -324,21 +1812,20 @@ pub async fn parse_invite(invite_code: String) -> Result<InviteData, String> {
 #[tauri::command]
 pub async fn check_room_health(
     state: State<'_, AppState>,
+    room_id: String,
 ) -> Result<bool, String> {
-    let mut current_party = state.current_party.lock().await;
-    if let Some(party) = current_party.as_mut() {
-        // Clean up offline users first (5 minute threshold)
-        party.cleanup_offline_users(5);
-        
-        // Check server health
-        let server_healthy = party.check_server_health();
-        
-        println!("🔍 Party health check: {}", if server_healthy { "healthy" } else { "unhealthy" });
-        
-        Ok(server_healthy)
-    } else {
-        Err("Not currently in a party".to_string())
-    }
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let party = &mut session.room;
+    // Clean up offline users first (5 minute threshold)
+    party.cleanup_offline_users(5);
+    
+    // Check server health
+    let server_healthy = party.check_server_health();
+    
+    println!("🔍 Party health check: {}", if server_healthy { "healthy" } else { "unhealthy" });
+    
+    Ok(server_healthy)
 }
 
 #[tauri::command]
@@ -347,25 +1834,21 @@ pub async fn mark_user_offline_cmd(
     room_id: String,
     user_id: String,
 ) -> Result<(), String> {
-    let room_uuid = Uuid::parse_str(&room_id)
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
     let user_uuid = Uuid::parse_str(&user_id)
         .map_err(|e| format!("Invalid user ID: {}", e))?;
 
-    let mut rooms = state.current_party.lock().await;
-    if let Some(room) = rooms.iter_mut().find(|r| r.id == room_uuid) {
-        room.mark_user_offline(user_uuid)
-            .map_err(|e| format!("Failed to mark user offline: {}", e))?;
-        
-        // Save updated room state
-        if let Err(e) = room.save_to_file() {
-            eprintln!("Failed to save room after marking user offline: {}", e);
-        }
-        
-        Ok(())
-    } else {
-        Err("Room not found".to_string())
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let room = &mut session.room;
+    room.mark_user_offline(user_uuid)
+        .map_err(|e| format!("Failed to mark user offline: {}", e))?;
+
+    // Save updated room state
+    if let Err(e) = state.store.save_room(room) {
+        eprintln!("Failed to save room after marking user offline: {}", e);
     }
+
+    Ok(())
 }
 
 #[tauri::command]
@@ -373,145 +1856,200 @@ pub async fn sync_messages(
     state: State<'_, AppState>,
     room_id: String,
 ) -> Result<Vec<crate::room::ChatMessage>, String> {
-    let room_uuid = Uuid::parse_str(&room_id)
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room, networking, local_user, .. } = &mut *session;
 
-    let mut rooms = state.current_party.lock().await;
-    if let Some(room) = rooms.iter_mut().find(|r| r.id == room_uuid) {
-        println!("🔄 Syncing messages for room '{}' (ID: {})", room.name, room.id);
-        
-        // In a real implementation, this would request message lists from all connected peers
-        // and merge them by timestamp. For now, we'll ensure proper order of this room's messages.
-        
-        // Sort THIS room's messages by timestamp to ensure proper order
-        room.messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
-        
-        println!("📋 Room '{}' has {} messages after sync", room.name, room.messages.len());
-        
-        // Save THIS room's updated data to its own file
-        if let Err(e) = room.save_to_file() {
-            eprintln!("Failed to save room '{}' after message sync: {}", room.name, e);
-        }
-        
-        Ok(room.messages.clone())
-    } else {
-        Err(format!("Room with ID {} not found for sync", room_uuid))
+    println!("🔄 Syncing messages for room '{}' (ID: {})", room.name, room.id);
+
+    // Merging keeps messages in a deterministic order; this only fixes up
+    // rooms saved before that was the case
+    room.messages.sort_by_key(|m| m.order_key());
+
+    // Peers answer our summary with whatever we're missing; the merge
+    // happens as their replies arrive
+    let summary = SyncMessage::Summary(crate::sync::summarize(room));
+    crate::handlers::send_room_sync(networking, local_user.id, None, summary).await;
+
+    println!("📋 Room '{}' has {} messages after sync", room.name, room.messages.len());
+
+    // Persist whatever changed in THIS room
+    if let Err(e) = state.store.save_room(room) {
+        eprintln!("Failed to save room '{}' after message sync: {}", room.name, e);
     }
+
+    Ok(room.messages.clone())
 }
 
 #[tauri::command]
 pub async fn get_room_messages(
     state: State<'_, AppState>,
     room_id: String,
-) -> Result<Vec<crate::room::ChatMessage>, String> {
-    let room_uuid = Uuid::parse_str(&room_id)
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
-
-    let rooms = state.current_party.lock().await;
-    if let Some(room) = rooms.iter().find(|r| r.id == room_uuid) {
-        Ok(room.messages.clone())
-    } else {
-        Err("Room not found".to_string())
+    channel_id: Option<String>,
+    cursor: Option<String>,
+    direction: Option<PageDirection>,
+    limit: Option<usize>,
+) -> Result<MessagePage, String> {
+    let cursor = cursor
+        .map(|cursor| MessageCursor::decode(&cursor))
+        .transpose()
+        .map_err(|e| format!("Invalid cursor: {}", e))?;
+
+    let direction = direction.unwrap_or(PageDirection::Before);
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let session = party_session(&state, &room_id).await?;
+    let session = session.lock().await;
+    let room = &session.room;
+    let channel_uuid = parse_channel_id(room, channel_id)?;
+    let mut page = room.page_messages(channel_uuid, cursor.as_ref(), direction, limit);
+
+    // History older than what's loaded in memory lives only in the database
+    let exhausted = direction == PageDirection::Before && !page.has_more_before;
+    let oldest = page.messages.first().map(MessageCursor::for_message).or(cursor);
+    if let Some(oldest) = oldest.filter(|_| exhausted && page.messages.len() < limit) {
+        let remaining = limit - page.messages.len();
+        let mut older = state.store.page_messages(room.id, channel_uuid, &oldest, PageDirection::Before, remaining)
+            .map_err(|e| format!("Failed to load older messages: {}", e))?;
+
+        if let Some(first) = older.first() {
+            page.has_more_before = older.len() == remaining;
+            page.before_cursor = Some(MessageCursor::for_message(first).encode());
+            older.append(&mut page.messages);
+            page.messages = older;
+        }
     }
+
+    Ok(page)
 }
 
 #[tauri::command]
-pub async fn join_call(
+pub async fn search_messages(
     state: State<'_, AppState>,
-) -> Result<(), String> {
-    let current_user = {
-        let current_user_guard = state.current_user.lock().await;
-        current_user_guard.as_ref()
-            .ok_or("No user configured")?
-            .clone()
+    query: String,
+    room_id: Option<String>,
+    author_id: Option<String>,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    limit: Option<usize>,
+) -> Result<Vec<SearchResult>, String> {
+    let room_uuid = room_id
+        .map(|id| Uuid::parse_str(&id))
+        .transpose()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let filter = SearchFilter {
+        author: author_id
+            .map(|id| Uuid::parse_str(&id))
+            .transpose()
+            .map_err(|e| format!("Invalid user ID: {}", e))?,
+        from,
+        to,
     };
 
-    let mut current_party = state.current_party.lock().await;
-    if let Some(party) = current_party.as_mut() {
-        // Find and update the user in the party
-        if let Some(user) = party.users.get_mut(&current_user.id) {
-            if !user.is_in_call {
-                let user_name = user.name.clone(); // Store name to avoid borrow conflicts
-                user.join_call();
-                
-                // Start call server if this is the first user joining
-                if !party.is_call_active {
-                    party.is_call_active = true;
-                    party.call_server_id = Some(current_user.id);
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let mut results = Vec::new();
+    let handles = state.parties.lock().await.handles();
+
+    // Active parties are searched in memory, which covers what isn't saved yet
+    for handle in handles {
+        let mut session = handle.lock().await;
+        if room_uuid.is_none_or(|id| id == session.room.id) {
+            results.extend(session.room.search_messages(&query, &filter));
+        }
+    }
+
+    // Everything saved, including history older than what's in memory,
+    // is searched in the database
+    let saved_rooms = state.store.list_rooms()
+        .map_err(|e| format!("Failed to list saved rooms: {}", e))?;
+    for saved_id in saved_rooms {
+        if room_uuid.is_some_and(|id| id != saved_id) {
+            continue;
+        }
+        match state.store.search_history(saved_id, &query, &filter, limit) {
+            Ok(hits) => {
+                for hit in hits {
+                    if !results.iter().any(|result| result.message.id == hit.message.id) {
+                        results.push(hit);
+                    }
                 }
-                
-                // Add system message
-                let system_message = ChatMessage {
-                    id: Uuid::new_v4(),
-                    user_id: Uuid::nil(), // System message
-                    user_name: "System".to_string(),
-                    content: format!("**{}** connected to call", user_name),
-                    timestamp: chrono::Utc::now(),
-                };
-                party.add_message(system_message);
-                
-                println!("✅ {} joined the call", user_name);
-                
-                Ok(())
-            } else {
-                Err("User is already in call".to_string())
             }
-        } else {
-            Err("User not found in party".to_string())
+            Err(e) => eprintln!("Failed to search history of room {}: {}", saved_id, e),
         }
-    } else {
-        Err("Not currently in a party".to_string())
     }
+
+    results.sort_by(|a, b| b.score.total_cmp(&a.score).then(b.message.timestamp.cmp(&a.message.timestamp)));
+    results.truncate(limit);
+    Ok(results)
+}
+
+#[tauri::command]
+pub async fn get_messages_around(
+    state: State<'_, AppState>,
+    room_id: String,
+    message_id: String,
+    limit: Option<usize>,
+) -> Result<MessagePage, String> {
+    let message_uuid = Uuid::parse_str(&message_id)
+        .map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let session = party_session(&state, &room_id).await?;
+    let session = session.lock().await;
+    session.room.messages_around(message_uuid, limit.unwrap_or(DEFAULT_PAGE_SIZE))
+        .ok_or_else(|| "Message not found".to_string())
+}
+
+#[tauri::command]
+pub async fn join_call(
+    state: State<'_, AppState>,
+    room_id: String,
+) -> Result<(), String> {
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
+
+    party.join_call(local_user.id).map_err(|e| e.to_string())?;
+    println!("✅ {} joined the call", local_user.name);
+
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    let update = CallUpdate { room_id: party.id, user_id: local_user.id, joined: true };
+    broadcast_call_update(networking, &update).await
 }
 
 #[tauri::command]
 pub async fn leave_call(
     state: State<'_, AppState>,
+    room_id: String,
 ) -> Result<(), String> {
-    let current_user = {
-        let current_user_guard = state.current_user.lock().await;
-        current_user_guard.as_ref()
-            .ok_or("No user configured")?
-            .clone()
-    };
+    let session = party_session(&state, &room_id).await?;
+    let mut session = session.lock().await;
+    let PartySession { room: party, networking, local_user, .. } = &mut *session;
 
-    let mut current_party = state.current_party.lock().await;
-    if let Some(party) = current_party.as_mut() {
-        // Find and update the user in the party
-        if let Some(user) = party.users.get_mut(&current_user.id) {
-            if user.is_in_call {
-                let user_name = user.name.clone(); // Store name to avoid borrow conflicts
-                user.leave_call();
-            } else {
-                return Err("User is not in call".to_string());
-            }
-        } else {
-            return Err("User not found in party".to_string());
-        }
-        
-        // Add system message
-        let system_message = ChatMessage {
-            id: Uuid::new_v4(),
-            user_id: Uuid::nil(), // System message
-            user_name: "System".to_string(),
-            content: format!("**{}** disconnected from call", current_user.name),
-            timestamp: chrono::Utc::now(),
-        };
-        party.add_message(system_message);
-        
-        // Check if no one is left in call
-        let users_in_call: Vec<_> = party.users.values().filter(|u| u.is_in_call).collect();
-        if users_in_call.is_empty() {
-            party.is_call_active = false;
-            party.call_server_id = None;
-        }
-        
-        println!("✅ {} left the call", current_user.name);
-        
-        Ok(())
-    } else {
-        Err("Not currently in a party".to_string())
+    party.leave_call(local_user.id).map_err(|e| e.to_string())?;
+    println!("✅ {} left the call", local_user.name);
+
+    state.store.save_room(party)
+        .map_err(|e| format!("Failed to save party: {}", e))?;
+
+    let update = CallUpdate { room_id: party.id, user_id: local_user.id, joined: false };
+    broadcast_call_update(networking, &update).await
+}
+
+/// Peers record the call events themselves once they've checked who sent it.
+async fn broadcast_call_update(networking: &NetworkManager, update: &CallUpdate) -> Result<(), String> {
+    let network_message = NetworkMessage::new(
+        update.user_id.to_string(),
+        None,
+        MessageType::CallUpdate,
+        serde_json::to_value(update).map_err(|e| e.to_string())?,
+    );
+
+    if let Err(e) = networking.broadcast_message(network_message).await {
+        eprintln!("Failed to broadcast call update: {}", e);
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -524,9 +2062,11 @@ mod tests {
 
     async fn create_test_app_state() -> AppState {
         AppState {
-            rooms: Arc::new(Mutex::new(Vec::new())),
+            parties: Arc::new(Mutex::new(crate::session::Parties::default())),
             current_user: Arc::new(Mutex::new(None)),
-            networking: Arc::new(Mutex::new(crate::networking::NetworkManager::new())),
+            identity: Arc::new(crate::identity::LocalIdentity::generate().unwrap()),
+            password_gate: Arc::new(Mutex::new(crate::password::PasswordGate::default())),
+            store: Arc::new(crate::storage::Store::open_in_memory().unwrap()),
         }
     }
 
@@ -544,20 +2084,18 @@ mod tests {
         }
         
         // Create a test room
-        let room = crate::room::Room::new("Test Room for Invite".to_string(), user, crate::networking::Protocol::TCP);
+        let room = crate::room::Room::new("Test Room for Invite".to_string(), user.clone(), crate::networking::Protocol::TCP);
         let room_id = room.id.to_string();
         
         // Add room to state
-        {
-            let mut rooms = state.current_party.lock().await;
-            rooms.push(room);
-        }
+        state.parties.lock().await.insert(PartySession::new(room, user));
         
         println!("🧪 Testing generate_invite command with room ID: {}", room_id);
         
         // Test the invite generation logic directly
-        let rooms = state.current_party.lock().await;
-        let room = rooms.iter().find(|r| r.id.to_string() == room_id).unwrap();
+        let session = party_session(&state, &room_id).await.unwrap();
+        let session = session.lock().await;
+        let room = &session.room;
         let current_user_guard = state.current_user.lock().await;
         let current_user = current_user_guard.as_ref().unwrap();
 
@@ -626,18 +2164,16 @@ mod tests {
         
         // Try to load existing rooms or create a test room with known ID
         let room_uuid = Uuid::parse_str(existing_room_id).unwrap();
-        let mut room = crate::room::Room::new("Existing Test Room".to_string(), user, crate::networking::Protocol::TCP);
+        let mut room = crate::room::Room::new("Existing Test Room".to_string(), user.clone(), crate::networking::Protocol::TCP);
         room.id = room_uuid;
         room.peer_addresses = vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 47)), 8080)];
         
-        {
-            let mut rooms = state.current_party.lock().await;
-            rooms.push(room);
-        }
+        state.parties.lock().await.insert(PartySession::new(room, user));
         
         // Test the invite generation logic directly
-        let rooms = state.current_party.lock().await;
-        let room = rooms.iter().find(|r| r.id.to_string() == existing_room_id).unwrap();
+        let session = party_session(&state, existing_room_id).await.unwrap();
+        let session = session.lock().await;
+        let room = &session.room;
         let current_user_guard = state.current_user.lock().await;
         let current_user = current_user_guard.as_ref().unwrap();
 
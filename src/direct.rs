@@ -0,0 +1,241 @@
+use serde::{Deserialize, Serialize};
+use base64::{Engine as _, engine::general_purpose};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::hkdf;
+use x25519_dalek::{PublicKey, StaticSecret};
+use uuid::Uuid;
+use anyhow::Result;
+use crate::clock::HlcTimestamp;
+use crate::identity::{self, LocalIdentity};
+use crate::user::User;
+
+const DM_INFO: &[u8] = b"shortgap dm";
+
+/// A direct message as kept locally by the two people in the conversation.
+/// Never stored with room history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectMessage {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub from: Uuid,
+    pub to: Uuid,
+    pub content: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub hlc: HlcTimestamp,
+}
+
+/// A direct message on the wire. The content is encrypted to the
+/// recipient's DM key with a fresh ephemeral key, so the server peer can
+/// relay it without reading it, and the envelope is signed by the sender's
+/// identity key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedDirectMessage {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub from: Uuid,
+    pub to: Uuid,
+    pub ephemeral_key: String,
+    pub nonce: String,
+    pub ciphertext: String,
+    pub sent_at: chrono::DateTime<chrono::Utc>,
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SealedContent {
+    content: String,
+    hlc: HlcTimestamp,
+}
+
+impl SealedDirectMessage {
+    fn signing_payload(&self) -> Vec<u8> {
+        format!(
+            "dm:{}:{}:{}:{}:{}:{}:{}:{}",
+            self.id,
+            self.room_id,
+            self.from,
+            self.to,
+            self.ephemeral_key,
+            self.nonce,
+            self.ciphertext,
+            self.sent_at.timestamp_millis(),
+        ).into_bytes()
+    }
+}
+
+/// The recipient's DM key, if it is signed by their identity key.
+pub fn verified_dm_key(user: &User) -> Option<[u8; 32]> {
+    let identity_key = user.identity_key.as_ref()?;
+    let dm_key = user.dm_key.as_ref()?;
+    let signature = user.dm_key_signature.as_ref()?;
+    if !identity::verify_signature(identity_key, &identity::dm_key_payload(dm_key), signature) {
+        return None;
+    }
+    general_purpose::URL_SAFE_NO_PAD.decode(dm_key).ok()?.try_into().ok()
+}
+
+fn message_key(shared: [u8; 32], ephemeral_key: &[u8; 32], recipient_key: &[u8; 32]) -> Result<LessSafeKey> {
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(ephemeral_key);
+    salt.extend_from_slice(recipient_key);
+
+    let mut key = [0u8; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &salt)
+        .extract(&shared)
+        .expand(&[DM_INFO], &CHACHA20_POLY1305)
+        .and_then(|okm| okm.fill(&mut key))
+        .map_err(|_| anyhow::anyhow!("Failed to derive message key"))?;
+
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+        .map_err(|_| anyhow::anyhow!("Invalid message key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn associated_data(id: Uuid, room_id: Uuid, from: Uuid, to: Uuid) -> Vec<u8> {
+    format!("{}:{}:{}:{}", id, room_id, from, to).into_bytes()
+}
+
+pub fn seal(sender: &LocalIdentity, message: &DirectMessage, recipient: &User) -> Result<SealedDirectMessage> {
+    let recipient_key = verified_dm_key(recipient)
+        .ok_or_else(|| anyhow::anyhow!("{} has no verified direct message key", recipient.name))?;
+
+    let ephemeral = StaticSecret::from(identity::random_bytes::<32>()?);
+    let ephemeral_key = *PublicKey::from(&ephemeral).as_bytes();
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(recipient_key)).to_bytes();
+    let key = message_key(shared, &ephemeral_key, &recipient_key)?;
+
+    let nonce = identity::random_bytes::<{ aead::NONCE_LEN }>()?;
+    let mut ciphertext = serde_json::to_vec(&SealedContent {
+        content: message.content.clone(),
+        hlc: message.hlc,
+    })?;
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(associated_data(message.id, message.room_id, message.from, message.to)),
+        &mut ciphertext,
+    ).map_err(|_| anyhow::anyhow!("Failed to encrypt direct message"))?;
+
+    let mut sealed = SealedDirectMessage {
+        id: message.id,
+        room_id: message.room_id,
+        from: message.from,
+        to: message.to,
+        ephemeral_key: general_purpose::URL_SAFE_NO_PAD.encode(ephemeral_key),
+        nonce: general_purpose::URL_SAFE_NO_PAD.encode(nonce),
+        ciphertext: general_purpose::URL_SAFE_NO_PAD.encode(ciphertext),
+        sent_at: message.timestamp,
+        signature: String::new(),
+    };
+    sealed.signature = sender.sign(&sealed.signing_payload());
+    Ok(sealed)
+}
+
+/// Checks the sender's signature and decrypts a message addressed to us.
+pub fn open(recipient: &LocalIdentity, sealed: &SealedDirectMessage, sender: &User) -> Result<DirectMessage> {
+    let identity_key = sender.identity_key.as_ref()
+        .ok_or_else(|| anyhow::anyhow!("{} has no identity key", sender.name))?;
+    if sender.id != sealed.from
+        || !identity::verify_signature(identity_key, &sealed.signing_payload(), &sealed.signature)
+    {
+        return Err(anyhow::anyhow!("Direct message signature is invalid"));
+    }
+
+    let ephemeral_key: [u8; 32] = general_purpose::URL_SAFE_NO_PAD.decode(&sealed.ephemeral_key)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid ephemeral key"))?;
+    let nonce: [u8; aead::NONCE_LEN] = general_purpose::URL_SAFE_NO_PAD.decode(&sealed.nonce)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid nonce"))?;
+    let mut ciphertext = general_purpose::URL_SAFE_NO_PAD.decode(&sealed.ciphertext)?;
+
+    let recipient_key: [u8; 32] = general_purpose::URL_SAFE_NO_PAD.decode(recipient.dm_public_key())?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid direct message key"))?;
+    let key = message_key(recipient.dm_agree(&ephemeral_key), &ephemeral_key, &recipient_key)?;
+
+    let plaintext = key.open_in_place(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(associated_data(sealed.id, sealed.room_id, sealed.from, sealed.to)),
+        &mut ciphertext,
+    ).map_err(|_| anyhow::anyhow!("Failed to decrypt direct message"))?;
+    let content: SealedContent = serde_json::from_slice(plaintext)?;
+
+    Ok(DirectMessage {
+        id: sealed.id,
+        room_id: sealed.room_id,
+        from: sealed.from,
+        to: sealed.to,
+        content: content.content,
+        timestamp: sealed.sent_at,
+        hlc: content.hlc,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_for(identity: &LocalIdentity, name: &str) -> User {
+        let mut user = User::new(name.to_string(), "127.0.0.1:8080".parse().unwrap());
+        user.identity_key = Some(identity.public_key());
+        user.dm_key = Some(identity.dm_public_key());
+        user.dm_key_signature = Some(identity.dm_key_signature());
+        user
+    }
+
+    fn message(from: &User, to: &User) -> DirectMessage {
+        DirectMessage {
+            id: Uuid::new_v4(),
+            room_id: Uuid::new_v4(),
+            from: from.id,
+            to: to.id,
+            content: "meet at the north gate".to_string(),
+            timestamp: chrono::Utc::now(),
+            hlc: HlcTimestamp::default(),
+        }
+    }
+
+    #[test]
+    fn test_only_the_recipient_can_open() {
+        let alice_identity = LocalIdentity::generate().unwrap();
+        let bob_identity = LocalIdentity::generate().unwrap();
+        let host_identity = LocalIdentity::generate().unwrap();
+        let alice = user_for(&alice_identity, "alice");
+        let bob = user_for(&bob_identity, "bob");
+
+        let sealed = seal(&alice_identity, &message(&alice, &bob), &bob).unwrap();
+        assert!(!sealed.ciphertext.contains("north gate"));
+
+        let opened = open(&bob_identity, &sealed, &alice).unwrap();
+        assert_eq!(opened.content, "meet at the north gate");
+        assert_eq!(opened.from, alice.id);
+
+        // The relaying host can't read it
+        assert!(open(&host_identity, &sealed, &alice).is_err());
+    }
+
+    #[test]
+    fn test_tampered_or_forged_messages_are_rejected() {
+        let alice_identity = LocalIdentity::generate().unwrap();
+        let bob_identity = LocalIdentity::generate().unwrap();
+        let mallory_identity = LocalIdentity::generate().unwrap();
+        let alice = user_for(&alice_identity, "alice");
+        let bob = user_for(&bob_identity, "bob");
+
+        let sealed = seal(&alice_identity, &message(&alice, &bob), &bob).unwrap();
+        let mut tampered = sealed.clone();
+        tampered.to = Uuid::new_v4();
+        assert!(open(&bob_identity, &tampered, &alice).is_err());
+
+        // Signed by someone else but claiming to be from alice
+        let mut forged = seal(&mallory_identity, &message(&alice, &bob), &bob).unwrap();
+        forged.from = alice.id;
+        assert!(open(&bob_identity, &forged, &alice).is_err());
+
+        // A DM key swapped in by a relay doesn't verify
+        let mut swapped = bob.clone();
+        swapped.dm_key = Some(mallory_identity.dm_public_key());
+        assert!(seal(&alice_identity, &message(&alice, &bob), &swapped).is_err());
+    }
+}
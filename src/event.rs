@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::moderation::ModerationAction;
+use crate::networking::Protocol;
+use crate::permissions::Role;
+
+/// Something that happened in a room, shown in the timeline between chat
+/// messages. Each peer records these itself when it applies a change it
+/// has verified; they are never accepted from other peers. Names are the
+/// ones at the time, since the user may be gone by the time it's shown.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RoomEvent {
+    UserJoined {
+        user_id: Uuid,
+        name: String,
+        #[serde(default)]
+        approved_by: Option<Uuid>,
+    },
+    UserLeft {
+        user_id: Uuid,
+        name: String,
+    },
+    JoinDenied {
+        user_id: Uuid,
+        name: String,
+        denied_by: Uuid,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    CallJoined {
+        user_id: Uuid,
+        name: String,
+    },
+    CallLeft {
+        user_id: Uuid,
+        name: String,
+    },
+    CallStarted {
+        started_by: Uuid,
+    },
+    CallEnded,
+    ProtocolChanged {
+        protocol: Protocol,
+        changed_by: Uuid,
+    },
+    ServerElected {
+        user_id: Option<Uuid>,
+    },
+    RoleChanged {
+        user_id: Uuid,
+        role: Role,
+        changed_by: Uuid,
+    },
+    RoomRenamed {
+        name: String,
+        renamed_by: Uuid,
+    },
+    Moderated {
+        target_user_id: Uuid,
+        target_name: String,
+        action: ModerationAction,
+        issued_by: Uuid,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
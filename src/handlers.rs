@@ -0,0 +1,1383 @@
+use std::net::SocketAddr;
+use tauri::{AppHandle, Manager};
+use uuid::Uuid;
+
+use crate::{AppState, networking::{MessageType, NetworkManager, NetworkMessage}, room::ChatMessage};
+use crate::join::{JoinDecision, JoinRequest, JoinResponse, PendingJoin};
+use crate::password::{self, PasswordCheck};
+use crate::moderation::{ModerationAction, ModerationCommand};
+use crate::networking::Protocol;
+use crate::permissions::{Permission, RoleChange};
+use crate::sync::{self, HistorySynced, SyncMessage};
+use crate::clock;
+use crate::identity::LocalIdentity;
+use crate::room::{CallUpdate, MessageEdit, MessageTombstone, PinUpdate, ReactionUpdate, Room, RoomRename, RoomSetting, SettingsUpdate};
+use crate::session::{PartySession, SessionHandle};
+use crate::channel::ChannelUpdate;
+use crate::direct::{self, SealedDirectMessage};
+use crate::typing::{Typist, TypingChanged, TypingNotice};
+use crate::receipts::ReceiptUpdate;
+use crate::transfer::{self, TransferMessage, TransferProgress};
+
+/// How often retention policies are applied to loaded and stored history.
+const COMPACTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// How often typists who went quiet are dropped.
+const TYPING_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+const TRANSFER_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct ReceiptsSynced {
+    room_id: Uuid,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct HistoryCompacted {
+    room_id: Uuid,
+    removed: usize,
+}
+
+/// Event payloads that don't carry a room id themselves get wrapped in
+/// this, so the UI knows which party they belong to.
+#[derive(Debug, Clone, serde::Serialize)]
+struct PartyEvent<'a, T> {
+    room_id: Uuid,
+    event: &'a T,
+}
+
+/// Handles incoming traffic for one party until its session goes away.
+pub async fn run_message_loop(app: AppHandle, session: SessionHandle) {
+    let state = app.state::<AppState>();
+
+    let receiver = session.lock().await.networking.message_receiver.take();
+    let mut receiver = match receiver {
+        Some(receiver) => receiver,
+        None => {
+            eprintln!("Network message receiver already taken, incoming messages will not be handled");
+            return;
+        }
+    };
+
+    while let Some(message) = receiver.recv().await {
+        let mut session = session.lock().await;
+        if !state.parties.lock().await.contains(session.room.id) {
+            break;
+        }
+        handle_network_message(&app, &state, &mut session, message).await;
+    }
+}
+
+pub async fn run_retention_compaction(app: AppHandle) {
+    let state = app.state::<AppState>();
+    let mut interval = tokio::time::interval(COMPACTION_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        let now = chrono::Utc::now();
+
+        let sessions = state.parties.lock().await.handles();
+        for session in sessions {
+            let mut session = session.lock().await;
+            let party = &mut session.room;
+            let removed = party.apply_retention(now);
+            if removed > 0 {
+                println!("🧹 Retention dropped {} messages from party '{}'", removed, party.name);
+                if let Err(e) = state.store.save_room(party) {
+                    eprintln!("Failed to save party after compaction: {}", e);
+                }
+                let event = HistoryCompacted { room_id: party.id, removed };
+                if let Err(e) = app.emit_all("history-compacted", &event) {
+                    eprintln!("Failed to emit compaction event: {}", e);
+                }
+            }
+        }
+
+        match state.store.compact_all(now) {
+            Ok(0) => {}
+            Ok(removed) => println!("🧹 Retention removed {} stored messages", removed),
+            Err(e) => eprintln!("Failed to compact stored history: {}", e),
+        }
+    }
+}
+
+pub async fn run_typing_expiry(app: AppHandle) {
+    let state = app.state::<AppState>();
+    let mut interval = tokio::time::interval(TYPING_SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        let now = std::time::Instant::now();
+
+        let sessions = state.parties.lock().await.handles();
+        for session in sessions {
+            let mut session = session.lock().await;
+            if session.typing.expire(now) {
+                emit_typing(&app, &session);
+            }
+        }
+    }
+}
+
+/// Re-asks for chunks whose requests went unanswered, so downloads carry
+/// on once the sender or the host is reachable again.
+pub async fn run_transfer_retries(app: AppHandle) {
+    let state = app.state::<AppState>();
+    let mut interval = tokio::time::interval(TRANSFER_RETRY_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let sessions = state.parties.lock().await.handles();
+        for session in sessions {
+            let mut session = session.lock().await;
+            let file_ids: Vec<Uuid> = session.downloads.keys().copied().collect();
+            for file_id in file_ids {
+                request_chunks(&mut session, file_id).await;
+            }
+        }
+    }
+}
+
+pub fn emit_unread(app: &AppHandle, room: &Room, reader_id: Uuid) {
+    if let Err(e) = app.emit_all("unread-changed", &room.unread_summary(reader_id)) {
+        eprintln!("Failed to emit unread event: {}", e);
+    }
+}
+
+/// Raises a desktop notification for a new message if our policy wants
+/// one. Mentions are read from the stored copy, which we resolved.
+fn raise_notification(app: &AppHandle, state: &AppState, room: &Room, message_id: Uuid, reader_id: Uuid) {
+    let Some(message) = room.messages.iter().find(|m| m.id == message_id) else {
+        return;
+    };
+    let policy = match state.store.notification_policy() {
+        Ok(policy) => policy,
+        Err(e) => {
+            eprintln!("Failed to load notification settings: {}", e);
+            return;
+        }
+    };
+
+    let now = chrono::Local::now().time();
+    if let Some(notification) = policy.notification_for(room, message, reader_id, now) {
+        if let Err(e) = app.emit_all("notification", &notification) {
+            eprintln!("Failed to emit notification event: {}", e);
+        }
+    }
+}
+
+fn emit_typing(app: &AppHandle, session: &PartySession) {
+    let typing = session.typing.typists()
+        .filter_map(|(user_id, channel_id)| {
+            let user = session.room.users.get(&user_id)?;
+            Some(Typist { user_id, name: user.name.clone(), channel_id })
+        })
+        .collect();
+    let event = TypingChanged { room_id: session.room.id, typing };
+    if let Err(e) = app.emit_all("typing-changed", &event) {
+        eprintln!("Failed to emit typing event: {}", e);
+    }
+}
+
+pub async fn handle_network_message(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    // Drop replays, forgeries and copies that arrived over more than one path
+    let sender_key = Uuid::parse_str(&message.from).ok()
+        .and_then(|user_id| session.room.users.get(&user_id))
+        .and_then(|user| user.identity_key.clone());
+    if session.networking.accept_incoming(&message, sender_key.as_deref()).is_err() {
+        return;
+    }
+
+    match message.message_type {
+        MessageType::ChatMessage => handle_chat_message(app, state, session, message).await,
+        MessageType::UserLeft => handle_user_left(app, state, session, message).await,
+        MessageType::Typing => handle_typing(app, session, message),
+        MessageType::Receipt => handle_receipt(app, state, session, message),
+        MessageType::FileTransfer => handle_file_transfer(app, state, session, message).await,
+        MessageType::JoinRequest => handle_join_request(app, state, session, message).await,
+        MessageType::JoinResponse => handle_join_response(app, state, session, message).await,
+        MessageType::ProtocolChange => handle_protocol_change(app, state, session, message).await,
+        MessageType::RoleChange => handle_role_change(app, state, session, message).await,
+        MessageType::RoomRename => handle_room_rename(app, state, session, message).await,
+        MessageType::RoomSettings => handle_room_settings(app, state, session, message).await,
+        MessageType::CallUpdate => handle_call_update(app, state, session, message).await,
+        MessageType::Moderation => handle_moderation(app, state, session, message).await,
+        MessageType::MessageEdit => handle_message_edit(app, state, session, message).await,
+        MessageType::MessageDelete => handle_message_delete(app, state, session, message).await,
+        MessageType::Reaction => handle_reaction(app, state, session, message).await,
+        MessageType::MessagePin => handle_message_pin(app, state, session, message).await,
+        MessageType::ChannelUpdate => handle_channel_update(app, state, session, message).await,
+        MessageType::DirectMessage => handle_direct_message(app, state, session, message).await,
+        MessageType::RoomSync => handle_room_sync(app, state, session, message).await,
+        _ => {}
+    }
+}
+
+/// Tears down a session we've been removed from or refused entry to.
+async fn close_session(state: &AppState, session: &mut PartySession) {
+    state.parties.lock().await.remove(session.room.id);
+    session.pending_join = None;
+    if let Err(e) = session.networking.disconnect_all().await {
+        eprintln!("Warning: Failed to disconnect from peers: {}", e);
+    }
+}
+
+async fn handle_chat_message(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let mut chat_message: ChatMessage = match serde_json::from_value(message.payload.clone()) {
+        Ok(chat_message) => chat_message,
+        Err(e) => {
+            eprintln!("Received malformed chat message from '{}': {}", message.from, e);
+            return;
+        }
+    };
+    if !chat_message.hlc.is_plausible() {
+        println!("🚫 Ignored message from {} stamped too far in the future", chat_message.user_id);
+        return;
+    }
+
+    // Events are recorded by each peer from changes it has verified, so
+    // one arriving as a chat message can only be forged
+    if sender_id(&message) != Some(chat_message.user_id) || chat_message.is_event() {
+        println!("🚫 Ignored chat message claiming to be from {} sent by '{}'", chat_message.user_id, message.from);
+        return;
+    }
+    // Image previews are shown as soon as they arrive, so check them now
+    if let Some(Err(e)) = chat_message.attachment.as_ref()
+        .and_then(|file| Some(file.image.as_ref()?.validate(&file.name)))
+    {
+        println!("🚫 Ignored message from {} with a bad image preview: {}", chat_message.user_id, e);
+        return;
+    }
+
+    if session.typing.clear(chat_message.user_id) {
+        emit_typing(app, session);
+    }
+
+    let party = &mut session.room;
+    if !party.has_permission(chat_message.user_id, Permission::SendMessages) {
+        println!("🚫 Ignored message from {} who may not send messages here", chat_message.user_id);
+        return;
+    }
+    if party.is_muted(chat_message.user_id) {
+        println!("🔇 Dropped message from muted user {}", chat_message.user_id);
+        return;
+    }
+
+    let is_new = !party.messages.iter().any(|m| m.id == chat_message.id);
+    chat_message.strip_peer_state();
+    party.add_message(chat_message.clone());
+    if let Err(e) = state.store.save_room(party) {
+        eprintln!("Failed to save received message: {}", e);
+    }
+
+    let event = PartyEvent { room_id: party.id, event: &chat_message };
+    if let Err(e) = app.emit_all("chat-message", &event) {
+        eprintln!("Failed to emit chat message event: {}", e);
+    }
+    if is_new {
+        emit_unread(app, party, session.local_user.id);
+        raise_notification(app, state, party, chat_message.id, session.local_user.id);
+        acknowledge(session, vec![chat_message.id]).await;
+    }
+}
+
+/// Tells the party these messages reached us.
+async fn acknowledge(session: &mut PartySession, message_ids: Vec<Uuid>) {
+    let update = ReceiptUpdate::Delivered {
+        room_id: session.room.id,
+        user_id: session.local_user.id,
+        message_ids,
+    };
+    if !session.room.apply_receipt(&update).unwrap_or(false) {
+        return;
+    }
+
+    if let Err(e) = send_receipt(&session.networking, &update).await {
+        eprintln!("Failed to send delivery receipt: {}", e);
+    }
+}
+
+pub async fn send_receipt(networking: &NetworkManager, update: &ReceiptUpdate) -> anyhow::Result<()> {
+    let message = NetworkMessage::new(
+        update.user_id().to_string(),
+        None,
+        MessageType::Receipt,
+        serde_json::to_value(update)?,
+    );
+    networking.broadcast_message(message).await
+}
+
+fn handle_receipt(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let update: ReceiptUpdate = match serde_json::from_value(message.payload.clone()) {
+        Ok(update) => update,
+        Err(e) => {
+            eprintln!("Received malformed receipt from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    if sender_id(&message) != Some(update.user_id()) {
+        println!("🚫 Ignored receipt claiming to be from {} sent by '{}'", update.user_id(), message.from);
+        return;
+    }
+
+    let party = &mut session.room;
+    match party.apply_receipt(&update) {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            println!("🚫 Rejected receipt from {}: {}", update.user_id(), e);
+            return;
+        }
+    }
+    if let Err(e) = state.store.save_room(party) {
+        eprintln!("Failed to save party after receipt: {}", e);
+    }
+
+    if let Err(e) = app.emit_all("receipt-updated", &update) {
+        eprintln!("Failed to emit receipt event: {}", e);
+    }
+}
+
+fn handle_typing(app: &AppHandle, session: &mut PartySession, message: NetworkMessage) {
+    let notice: TypingNotice = match serde_json::from_value(message.payload.clone()) {
+        Ok(notice) => notice,
+        Err(e) => {
+            eprintln!("Received malformed typing notice from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    if sender_id(&message) != Some(notice.user_id) || notice.room_id != session.room.id {
+        return;
+    }
+    // Muted members and strangers can't appear to be typing
+    let party = &session.room;
+    if !party.has_permission(notice.user_id, Permission::SendMessages) || party.is_muted(notice.user_id) {
+        return;
+    }
+
+    if session.typing.apply(&notice, std::time::Instant::now()) {
+        emit_typing(app, session);
+    }
+}
+
+async fn handle_user_left(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let user_id: Uuid = match serde_json::from_value(message.payload.clone()) {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            eprintln!("Received malformed leave notice from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    // Only members themselves can say they left
+    if sender_id(&message) != Some(user_id) {
+        println!("🚫 Ignored leave notice for {} sent by '{}'", user_id, message.from);
+        return;
+    }
+
+    if session.typing.clear(user_id) {
+        emit_typing(app, session);
+    }
+
+    let party = &mut session.room;
+    if let Err(e) = party.user_left(user_id) {
+        println!("🚫 Rejected leave notice from {}: {}", user_id, e);
+        return;
+    }
+    if let Err(e) = state.store.save_room(party) {
+        eprintln!("Failed to save party after a member left: {}", e);
+    }
+
+    let event = PartyEvent { room_id: party.id, event: &user_id };
+    if let Err(e) = app.emit_all("user-left", &event) {
+        eprintln!("Failed to emit user left event: {}", e);
+    }
+}
+
+async fn handle_message_edit(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let edit: MessageEdit = match serde_json::from_value(message.payload.clone()) {
+        Ok(edit) => edit,
+        Err(e) => {
+            eprintln!("Received malformed message edit from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    if sender_id(&message) != Some(edit.edited_by) {
+        println!("🚫 Ignored message edit claiming to be from {} sent by '{}'", edit.edited_by, message.from);
+        return;
+    }
+
+    let party = &mut session.room;
+    if party.id == edit.room_id {
+        match party.edit_message(&edit) {
+            Ok(edited) => {
+                let edited = edited.clone();
+                if let Err(e) = state.store.save_room(party) {
+                    eprintln!("Failed to save party after message edit: {}", e);
+                }
+                if let Err(e) = app.emit_all("message-edited", &edited) {
+                    eprintln!("Failed to emit message edit event: {}", e);
+                }
+            }
+            Err(e) => println!("🚫 Rejected edit of message {}: {}", edit.message_id, e),
+        }
+    }
+}
+
+async fn handle_message_delete(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let tombstone: MessageTombstone = match serde_json::from_value(message.payload.clone()) {
+        Ok(tombstone) => tombstone,
+        Err(e) => {
+            eprintln!("Received malformed message deletion from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    if sender_id(&message) != Some(tombstone.deleted_by) {
+        println!("🚫 Ignored message deletion claiming to be from {} sent by '{}'", tombstone.deleted_by, message.from);
+        return;
+    }
+
+    let party = &mut session.room;
+    if party.id == tombstone.room_id {
+        match party.delete_message(tombstone.clone()) {
+            Ok(()) => {
+                if let Err(e) = state.store.save_room(party) {
+                    eprintln!("Failed to save party after message deletion: {}", e);
+                }
+                if let Err(e) = app.emit_all("message-deleted", &tombstone) {
+                    eprintln!("Failed to emit message deletion event: {}", e);
+                }
+                emit_unread(app, party, session.local_user.id);
+            }
+            Err(e) => println!("🚫 Rejected deletion of message {}: {}", tombstone.message_id, e),
+        }
+    }
+}
+
+async fn handle_reaction(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let update: ReactionUpdate = match serde_json::from_value(message.payload.clone()) {
+        Ok(update) => update,
+        Err(e) => {
+            eprintln!("Received malformed reaction from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    // Users only ever toggle their own reactions
+    if sender_id(&message) != Some(update.user_id) {
+        println!("🚫 Ignored reaction claiming to be from {} sent by '{}'", update.user_id, message.from);
+        return;
+    }
+
+    let party = &mut session.room;
+    if !party.has_permission(update.user_id, Permission::SendMessages) {
+        println!("🚫 Ignored reaction from {} who may not react here", update.user_id);
+        return;
+    }
+    if party.id == update.room_id {
+        match party.apply_reaction(&update) {
+            Ok(true) => {
+                if let Err(e) = state.store.save_room(party) {
+                    eprintln!("Failed to save party after reaction: {}", e);
+                }
+                if let Err(e) = app.emit_all("reaction-updated", &update) {
+                    eprintln!("Failed to emit reaction event: {}", e);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => println!("🚫 Rejected reaction on message {}: {}", update.message_id, e),
+        }
+    }
+}
+
+async fn handle_message_pin(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let update: PinUpdate = match serde_json::from_value(message.payload.clone()) {
+        Ok(update) => update,
+        Err(e) => {
+            eprintln!("Received malformed pin update from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    if sender_id(&message) != Some(update.pinned_by) {
+        println!("🚫 Ignored pin update claiming to be from {} sent by '{}'", update.pinned_by, message.from);
+        return;
+    }
+
+    let party = &mut session.room;
+    if party.id == update.room_id {
+        match party.set_pinned(&update) {
+            Ok(true) => {
+                if let Err(e) = state.store.save_room(party) {
+                    eprintln!("Failed to save party after pin update: {}", e);
+                }
+                if let Err(e) = app.emit_all("message-pinned", &update) {
+                    eprintln!("Failed to emit pin event: {}", e);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => println!("🚫 Rejected pin update on message {}: {}", update.message_id, e),
+        }
+    }
+}
+
+async fn handle_join_request(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let request: JoinRequest = match serde_json::from_value(message.payload.clone()) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("Received malformed join request from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    let local_user_id = session.local_user.id;
+    let party = &mut session.room;
+
+    // Only the server peer runs the join handshake
+    if !party.is_user_server(local_user_id) {
+        return;
+    }
+    // The joiner sends their own request, signed with the key it names;
+    // otherwise a captured one could be passed off from elsewhere
+    if sender_id(&message) != Some(request.user.id) || !message.is_signed_by(&request.identity_key) {
+        println!("🚫 Ignored join request for {} sent by '{}'", request.user.id, message.from);
+        return;
+    }
+
+    let joiner_id = request.user.id;
+    let joiner_addr = request.user.address;
+
+    let decision = if party.find_ban(&request.user).is_some() {
+        println!("🚫 Refused banned user '{}' ({})", request.user.name, request.user.address);
+        JoinDecision::Denied(Some("You are banned from this room".to_string()))
+    } else {
+        match check_room_password(state, party, &request).await {
+            Some(decision) => decision,
+            None => match party.handle_join_request(request.clone()) {
+                Ok(decision) => decision,
+                Err(e) => {
+                    println!("🚫 Rejected join request from '{}': {}", request.user.name, e);
+                    JoinDecision::Denied(Some(e.to_string()))
+                }
+            },
+        }
+    };
+
+    if decision == JoinDecision::Pending {
+        if let Err(e) = app.emit_all("join-request", &request) {
+            eprintln!("Failed to emit join request event: {}", e);
+        }
+    }
+
+    let room = match decision {
+        JoinDecision::Approved => Some(party.snapshot_for_peer()),
+        _ => None,
+    };
+    let response = JoinResponse {
+        room_id: party.id,
+        user_id: joiner_id,
+        decision,
+        decided_by: None,
+        room,
+    };
+    send_join_response(&session.networking, local_user_id, joiner_addr, response).await;
+}
+
+/// Runs the password challenge for protected rooms. Returns a decision to
+/// send back when the joiner hasn't (yet) proven knowledge of the password.
+async fn check_room_password(state: &AppState, party: &Room, request: &JoinRequest) -> Option<JoinDecision> {
+    if !party.requires_password() {
+        return None;
+    }
+
+    if !request.verify() {
+        return Some(JoinDecision::Denied(Some("Join request signature is invalid".to_string())));
+    }
+    // Members reconnecting after a drop were already checked
+    let returning = party.users.get(&request.user.id)
+        .is_some_and(|member| member.identity_key.as_deref() == Some(request.identity_key.as_str()));
+    if returning {
+        return None;
+    }
+
+    // Only whoever set the password holds its verifier; until they host
+    // again nobody new gets in
+    let password = match party.password.as_ref() {
+        Some(password) => password,
+        None => {
+            return Some(JoinDecision::Denied(Some("The current host can't check the room password, try again later".to_string())));
+        }
+    };
+
+    // The address is covered by the request signature checked above
+    let check = state.password_gate.lock().await.check(
+        password,
+        party.id,
+        request.user.id,
+        request.user.address.ip(),
+        request.password_proof.as_ref(),
+    );
+
+    match check {
+        Ok(PasswordCheck::Accepted) => None,
+        Ok(PasswordCheck::Challenge(challenge)) => Some(JoinDecision::PasswordRequired(challenge)),
+        Ok(PasswordCheck::Rejected) => {
+            println!("🔒 Wrong room password from '{}' ({})", request.user.name, request.user.address);
+            Some(JoinDecision::Denied(Some("Incorrect room password".to_string())))
+        }
+        Ok(PasswordCheck::RateLimited) => {
+            Some(JoinDecision::Denied(Some("Too many failed password attempts, try again later".to_string())))
+        }
+        Err(e) => Some(JoinDecision::Denied(Some(e.to_string()))),
+    }
+}
+
+async fn handle_join_response(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let response: JoinResponse = match serde_json::from_value(message.payload.clone()) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Received malformed join response from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    let local_user_id = session.local_user.id;
+    if response.user_id != local_user_id || response.room_id != session.room.id {
+        return;
+    }
+
+    // Only the host we asked gets to decide, and only while we're waiting
+    let from_host = session.pending_join.as_ref().is_some_and(|pending| pending.is_from_host(&message));
+    if !from_host {
+        eprintln!("Ignored join response from '{}' that isn't signed by the host", message.from);
+        return;
+    }
+
+    match &response.decision {
+        JoinDecision::Approved => {
+            let party = &mut session.room;
+            // Adopt the server's view of the room, including members and roles
+            if let Some(snapshot) = response.room.clone().filter(|room| room.users.contains_key(&local_user_id)) {
+                party.adopt_snapshot(snapshot);
+            }
+            party.awaiting_approval = false;
+            println!("✅ Join request for party '{}' was approved", party.name);
+            if let Err(e) = state.store.save_room(party) {
+                eprintln!("Failed to save party: {}", e);
+            }
+
+            // The snapshot carries the server's history; other peers may know more
+            let summary = SyncMessage::Summary(sync::summarize(party));
+            send_room_sync(&session.networking, local_user_id, None, summary).await;
+            session.pending_join = None;
+        }
+        JoinDecision::Pending => {
+            session.room.awaiting_approval = true;
+        }
+        JoinDecision::PasswordRequired(challenge) => {
+            if let Some(pending) = session.pending_join.as_mut() {
+                pending.challenge = Some(challenge.clone());
+
+                // Answer right away if the password was given up front
+                if pending.password.is_some() {
+                    if let Err(e) = answer_password_challenge(&session.networking, &state.identity, pending).await {
+                        eprintln!("Failed to answer password challenge: {}", e);
+                    }
+                    return;
+                }
+            }
+        }
+        JoinDecision::Denied(reason) => {
+            println!("🚫 Join request was denied: {}", reason.as_deref().unwrap_or("no reason given"));
+            close_session(state, session).await;
+        }
+    }
+
+    if let Err(e) = app.emit_all("join-decision", &response) {
+        eprintln!("Failed to emit join decision event: {}", e);
+    }
+}
+
+async fn handle_protocol_change(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let sender_id = match signed_sender(&session.room, &message) {
+        Some(sender_id) => sender_id,
+        None => {
+            println!("🚫 Ignored protocol change from '{}' not signed with their identity key", message.from);
+            return;
+        }
+    };
+    let protocol: Protocol = match serde_json::from_value(message.payload) {
+        Ok(protocol) => protocol,
+        Err(e) => {
+            eprintln!("Received malformed protocol change from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    let party = &mut session.room;
+    if !party.has_permission(sender_id, Permission::ChangeProtocol) {
+        println!("🚫 Ignored protocol change from {} without permission", sender_id);
+        return;
+    }
+
+    party.switch_protocol(sender_id, protocol);
+    println!("🔀 Party protocol changed to {:?} by {}", party.protocol, sender_id);
+    if let Err(e) = state.store.save_room(party) {
+        eprintln!("Failed to save party after protocol change: {}", e);
+    }
+
+    let event = PartyEvent { room_id: party.id, event: &party.protocol };
+    if let Err(e) = app.emit_all("protocol-changed", &event) {
+        eprintln!("Failed to emit protocol change event: {}", e);
+    }
+}
+
+async fn handle_role_change(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let sender_id = match signed_sender(&session.room, &message) {
+        Some(sender_id) => sender_id,
+        None => {
+            println!("🚫 Ignored role change from '{}' not signed with their identity key", message.from);
+            return;
+        }
+    };
+    let change: RoleChange = match serde_json::from_value(message.payload) {
+        Ok(change) => change,
+        Err(e) => {
+            eprintln!("Received malformed role change from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    if change.changed_by != sender_id {
+        println!("🚫 Ignored role change relayed on behalf of another user");
+        return;
+    }
+
+    let party = &mut session.room;
+    if party.id == change.room_id {
+        // set_role re-checks that the sender is allowed to make this change
+        if let Err(e) = party.set_role(change.changed_by, change.user_id, change.role) {
+            println!("🚫 Rejected role change from {}: {}", sender_id, e);
+            return;
+        }
+        if let Err(e) = state.store.save_room(party) {
+            eprintln!("Failed to save party after role change: {}", e);
+        }
+
+        if let Err(e) = app.emit_all("role-changed", &change) {
+            eprintln!("Failed to emit role change event: {}", e);
+        }
+    }
+}
+
+async fn handle_room_rename(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let sender_id = match signed_sender(&session.room, &message) {
+        Some(sender_id) => sender_id,
+        None => {
+            println!("🚫 Ignored room rename from '{}' not signed with their identity key", message.from);
+            return;
+        }
+    };
+    let rename: RoomRename = match serde_json::from_value(message.payload) {
+        Ok(rename) => rename,
+        Err(e) => {
+            eprintln!("Received malformed room rename from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    if rename.renamed_by != sender_id {
+        println!("🚫 Ignored room rename relayed on behalf of another user");
+        return;
+    }
+
+    let party = &mut session.room;
+    if party.id == rename.room_id {
+        if let Err(e) = party.rename(rename.renamed_by, rename.name.clone()) {
+            println!("🚫 Rejected room rename from {}: {}", sender_id, e);
+            return;
+        }
+        if let Err(e) = state.store.save_room(party) {
+            eprintln!("Failed to save party after room rename: {}", e);
+        }
+
+        if let Err(e) = app.emit_all("room-renamed", &rename) {
+            eprintln!("Failed to emit room rename event: {}", e);
+        }
+    }
+}
+
+async fn handle_room_settings(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let sender_id = match signed_sender(&session.room, &message) {
+        Some(sender_id) => sender_id,
+        None => {
+            println!("🚫 Ignored settings change from '{}' not signed with their identity key", message.from);
+            return;
+        }
+    };
+    let update: SettingsUpdate = match serde_json::from_value(message.payload) {
+        Ok(update) => update,
+        Err(e) => {
+            eprintln!("Received malformed settings change from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    if update.changed_by != sender_id {
+        println!("🚫 Ignored settings change relayed on behalf of another user");
+        return;
+    }
+
+    let party = &mut session.room;
+    if party.id == update.room_id {
+        if let Err(e) = party.apply_setting(update.changed_by, &update.setting) {
+            println!("🚫 Rejected settings change from {}: {}", sender_id, e);
+            return;
+        }
+        // Stored history catches up on the next compaction pass
+        if matches!(update.setting, RoomSetting::Retention { .. }) {
+            party.apply_retention(chrono::Utc::now());
+        }
+        if let Err(e) = state.store.save_room(party) {
+            eprintln!("Failed to save party after settings change: {}", e);
+        }
+
+        if let Err(e) = app.emit_all("room-settings-changed", &update) {
+            eprintln!("Failed to emit settings change event: {}", e);
+        }
+    }
+}
+
+async fn handle_call_update(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let sender_id = match signed_sender(&session.room, &message) {
+        Some(sender_id) => sender_id,
+        None => {
+            println!("🚫 Ignored call update from '{}' not signed with their identity key", message.from);
+            return;
+        }
+    };
+    let update: CallUpdate = match serde_json::from_value(message.payload) {
+        Ok(update) => update,
+        Err(e) => {
+            eprintln!("Received malformed call update from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    if update.user_id != sender_id {
+        println!("🚫 Ignored call update relayed on behalf of another user");
+        return;
+    }
+
+    let party = &mut session.room;
+    if party.id != update.room_id {
+        return;
+    }
+    let result = if update.joined { party.join_call(sender_id) } else { party.leave_call(sender_id) };
+    if let Err(e) = result {
+        println!("🚫 Rejected call update from {}: {}", sender_id, e);
+        return;
+    }
+    if let Err(e) = state.store.save_room(party) {
+        eprintln!("Failed to save party after call update: {}", e);
+    }
+
+    if let Err(e) = app.emit_all("call-updated", &update) {
+        eprintln!("Failed to emit call update event: {}", e);
+    }
+}
+
+async fn handle_channel_update(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let sender_id = match signed_sender(&session.room, &message) {
+        Some(sender_id) => sender_id,
+        None => {
+            println!("🚫 Ignored channel update from '{}' not signed with their identity key", message.from);
+            return;
+        }
+    };
+    let update: ChannelUpdate = match serde_json::from_value(message.payload) {
+        Ok(update) => update,
+        Err(e) => {
+            eprintln!("Received malformed channel update from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    if update.updated_by != sender_id {
+        println!("🚫 Ignored channel update relayed on behalf of another user");
+        return;
+    }
+
+    let party = &mut session.room;
+    if party.id == update.room_id {
+        match party.apply_channel_update(&update) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                println!("🚫 Rejected channel update from {}: {}", sender_id, e);
+                return;
+            }
+        }
+        if let Err(e) = state.store.save_room(party) {
+            eprintln!("Failed to save party after channel update: {}", e);
+        }
+
+        if let Err(e) = app.emit_all("channel-updated", &update) {
+            eprintln!("Failed to emit channel update event: {}", e);
+        }
+        emit_unread(app, party, session.local_user.id);
+    }
+}
+
+async fn handle_direct_message(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let sender_id = match signed_sender(&session.room, &message) {
+        Some(sender_id) => sender_id,
+        None => {
+            println!("🚫 Ignored direct message from '{}' not signed with their identity key", message.from);
+            return;
+        }
+    };
+    let sealed: SealedDirectMessage = match serde_json::from_value(message.payload.clone()) {
+        Ok(sealed) => sealed,
+        Err(e) => {
+            eprintln!("Received malformed direct message from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    if sealed.from != sender_id || sealed.room_id != session.room.id {
+        println!("🚫 Ignored direct message relayed on behalf of another user");
+        return;
+    }
+    if !session.room.users.contains_key(&sealed.to) {
+        return;
+    }
+
+    // Not for us: the host passes it on without being able to read it
+    if sealed.to != session.local_user.id {
+        if session.networking.is_server {
+            if let Err(e) = session.networking.send_to_peer(&sealed.to.to_string(), message).await {
+                eprintln!("Failed to relay direct message: {}", e);
+            }
+        }
+        return;
+    }
+
+    let sender = match session.room.users.get(&sender_id) {
+        Some(sender) => sender,
+        None => return,
+    };
+    let pinned = sender.identity_key.as_deref()
+        .map(|identity_key| state.store.pin_identity_key(session.room.id, sender_id, identity_key));
+    if !matches!(pinned, Some(Ok(true))) {
+        println!("🚫 Rejected direct message from {} whose identity key has changed", sender_id);
+        return;
+    }
+    let direct_message = match direct::open(&state.identity, &sealed, sender) {
+        Ok(direct_message) => direct_message,
+        Err(e) => {
+            println!("🚫 Rejected direct message from {}: {}", sender_id, e);
+            return;
+        }
+    };
+
+    if let Err(e) = state.store.save_direct_message(sender_id, &direct_message) {
+        eprintln!("Failed to save direct message: {}", e);
+    }
+    if let Err(e) = app.emit_all("direct-message", &direct_message) {
+        eprintln!("Failed to emit direct message event: {}", e);
+    }
+}
+
+async fn handle_moderation(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let command: ModerationCommand = match serde_json::from_value(message.payload) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("Received malformed moderation command from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    let local_user_id = session.local_user.id;
+    let party = &mut session.room;
+    if party.id != command.room_id {
+        return;
+    }
+
+    // Signature, issuer key and permissions are all checked here
+    if let Err(e) = party.apply_moderation(&command) {
+        println!("🚫 Rejected moderation command {} from {}: {}", command.id, command.issued_by, e);
+        return;
+    }
+    if let Err(e) = state.store.save_room(party) {
+        eprintln!("Failed to save party after moderation: {}", e);
+    }
+
+    if let Err(e) = app.emit_all("moderation-action", &command) {
+        eprintln!("Failed to emit moderation event: {}", e);
+    }
+
+    let removed = matches!(command.action, ModerationAction::Kick | ModerationAction::Ban { .. });
+    if removed && command.target_user_id == local_user_id {
+        println!("👢 Removed from party '{}': {}", party.name, command.reason.as_deref().unwrap_or("no reason given"));
+        close_session(state, session).await;
+    }
+}
+
+async fn handle_room_sync(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let sync_message: SyncMessage = match serde_json::from_value(message.payload.clone()) {
+        Ok(sync_message) => sync_message,
+        Err(e) => {
+            eprintln!("Received malformed room sync from '{}': {}", message.from, e);
+            return;
+        }
+    };
+
+    let local_user_id = session.local_user.id;
+    let party = &mut session.room;
+    if party.id != sync_message.room_id() {
+        return;
+    }
+
+    // Only members get to read or feed history
+    let (sender_id, sender_addr) = match sender_id(&message).and_then(|id| party.users.get(&id)) {
+        Some(sender) => (sender.id, sender.address),
+        None => {
+            println!("🚫 Ignored room sync from non-member '{}'", message.from);
+            return;
+        }
+    };
+
+    let reply = match sync_message {
+        SyncMessage::Summary(summary) => sync::request_missing(party, &summary),
+        SyncMessage::Request { buckets, known, .. } => Some(sync::answer_request(party, &buckets, &known)),
+        SyncMessage::Messages { messages, tombstones, receipts, .. } => {
+            let messages: Vec<ChatMessage> = messages.into_iter().filter(|m| m.hlc.is_plausible()).collect();
+            if let Some(latest) = messages.iter().map(|m| m.hlc).max() {
+                clock::LOCAL.observe(latest);
+            }
+
+            let offered: Vec<Uuid> = messages.iter()
+                .filter(|m| !m.is_event() && !party.messages.iter().any(|local| local.id == m.id))
+                .map(|m| m.id)
+                .collect();
+            let merged = party.merge_synced(sender_id, messages, tombstones);
+            let receipts_changed = party.merge_receipts(sender_id, receipts);
+            if merged > 0 || receipts_changed {
+                if let Err(e) = state.store.save_room(party) {
+                    eprintln!("Failed to save party after sync: {}", e);
+                }
+            }
+            if merged > 0 {
+                println!("🔄 Merged {} messages into party '{}' from '{}'", merged, party.name, message.from);
+                let event = HistorySynced { room_id: party.id, merged };
+                if let Err(e) = app.emit_all("history-synced", &event) {
+                    eprintln!("Failed to emit history sync event: {}", e);
+                }
+                emit_unread(app, party, local_user_id);
+            }
+
+            if receipts_changed {
+                let event = ReceiptsSynced { room_id: party.id };
+                if let Err(e) = app.emit_all("receipts-synced", &event) {
+                    eprintln!("Failed to emit receipt sync event: {}", e);
+                }
+            }
+
+            // Messages that only reached us through sync still count as delivered
+            let arrived: Vec<Uuid> = offered.into_iter()
+                .filter(|id| party.messages.iter().any(|m| m.id == *id))
+                .collect();
+            if !arrived.is_empty() {
+                acknowledge(session, arrived).await;
+            }
+            None
+        }
+    };
+
+    if let Some(reply) = reply {
+        send_room_sync(&session.networking, local_user_id, Some(sender_addr), reply).await;
+    }
+}
+
+/// The sender of a message that changes the room for everyone, if they're
+/// a member and signed it with the identity key we hold for them.
+fn signed_sender(room: &Room, message: &NetworkMessage) -> Option<Uuid> {
+    let sender_id = sender_id(message)?;
+    let identity_key = room.users.get(&sender_id)?.identity_key.as_deref()?;
+    message.is_signed_by(identity_key).then_some(sender_id)
+}
+
+fn sender_id(message: &NetworkMessage) -> Option<Uuid> {
+    match Uuid::parse_str(&message.from) {
+        Ok(sender_id) => Some(sender_id),
+        Err(_) => {
+            eprintln!("Ignoring {:?} from unidentified sender '{}'", message.message_type, message.from);
+            None
+        }
+    }
+}
+
+/// Sends to one member, through the host unless we're connected to them.
+async fn send_to_member(networking: &NetworkManager, to: Uuid, message: NetworkMessage) -> anyhow::Result<()> {
+    let to = to.to_string();
+    let peer = match &networking.server_peer {
+        Some(server_peer) if !networking.connections.contains_key(&to) => server_peer.clone(),
+        _ => to,
+    };
+    networking.send_to_peer(&peer, message).await
+}
+
+pub async fn send_direct_message(networking: &NetworkManager, sealed: &SealedDirectMessage) -> anyhow::Result<()> {
+    let message = NetworkMessage::new(
+        sealed.from.to_string(),
+        Some(sealed.to.to_string()),
+        MessageType::DirectMessage,
+        serde_json::to_value(sealed)?,
+    );
+    send_to_member(networking, sealed.to, message).await
+}
+
+async fn send_transfer(networking: &NetworkManager, from: Uuid, to: Uuid, transfer: &TransferMessage) -> anyhow::Result<()> {
+    let message = NetworkMessage::new(
+        from.to_string(),
+        Some(to.to_string()),
+        MessageType::FileTransfer,
+        serde_json::to_value(transfer)?,
+    );
+    send_to_member(networking, to, message).await
+}
+
+/// Asks the sender for whatever chunks of a download are due.
+pub async fn request_chunks(session: &mut PartySession, file_id: Uuid) {
+    let Some(download) = session.downloads.get_mut(&file_id) else {
+        return;
+    };
+    let source = download.source;
+    let indices = download.next_requests(std::time::Instant::now());
+
+    for index in indices {
+        let request = TransferMessage::Request {
+            room_id: session.room.id,
+            file_id,
+            index,
+            requested_by: session.local_user.id,
+        };
+        if let Err(e) = send_transfer(&session.networking, session.local_user.id, source, &request).await {
+            eprintln!("Failed to request chunk {} of {}: {}", index, file_id, e);
+        }
+    }
+}
+
+pub fn emit_progress(app: &AppHandle, progress: &TransferProgress) {
+    if let Err(e) = app.emit_all("file-progress", progress) {
+        eprintln!("Failed to emit file progress event: {}", e);
+    }
+}
+
+/// Checks a download that has every chunk and moves it into place.
+pub fn finish_download(app: &AppHandle, session: &mut PartySession, file_id: Uuid) {
+    let Some(download) = session.downloads.remove(&file_id) else {
+        return;
+    };
+    let mut progress = download.progress();
+    match download.finish() {
+        Ok(path) => {
+            println!("📥 Downloaded '{}' to {}", progress.name, path.display());
+            progress.complete = true;
+            progress.path = Some(path);
+        }
+        Err(e) => {
+            eprintln!("Download of '{}' failed: {}", progress.name, e);
+            progress.error = Some(e.to_string());
+        }
+    }
+    emit_progress(app, &progress);
+}
+
+async fn handle_file_transfer(app: &AppHandle, state: &AppState, session: &mut PartySession, message: NetworkMessage) {
+    let sender_id = match signed_sender(&session.room, &message) {
+        Some(sender_id) => sender_id,
+        None => {
+            println!("🚫 Ignored file transfer message from '{}' not signed with their identity key", message.from);
+            return;
+        }
+    };
+    let transfer: TransferMessage = match serde_json::from_value(message.payload.clone()) {
+        Ok(transfer) => transfer,
+        Err(e) => {
+            eprintln!("Received malformed file transfer message from '{}': {}", message.from, e);
+            return;
+        }
+    };
+    if transfer.room_id() != session.room.id || !session.room.users.contains_key(&sender_id) {
+        return;
+    }
+
+    match transfer {
+        TransferMessage::Request { file_id, index, requested_by, .. } => {
+            if requested_by != sender_id {
+                println!("🚫 Ignored chunk request made on behalf of another user");
+                return;
+            }
+            let Some(offer) = session.room.file_offer(file_id) else {
+                return;
+            };
+            let (source, Some(manifest)) = (offer.user_id, offer.attachment.clone()) else {
+                return;
+            };
+
+            // Not ours to serve: the host passes it on to whoever offered it
+            if source != session.local_user.id {
+                if session.networking.is_server {
+                    if let Err(e) = session.networking.send_to_peer(&source.to_string(), message).await {
+                        eprintln!("Failed to relay chunk request: {}", e);
+                    }
+                }
+                return;
+            }
+
+            let data = match state.store.shared_file(session.room.id, file_id) {
+                Ok(Some(path)) => transfer::read_chunk(&path, &manifest, index),
+                Ok(None) => Err(anyhow::anyhow!("{} is no longer shared", manifest.name)),
+                Err(e) => Err(e),
+            };
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    eprintln!("Can't serve chunk {} of '{}': {}", index, manifest.name, e);
+                    return;
+                }
+            };
+
+            let chunk = TransferMessage::Chunk {
+                room_id: session.room.id,
+                file_id,
+                index,
+                requested_by,
+                data: transfer::encode_chunk(&data),
+            };
+            if let Err(e) = send_transfer(&session.networking, session.local_user.id, requested_by, &chunk).await {
+                eprintln!("Failed to send chunk {} of '{}': {}", index, manifest.name, e);
+            }
+        }
+        TransferMessage::Chunk { file_id, index, requested_by, data, .. } => {
+            if requested_by != session.local_user.id {
+                if session.networking.is_server && session.room.users.contains_key(&requested_by) {
+                    if let Err(e) = session.networking.send_to_peer(&requested_by.to_string(), message).await {
+                        eprintln!("Failed to relay chunk: {}", e);
+                    }
+                }
+                return;
+            }
+
+            let Some(download) = session.downloads.get_mut(&file_id) else {
+                return;
+            };
+            if download.source != sender_id {
+                return;
+            }
+            match download.accept_chunk(index, &data) {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(e) => {
+                    // Asked for again once the request times out
+                    eprintln!("Rejected chunk from {}: {}", sender_id, e);
+                    return;
+                }
+            }
+
+            if download.is_complete() {
+                finish_download(app, session, file_id);
+            } else {
+                emit_progress(app, &download.progress());
+                request_chunks(session, file_id).await;
+            }
+        }
+    }
+}
+
+pub async fn send_join_request(networking: &NetworkManager, pending: &PendingJoin) -> anyhow::Result<()> {
+    let message = NetworkMessage::new(
+        pending.request.user.id.to_string(),
+        Some(pending.server_addr.to_string()),
+        MessageType::JoinRequest,
+        serde_json::to_value(&pending.request)?,
+    );
+
+    networking.send_to_peer(&pending.server_addr.to_string(), message).await
+}
+
+pub async fn answer_password_challenge(networking: &NetworkManager, identity: &LocalIdentity, pending: &mut PendingJoin) -> anyhow::Result<()> {
+    let challenge = pending.challenge.take()
+        .ok_or_else(|| anyhow::anyhow!("No password challenge received"))?;
+    let password = pending.password.as_deref()
+        .ok_or_else(|| anyhow::anyhow!("No password provided"))?;
+
+    let proof = password::prove(password, &challenge, pending.request.room_id, pending.request.user.id)?;
+    pending.request.password_proof = Some(proof);
+    pending.request.refresh(identity);
+
+    send_join_request(networking, pending).await
+}
+
+pub async fn send_join_response(
+    networking: &NetworkManager,
+    from: Uuid,
+    to: SocketAddr,
+    response: JoinResponse,
+) {
+    let payload = match serde_json::to_value(&response) {
+        Ok(payload) => payload,
+        Err(e) => {
+            eprintln!("Failed to serialize join response: {}", e);
+            return;
+        }
+    };
+
+    let message = NetworkMessage::new(
+        from.to_string(),
+        Some(to.to_string()),
+        MessageType::JoinResponse,
+        payload,
+    );
+
+    if let Err(e) = networking.send_to_peer(&to.to_string(), message).await {
+        eprintln!("Failed to send join response to {}: {}", to, e);
+    }
+}
+
+/// Sends a sync payload to one peer, or to everyone when `to` is `None`.
+pub async fn send_room_sync(
+    networking: &NetworkManager,
+    from: Uuid,
+    to: Option<SocketAddr>,
+    sync_message: SyncMessage,
+) {
+    let payload = match serde_json::to_value(&sync_message) {
+        Ok(payload) => payload,
+        Err(e) => {
+            eprintln!("Failed to serialize room sync: {}", e);
+            return;
+        }
+    };
+
+    let message = NetworkMessage::new(
+        from.to_string(),
+        to.map(|addr| addr.to_string()),
+        MessageType::RoomSync,
+        payload,
+    );
+
+    let result = match to {
+        Some(addr) => networking.send_to_peer(&addr.to_string(), message).await,
+        None => networking.broadcast_message(message).await,
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to send room sync: {}", e);
+    }
+}
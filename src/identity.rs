@@ -0,0 +1,136 @@
+use base64::{Engine as _, engine::general_purpose};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use x25519_dalek::{PublicKey, StaticSecret};
+use anyhow::Result;
+
+/// Signing key that identifies us across sessions, plus the key agreement
+/// key direct messages are encrypted to.
+pub struct LocalIdentity {
+    key_pair: Ed25519KeyPair,
+    dm_secret: StaticSecret,
+}
+
+impl LocalIdentity {
+    pub fn generate() -> Result<Self> {
+        let pkcs8 = Self::generate_pkcs8()?;
+        Self::from_pkcs8(&pkcs8, Self::generate_dm_secret()?)
+    }
+
+    fn generate_pkcs8() -> Result<Vec<u8>> {
+        let rng = SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+            .map_err(|_| anyhow::anyhow!("Failed to generate identity key"))?;
+        Ok(pkcs8.as_ref().to_vec())
+    }
+
+    fn generate_dm_secret() -> Result<[u8; 32]> {
+        random_bytes()
+    }
+
+    fn from_pkcs8(pkcs8: &[u8], dm_secret: [u8; 32]) -> Result<Self> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8)
+            .map_err(|_| anyhow::anyhow!("Invalid identity key"))?;
+        Ok(Self { key_pair, dm_secret: StaticSecret::from(dm_secret) })
+    }
+
+    pub fn load_or_create() -> Result<Self> {
+        let app_data_dir = dirs::data_dir()
+            .ok_or_else(|| anyhow::anyhow!("Could not find app data directory"))?
+            .join("shortgap");
+
+        let file_path = app_data_dir.join("identity.key");
+        let dm_file_path = app_data_dir.join("dm.key");
+        std::fs::create_dir_all(&app_data_dir)?;
+
+        // Identities created before direct messages get their key added
+        let dm_secret = if dm_file_path.exists() {
+            let encoded = std::fs::read_to_string(&dm_file_path)?;
+            general_purpose::STANDARD.decode(encoded.trim())?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid direct message key"))?
+        } else {
+            let dm_secret = Self::generate_dm_secret()?;
+            std::fs::write(&dm_file_path, general_purpose::STANDARD.encode(dm_secret))?;
+            dm_secret
+        };
+
+        if file_path.exists() {
+            let encoded = std::fs::read_to_string(&file_path)?;
+            let pkcs8 = general_purpose::STANDARD.decode(encoded.trim())?;
+            return Self::from_pkcs8(&pkcs8, dm_secret);
+        }
+
+        let pkcs8 = Self::generate_pkcs8()?;
+        std::fs::write(&file_path, general_purpose::STANDARD.encode(&pkcs8))?;
+
+        let identity = Self::from_pkcs8(&pkcs8, dm_secret)?;
+        println!("🔑 Created new identity key: {}", identity.public_key());
+        Ok(identity)
+    }
+
+    pub fn public_key(&self) -> String {
+        general_purpose::URL_SAFE_NO_PAD.encode(self.key_pair.public_key().as_ref())
+    }
+
+    pub fn sign(&self, data: &[u8]) -> String {
+        general_purpose::URL_SAFE_NO_PAD.encode(self.key_pair.sign(data).as_ref())
+    }
+
+    pub fn dm_public_key(&self) -> String {
+        general_purpose::URL_SAFE_NO_PAD.encode(PublicKey::from(&self.dm_secret).as_bytes())
+    }
+
+    /// Our direct message key, signed with the identity key so that peers
+    /// relaying it can't swap in their own.
+    pub fn dm_key_signature(&self) -> String {
+        self.sign(&dm_key_payload(&self.dm_public_key()))
+    }
+
+    pub fn dm_agree(&self, their_public: &[u8; 32]) -> [u8; 32] {
+        self.dm_secret.diffie_hellman(&PublicKey::from(*their_public)).to_bytes()
+    }
+}
+
+pub fn dm_key_payload(dm_key: &str) -> Vec<u8> {
+    format!("dm-key:{}", dm_key).into_bytes()
+}
+
+pub fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    SystemRandom::new().fill(&mut bytes)
+        .map_err(|_| anyhow::anyhow!("Failed to generate random bytes"))?;
+    Ok(bytes)
+}
+
+pub fn verify_signature(public_key: &str, data: &[u8], signature: &str) -> bool {
+    let (public_key, signature) = match (
+        general_purpose::URL_SAFE_NO_PAD.decode(public_key),
+        general_purpose::URL_SAFE_NO_PAD.decode(signature),
+    ) {
+        (Ok(public_key), Ok(signature)) => (public_key, signature),
+        _ => return false,
+    };
+
+    signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+        .verify(data, &signature)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let identity = LocalIdentity::generate().unwrap();
+        let other = LocalIdentity::generate().unwrap();
+
+        let signature = identity.sign(b"join room");
+
+        assert!(verify_signature(&identity.public_key(), b"join room", &signature));
+        assert!(!verify_signature(&identity.public_key(), b"join another room", &signature));
+        assert!(!verify_signature(&other.public_key(), b"join room", &signature));
+        assert!(!verify_signature("not a key", b"join room", &signature));
+    }
+}
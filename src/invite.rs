@@ -12,6 +12,14 @@ pub struct InviteData {
     pub peer_addresses: Vec<SocketAddr>,
     pub protocol: crate::networking::Protocol,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub requires_approval: bool,
+    #[serde(default)]
+    pub requires_password: bool,
+    /// Identity key of the host when the invite was made; its answer to
+    /// our join request has to be signed with it
+    #[serde(default)]
+    pub host_key: Option<String>,
 }
 
 impl InviteData {
@@ -29,6 +37,9 @@ impl InviteData {
             peer_addresses,
             protocol,
             created_at: chrono::Utc::now(),
+            requires_approval: false,
+            requires_password: false,
+            host_key: None,
         }
     }
 
@@ -106,6 +117,9 @@ mod tests {
             peer_addresses: vec![],
             protocol: crate::networking::Protocol::TCP,
             created_at: chrono::Utc::now() - chrono::Duration::hours(25),
+            requires_approval: false,
+            requires_password: false,
+            host_key: None,
         };
 
         assert!(invite_data.is_expired(24));
@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use uuid::Uuid;
+use crate::identity::{self, LocalIdentity};
+use crate::networking::NetworkMessage;
+use crate::password::{PasswordChallenge, PasswordProof};
+use crate::room::Room;
+use crate::user::User;
+
+/// How far a join request's timestamp may be from our clock, so captured
+/// requests can't be replayed later.
+const MAX_REQUEST_AGE: chrono::Duration = chrono::Duration::minutes(2);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinRequest {
+    pub room_id: Uuid,
+    pub user: User,
+    pub identity_key: String,
+    pub signature: String,
+    pub requested_at: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub password_proof: Option<PasswordProof>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum JoinDecision {
+    Approved,
+    Pending,
+    PasswordRequired(PasswordChallenge),
+    Denied(Option<String>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinResponse {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub decision: JoinDecision,
+    pub decided_by: Option<Uuid>,
+    #[serde(default)]
+    pub room: Option<Room>,
+}
+
+/// Joiner-side state for a handshake that hasn't been decided yet.
+#[derive(Debug, Clone)]
+pub struct PendingJoin {
+    pub request: JoinRequest,
+    pub server_addr: SocketAddr,
+    pub password: Option<String>,
+    pub challenge: Option<PasswordChallenge>,
+    /// Identity key of the host we asked; only its signed answers count
+    pub host_key: Option<String>,
+}
+
+impl JoinRequest {
+    pub fn new(room_id: Uuid, user: User, identity: &LocalIdentity) -> Self {
+        let mut request = Self {
+            room_id,
+            user,
+            identity_key: identity.public_key(),
+            signature: String::new(),
+            requested_at: chrono::Utc::now(),
+            password_proof: None,
+        };
+        request.signature = identity.sign(&request.signing_payload());
+        request
+    }
+
+    /// Re-stamps and re-signs the request before it is sent again, e.g.
+    /// with the answer to a password challenge.
+    pub fn refresh(&mut self, identity: &LocalIdentity) {
+        self.requested_at = chrono::Utc::now();
+        self.signature = identity.sign(&self.signing_payload());
+    }
+
+    /// Covers the whole profile, address included, so a captured request
+    /// can't be sent on from somewhere else.
+    fn signing_payload(&self) -> Vec<u8> {
+        let profile = serde_json::to_string(&self.user).unwrap_or_default();
+        format!(
+            "join:{}:{}:{}:{}",
+            self.room_id,
+            self.identity_key,
+            self.requested_at.to_rfc3339(),
+            profile,
+        ).into_bytes()
+    }
+
+    /// Checks that the request is recent and was signed by the identity it
+    /// claims to carry.
+    pub fn verify(&self) -> bool {
+        if self.user.identity_key.as_deref() != Some(self.identity_key.as_str()) {
+            return false;
+        }
+        let age = chrono::Utc::now().signed_duration_since(self.requested_at);
+        if age > MAX_REQUEST_AGE || -age > MAX_REQUEST_AGE {
+            return false;
+        }
+
+        identity::verify_signature(&self.identity_key, &self.signing_payload(), &self.signature)
+    }
+}
+
+impl PendingJoin {
+    /// Whether `message` was signed by the host this request went to.
+    pub fn is_from_host(&self, message: &NetworkMessage) -> bool {
+        self.host_key.as_deref().is_some_and(|host_key| message.is_signed_by(host_key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_join_request_signature() {
+        let identity = LocalIdentity::generate().unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut user = User::new("Joiner".to_string(), addr);
+        user.identity_key = Some(identity.public_key());
+
+        let request = JoinRequest::new(Uuid::new_v4(), user, &identity);
+        assert!(request.verify());
+
+        // Tampering with the target room or the address to answer invalidates the signature
+        let mut tampered = request.clone();
+        tampered.room_id = Uuid::new_v4();
+        assert!(!tampered.verify());
+        let mut redirected = request.clone();
+        redirected.user.address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 66)), 8080);
+        assert!(!redirected.verify());
+
+        // A request captured a while ago can't be replayed
+        let mut stale = request.clone();
+        stale.requested_at -= chrono::Duration::minutes(10);
+        assert!(!stale.verify());
+        stale.refresh(&identity);
+        assert!(stale.verify());
+
+        // Claiming a different identity than the profile carries is rejected
+        let mut mismatched = request;
+        mismatched.user.identity_key = Some(LocalIdentity::generate().unwrap().public_key());
+        assert!(!mismatched.verify());
+    }
+
+    #[test]
+    fn test_join_responses_must_be_signed_by_the_host() {
+        let host = LocalIdentity::generate().unwrap();
+        let joiner = LocalIdentity::generate().unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut user = User::new("Joiner".to_string(), addr);
+        user.identity_key = Some(joiner.public_key());
+
+        let pending = PendingJoin {
+            request: JoinRequest::new(Uuid::new_v4(), user, &joiner),
+            server_addr: addr,
+            password: None,
+            challenge: None,
+            host_key: Some(host.public_key()),
+        };
+
+        let sign = |identity: &LocalIdentity| {
+            let mut message = NetworkMessage::new(
+                Uuid::new_v4().to_string(),
+                None,
+                crate::networking::MessageType::JoinResponse,
+                serde_json::json!({ "decision": "Approved" }),
+            );
+            message.sequence = 1;
+            message.signature = Some(identity.sign(&message.signing_payload()));
+            message
+        };
+
+        assert!(pending.is_from_host(&sign(&host)));
+
+        // Anyone else on the network can't answer for the host
+        assert!(!pending.is_from_host(&sign(&LocalIdentity::generate().unwrap())));
+        let mut unsigned = sign(&host);
+        unsigned.signature = None;
+        assert!(!pending.is_from_host(&unsigned));
+
+        // Nor can the host's signature be moved onto a different decision
+        let mut tampered = sign(&host);
+        tampered.payload = serde_json::json!({ "decision": "Denied" });
+        assert!(!pending.is_from_host(&tampered));
+
+        // Without a key to check against, nothing is trusted
+        let unpinned = PendingJoin { host_key: None, ..pending };
+        assert!(!unpinned.is_from_host(&sign(&host)));
+    }
+}
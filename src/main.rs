@@ -10,39 +10,134 @@ mod ping;
 mod invite;
 mod protocol;
 mod commands;
+mod replay;
+mod handlers;
+mod identity;
+mod join;
+mod password;
+mod permissions;
+mod moderation;
+mod sync;
+mod clock;
+mod pagination;
+mod search;
+mod storage;
+mod retention;
+mod session;
+mod channel;
+mod direct;
+mod event;
+mod typing;
+mod receipts;
+mod unread;
+mod mention;
+mod notify;
+mod transfer;
+mod media;
 
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 pub struct AppState {
-    pub current_party: Arc<Mutex<Option<room::Room>>>,
+    pub parties: Arc<Mutex<session::Parties>>,
     pub current_user: Arc<Mutex<Option<user::User>>>,
-    pub networking: Arc<Mutex<networking::NetworkManager>>,
+    pub identity: Arc<identity::LocalIdentity>,
+    pub password_gate: Arc<Mutex<password::PasswordGate>>,
+    pub store: Arc<storage::Store>,
 }
 
 #[tokio::main]
 async fn main() {
+    let store = storage::Store::open_default().expect("failed to open database");
+    match store.import_legacy_rooms() {
+        Ok(0) => {}
+        Ok(imported) => println!("📦 Imported {} saved rooms into the database", imported),
+        Err(e) => eprintln!("Failed to import saved rooms: {}", e),
+    }
+
     let app_state = AppState {
-        current_party: Arc::new(Mutex::new(None)),
+        parties: Arc::new(Mutex::new(session::Parties::default())),
         current_user: Arc::new(Mutex::new(None)),
-        networking: Arc::new(Mutex::new(networking::NetworkManager::new())),
+        identity: Arc::new(
+            identity::LocalIdentity::load_or_create().expect("failed to load identity key"),
+        ),
+        password_gate: Arc::new(Mutex::new(password::PasswordGate::default())),
+        store: Arc::new(store),
     };
 
     tauri::Builder::default()
         .manage(app_state)
+        .setup(|app| {
+            tokio::spawn(handlers::run_retention_compaction(app.handle()));
+            tokio::spawn(handlers::run_typing_expiry(app.handle()));
+            tokio::spawn(handlers::run_transfer_retries(app.handle()));
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             commands::create_party,
             commands::join_party,
             commands::leave_party,
+            commands::get_recent_parties,
+            commands::rejoin_party,
             commands::send_message,
-            commands::get_current_party,
+            commands::get_thread,
+            commands::edit_message,
+            commands::delete_message,
+            commands::add_reaction,
+            commands::remove_reaction,
+            commands::pin_message,
+            commands::unpin_message,
+            commands::set_retention_policy,
+            commands::get_party,
+            commands::list_parties,
+            commands::focus_party,
+            commands::get_focused_party,
             commands::set_user_settings,
             commands::get_ping_stats,
+            commands::get_network_diagnostics,
             commands::change_protocol,
             commands::generate_invite,
             commands::parse_invite,
+            commands::set_join_approval,
+            commands::get_pending_joins,
+            commands::approve_join,
+            commands::deny_join,
+            commands::set_room_password,
+            commands::submit_join_password,
+            commands::rename_room,
+            commands::create_channel,
+            commands::rename_channel,
+            commands::delete_channel,
+            commands::list_channels,
+            commands::mark_channel_read,
+            commands::mark_party_read,
+            commands::get_unread_counts,
+            commands::send_direct_message,
+            commands::get_direct_messages,
+            commands::set_typing,
+            commands::get_receipts,
+            commands::set_read_receipts,
+            commands::get_read_receipts,
+            commands::get_notification_policy,
+            commands::set_notification_policy,
+            commands::set_room_notifications,
+            commands::share_file,
+            commands::download_file,
+            commands::cancel_download,
+            commands::get_downloads_dir,
+            commands::set_downloads_dir,
+            commands::set_max_file_size,
+            commands::set_user_role,
+            commands::kick_user,
+            commands::ban_user,
+            commands::unban_user,
+            commands::mute_user,
+            commands::unmute_user,
+            commands::get_moderation_log,
             commands::sync_messages,
             commands::get_room_messages,
+            commands::get_messages_around,
+            commands::search_messages,
             commands::check_room_health,
             commands::mark_user_offline_cmd,
             commands::join_call,
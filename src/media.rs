@@ -0,0 +1,383 @@
+use serde::{Deserialize, Serialize};
+use base64::{Engine as _, engine::general_purpose};
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use anyhow::Result;
+
+/// Largest image we'll process for sharing; bigger ones can still go out
+/// as plain files under another extension.
+pub const MAX_IMAGE_SIZE: u64 = 25 * 1024 * 1024;
+
+/// Longest side we'll decode, to stay clear of decompression bombs.
+pub const MAX_IMAGE_DIMENSION: u32 = 12_000;
+
+pub const THUMBNAIL_DIMENSION: u32 = 256;
+
+/// Thumbnails ride inline with the chat message, so they're kept small.
+pub const MAX_THUMBNAIL_BYTES: usize = 48 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageKind {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+}
+
+impl ImageKind {
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "jpg" | "jpeg" => Some(ImageKind::Jpeg),
+            "png" => Some(ImageKind::Png),
+            "gif" => Some(ImageKind::Gif),
+            "webp" => Some(ImageKind::WebP),
+            _ => None,
+        }
+    }
+
+    /// Tells the format from the file's leading bytes.
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0xFF, 0xD8, 0xFF, ..] => Some(ImageKind::Jpeg),
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(ImageKind::Png),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(ImageKind::Gif),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(ImageKind::WebP),
+            _ => None,
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        [ImageKind::Jpeg, ImageKind::Png, ImageKind::Gif, ImageKind::WebP]
+            .into_iter()
+            .find(|kind| kind.mime() == mime)
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            ImageKind::Jpeg => "image/jpeg",
+            ImageKind::Png => "image/png",
+            ImageKind::Gif => "image/gif",
+            ImageKind::WebP => "image/webp",
+        }
+    }
+
+    fn format(self) -> ImageFormat {
+        match self {
+            ImageKind::Jpeg => ImageFormat::Jpeg,
+            ImageKind::Png => ImageFormat::Png,
+            ImageKind::Gif => ImageFormat::Gif,
+            ImageKind::WebP => ImageFormat::WebP,
+        }
+    }
+}
+
+/// Shown in place of an image until someone fetches the full file. The
+/// thumbnail is a base64 JPEG.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImagePreview {
+    pub mime: String,
+    pub width: u32,
+    pub height: u32,
+    pub thumbnail: String,
+    pub thumbnail_width: u32,
+    pub thumbnail_height: u32,
+}
+
+impl ImagePreview {
+    /// Checks a preview that came with a peer's message.
+    pub fn validate(&self, name: &str) -> Result<()> {
+        let kind = ImageKind::from_mime(&self.mime)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported image type {}", self.mime))?;
+        if ImageKind::from_extension(Path::new(name)) != Some(kind) {
+            return Err(anyhow::anyhow!("{} is not a {} file", name, self.mime));
+        }
+        if self.thumbnail_width > THUMBNAIL_DIMENSION || self.thumbnail_height > THUMBNAIL_DIMENSION {
+            return Err(anyhow::anyhow!("Thumbnail is too large"));
+        }
+        let thumbnail = general_purpose::STANDARD.decode(&self.thumbnail)?;
+        if thumbnail.len() > MAX_THUMBNAIL_BYTES || ImageKind::sniff(&thumbnail) != Some(ImageKind::Jpeg) {
+            return Err(anyhow::anyhow!("Thumbnail is not a small JPEG"));
+        }
+        Ok(())
+    }
+}
+
+fn read_head(path: &Path) -> Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(12);
+    std::fs::File::open(path)?.take(12).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// Whether a downloaded file really is the kind of image it was offered as.
+pub fn verify_kind(path: &Path, preview: &ImagePreview) -> Result<()> {
+    if ImageKind::sniff(&read_head(path)?).map(ImageKind::mime) != Some(preview.mime.as_str()) {
+        return Err(anyhow::anyhow!("{} is not a {} file", path.display(), preview.mime));
+    }
+    Ok(())
+}
+
+/// Gets an image ready to share: checks its contents match its extension,
+/// takes out metadata such as EXIF location and camera details, and makes
+/// the thumbnail. The cleaned copy is written under `dir` with the
+/// original name. Returns `None` for files that aren't images.
+pub fn prepare_image(path: &Path, dir: &Path) -> Result<Option<(PathBuf, ImagePreview)>> {
+    let claimed = ImageKind::from_extension(path);
+    let sniffed = ImageKind::sniff(&read_head(path)?);
+    let kind = match (claimed, sniffed) {
+        (None, None) => return Ok(None),
+        (Some(claimed), Some(sniffed)) if claimed == sniffed => claimed,
+        _ => return Err(anyhow::anyhow!("{} doesn't contain what its extension says", path.display())),
+    };
+    if std::fs::metadata(path)?.len() > MAX_IMAGE_SIZE {
+        return Err(anyhow::anyhow!("Images can be at most {} bytes", MAX_IMAGE_SIZE));
+    }
+
+    let original = std::fs::read(path)?;
+    let (image, orientation) = decode(&original, kind)?;
+    let cleaned = if orientation == Orientation::NoTransforms {
+        strip_metadata(kind, &original)?
+    } else {
+        // The rotation lives in the metadata we're removing, so it has to
+        // be applied to the pixels instead
+        encode(&image, kind)?
+    };
+
+    let name = path.file_name().ok_or_else(|| anyhow::anyhow!("Not a file: {}", path.display()))?;
+    let copy_dir = dir.join(Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&copy_dir)?;
+    let copy = copy_dir.join(name);
+    std::fs::write(&copy, cleaned)?;
+
+    Ok(Some((copy, preview(&image, kind)?)))
+}
+
+fn decode(bytes: &[u8], kind: ImageKind) -> Result<(DynamicImage, Orientation)> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), kind.format());
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok((image, orientation))
+}
+
+fn encode(image: &DynamicImage, kind: ImageKind) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match kind {
+        ImageKind::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, 90))?,
+        _ => DynamicImage::ImageRgba8(image.to_rgba8())
+            .write_to(Cursor::new(&mut bytes), kind.format())?,
+    }
+    Ok(bytes)
+}
+
+fn preview(image: &DynamicImage, kind: ImageKind) -> Result<ImagePreview> {
+    for dimension in [THUMBNAIL_DIMENSION, THUMBNAIL_DIMENSION / 2] {
+        let thumbnail = image.thumbnail(dimension, dimension);
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(thumbnail.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, 70))?;
+        if bytes.len() <= MAX_THUMBNAIL_BYTES {
+            return Ok(ImagePreview {
+                mime: kind.mime().to_string(),
+                width: image.width(),
+                height: image.height(),
+                thumbnail: general_purpose::STANDARD.encode(bytes),
+                thumbnail_width: thumbnail.width(),
+                thumbnail_height: thumbnail.height(),
+            });
+        }
+    }
+    Err(anyhow::anyhow!("Couldn't make a small enough thumbnail"))
+}
+
+/// Drops metadata without touching the image data. GIFs carry no EXIF and
+/// are left as they are.
+fn strip_metadata(kind: ImageKind, bytes: &[u8]) -> Result<Vec<u8>> {
+    match kind {
+        ImageKind::Jpeg => strip_jpeg(bytes),
+        ImageKind::Png => strip_png(bytes),
+        ImageKind::WebP => strip_webp(bytes),
+        ImageKind::Gif => Ok(bytes.to_vec()),
+    }
+}
+
+fn malformed() -> anyhow::Error {
+    anyhow::anyhow!("Image file is malformed")
+}
+
+/// Keeps JFIF (APP0), ICC profiles (APP2) and Adobe color info (APP14);
+/// drops EXIF/XMP (APP1), IPTC (APP13), other app segments and comments.
+fn strip_jpeg(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut stripped = bytes[..2].to_vec();
+    let mut pos = 2;
+    loop {
+        let header = bytes.get(pos..pos + 4).ok_or_else(malformed)?;
+        if header[0] != 0xFF {
+            return Err(malformed());
+        }
+        let marker = header[1];
+        if marker == 0xFF {
+            // Fill byte before the marker
+            pos += 1;
+            continue;
+        }
+        let end = pos + 2 + u16::from_be_bytes([header[2], header[3]]) as usize;
+        let segment = bytes.get(pos..end).ok_or_else(malformed)?;
+
+        let metadata = matches!(marker, 0xE1 | 0xE3..=0xED | 0xEF | 0xFE);
+        if !metadata {
+            stripped.extend_from_slice(segment);
+        }
+        if marker == 0xDA {
+            // Start of scan; the rest is image data
+            stripped.extend_from_slice(&bytes[end..]);
+            return Ok(stripped);
+        }
+        pos = end;
+    }
+}
+
+fn strip_png(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut stripped = bytes[..8].to_vec();
+    let mut pos = 8;
+    loop {
+        let header = bytes.get(pos..pos + 8).ok_or_else(malformed)?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let chunk_type = &header[4..8];
+        let end = pos + 12 + len;
+        let chunk = bytes.get(pos..end).ok_or_else(malformed)?;
+
+        if !matches!(chunk_type, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME") {
+            stripped.extend_from_slice(chunk);
+        }
+        if chunk_type == b"IEND" {
+            return Ok(stripped);
+        }
+        pos = end;
+    }
+}
+
+fn strip_webp(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut stripped = bytes.get(..12).ok_or_else(malformed)?.to_vec();
+    let mut pos = 12;
+    while pos < bytes.len() {
+        let header = bytes.get(pos..pos + 8).ok_or_else(malformed)?;
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let end = pos + 8 + len + len % 2;
+        let chunk = bytes.get(pos..end.min(bytes.len())).ok_or_else(malformed)?;
+
+        match &header[..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let mut chunk = chunk.to_vec();
+                // Clear the EXIF and XMP flags to match
+                if let Some(flags) = chunk.get_mut(8) {
+                    *flags &= !0x0C;
+                }
+                stripped.extend_from_slice(&chunk);
+            }
+            _ => stripped.extend_from_slice(chunk),
+        }
+        pos = end;
+    }
+
+    let riff_size = (stripped.len() - 8) as u32;
+    stripped[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("shortgap-media-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn photo(format: ImageFormat) -> Vec<u8> {
+        let image = RgbImage::from_fn(640, 480, |x, y| Rgb([(x % 256) as u8, (y % 256) as u8, 128]));
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(image).write_to(Cursor::new(&mut bytes), format).unwrap();
+        bytes
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn test_images_are_cleaned_and_previewed() {
+        let dir = temp_dir();
+
+        // A JPEG with an EXIF segment carrying a location
+        let jpeg = photo(ImageFormat::Jpeg);
+        let exif = b"Exif\0\0GPS 51.5007N 0.1246W";
+        let mut tagged = jpeg[..2].to_vec();
+        tagged.extend_from_slice(&[0xFF, 0xE1]);
+        tagged.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+        tagged.extend_from_slice(exif);
+        tagged.extend_from_slice(&jpeg[2..]);
+        let path = dir.join("Photo.JPG");
+        std::fs::write(&path, &tagged).unwrap();
+
+        let (copy, preview) = prepare_image(&path, &dir.join("shared")).unwrap().unwrap();
+        let cleaned = std::fs::read(&copy).unwrap();
+        assert!(!contains(&cleaned, b"GPS"));
+        assert_eq!(cleaned, jpeg);
+        assert_eq!(copy.file_name().unwrap(), "Photo.JPG");
+        assert_eq!((preview.width, preview.height), (640, 480));
+        assert_eq!((preview.thumbnail_width, preview.thumbnail_height), (256, 192));
+        preview.validate("Photo.JPG").unwrap();
+        assert!(preview.validate("Photo.png").is_err());
+        verify_kind(&copy, &preview).unwrap();
+
+        // PNG text chunks go too
+        let png = photo(ImageFormat::Png);
+        let mut text_chunk = 10u32.to_be_bytes().to_vec();
+        text_chunk.extend_from_slice(b"tEXtAuthor\0Sam");
+        text_chunk.extend_from_slice(&[0, 0, 0, 0]);
+        let mut tagged = png[..33].to_vec();
+        tagged.extend_from_slice(&text_chunk);
+        tagged.extend_from_slice(&png[33..]);
+        assert_eq!(strip_png(&tagged).unwrap(), png);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_mismatched_extensions_are_rejected() {
+        let dir = temp_dir();
+
+        let disguised = dir.join("cat.jpg");
+        std::fs::write(&disguised, photo(ImageFormat::Png)).unwrap();
+        assert!(prepare_image(&disguised, &dir).is_err());
+
+        let renamed = dir.join("cat.txt");
+        std::fs::write(&renamed, photo(ImageFormat::Png)).unwrap();
+        assert!(prepare_image(&renamed, &dir).is_err());
+
+        let fake = dir.join("cat.png");
+        std::fs::write(&fake, b"not really a picture").unwrap();
+        assert!(prepare_image(&fake, &dir).is_err());
+
+        let notes = dir.join("notes.txt");
+        std::fs::write(&notes, b"bring snacks").unwrap();
+        assert!(prepare_image(&notes, &dir).unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
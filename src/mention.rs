@@ -0,0 +1,70 @@
+use uuid::Uuid;
+
+pub const EVERYONE: &str = "everyone";
+
+/// Who a message mentions, resolved against the room's members.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Mentions {
+    pub user_ids: Vec<Uuid>,
+    pub everyone: bool,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Finds "@name" mentions in `content`, ignoring case. A mention has to
+/// stand on its own, so "@sam" doesn't match "@sam-2", "@samantha" or an
+/// email address. Where names overlap the longest one wins, so "@Sam Lee"
+/// only mentions Sam Lee even if there is also a Sam.
+pub fn resolve<'a>(content: &str, users: impl IntoIterator<Item = (Uuid, &'a str)>) -> Mentions {
+    let mut names: Vec<(Uuid, String)> = users.into_iter()
+        .filter(|(_, name)| !name.is_empty())
+        .map(|(user_id, name)| (user_id, name.to_lowercase()))
+        .collect();
+    names.sort_by_key(|(_, name)| std::cmp::Reverse(name.len()));
+
+    let content = content.to_lowercase();
+    let mut mentions = Mentions::default();
+    for (index, _) in content.match_indices('@') {
+        if content[..index].chars().next_back().is_some_and(is_name_char) {
+            continue;
+        }
+        let rest = &content[index + 1..];
+        let stands_alone = |name: &str| {
+            rest.starts_with(name) && !rest[name.len()..].chars().next().is_some_and(is_name_char)
+        };
+
+        if let Some((user_id, _)) = names.iter().find(|(_, name)| stands_alone(name)) {
+            if !mentions.user_ids.contains(user_id) {
+                mentions.user_ids.push(*user_id);
+            }
+        } else if stands_alone(EVERYONE) {
+            mentions.everyone = true;
+        }
+    }
+    mentions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mentions_resolve_whole_names() {
+        let (sam, sam_2, sam_lee) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let users = [(sam, "Sam"), (sam_2, "Sam-2"), (sam_lee, "Sam Lee")];
+        let resolve = |content| resolve(content, users.iter().copied());
+
+        assert_eq!(resolve("hey @sam, ready?").user_ids, vec![sam]);
+        assert_eq!(resolve("@SAM-2 and @sam-2").user_ids, vec![sam_2]);
+        assert_eq!(resolve("ping @Sam Lee please").user_ids, vec![sam_lee]);
+        assert!(resolve("hey @samantha").user_ids.is_empty());
+        assert!(resolve("hey sam").user_ids.is_empty());
+        assert!(resolve("email sam@example.com").user_ids.is_empty());
+
+        let everyone = resolve("@everyone lobby in 5");
+        assert!(everyone.everyone && everyone.user_ids.is_empty());
+        assert!(!resolve("@everyones").everyone);
+    }
+}
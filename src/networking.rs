@@ -1,11 +1,16 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::mpsc;
 use uuid::Uuid;
 use anyhow::Result;
+use crate::clock::{self, HlcTimestamp};
+use crate::identity::{self, LocalIdentity};
+use crate::replay::{ReplayGuard, ReplayRejection, ReplayStats};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Protocol {
     TCP,
     WebSocket,
@@ -20,6 +25,55 @@ pub struct NetworkMessage {
     pub message_type: MessageType,
     pub payload: serde_json::Value,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub sequence: u64,
+    #[serde(default)]
+    pub hlc: HlcTimestamp,
+    /// The sender's identity key over the envelope, so `from` and
+    /// `sequence` can't be forged by another peer
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+impl NetworkMessage {
+    pub fn new(
+        from: String,
+        to: Option<String>,
+        message_type: MessageType,
+        payload: serde_json::Value,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            from,
+            to,
+            message_type,
+            payload,
+            timestamp: chrono::Utc::now(),
+            sequence: 0, // Assigned by the NetworkManager when sent
+            hlc: HlcTimestamp::default(),
+            signature: None,
+        }
+    }
+
+    pub fn signing_payload(&self) -> Vec<u8> {
+        format!(
+            "msg:{}:{}:{}:{:?}:{}:{}:{}:{}:{}",
+            self.id,
+            self.from,
+            self.to.as_deref().unwrap_or_default(),
+            self.message_type,
+            self.sequence,
+            self.timestamp.timestamp_millis(),
+            self.hlc.physical,
+            self.hlc.logical,
+            self.payload,
+        ).into_bytes()
+    }
+
+    pub fn is_signed_by(&self, identity_key: &str) -> bool {
+        self.signature.as_deref()
+            .is_some_and(|signature| identity::verify_signature(identity_key, &self.signing_payload(), signature))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,6 +86,23 @@ pub enum MessageType {
     ProtocolChange,
     VoiceData,
     RoomSync,
+    JoinRequest,
+    JoinResponse,
+    RoleChange,
+    RoomRename,
+    RoomSettings,
+    Moderation,
+    MessageEdit,
+    MessageDelete,
+    Reaction,
+    MessagePin,
+    ChannelUpdate,
+    DirectMessage,
+    CallUpdate,
+    /// Live only; never stored
+    Typing,
+    Receipt,
+    FileTransfer,
 }
 
 #[derive(Debug, Clone)]
@@ -50,11 +121,16 @@ pub struct NetworkManager {
     pub current_protocol: Protocol,
     pub is_server: bool,
     pub server_peer: Option<String>,
+    next_sequence: AtomicU64,
+    replay_guard: ReplayGuard,
+    signer: Option<Arc<LocalIdentity>>,
 }
 
 impl NetworkManager {
     pub fn new() -> Self {
         let (tx, rx) = mpsc::unbounded_channel();
+        // Seed the counter from the clock so it keeps increasing across restarts
+        let sequence_seed = chrono::Utc::now().timestamp_millis().max(1) as u64;
         Self {
             connections: HashMap::new(),
             message_sender: Some(tx),
@@ -62,7 +138,42 @@ impl NetworkManager {
             current_protocol: Protocol::TCP,
             is_server: false,
             server_peer: None,
+            next_sequence: AtomicU64::new(sequence_seed),
+            replay_guard: ReplayGuard::default(),
+            signer: None,
+        }
+    }
+
+    /// Signs everything we send from now on with our identity key.
+    pub fn set_signer(&mut self, identity: Arc<LocalIdentity>) {
+        self.signer = Some(identity);
+    }
+
+    fn stamp(&self, mut message: NetworkMessage) -> NetworkMessage {
+        // Relayed messages keep their sender's stamp and signature
+        if message.sequence != 0 {
+            return message;
         }
+        message.sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+        message.hlc = clock::LOCAL.now();
+        if let Some(identity) = &self.signer {
+            message.signature = Some(identity.sign(&message.signing_payload()));
+        }
+        message
+    }
+
+    /// `sender_key` is the identity key we hold for the member named in
+    /// `from`, if any; their messages must then be signed with it.
+    pub fn accept_incoming(&mut self, message: &NetworkMessage, sender_key: Option<&str>) -> Result<(), ReplayRejection> {
+        self.replay_guard.check(message, sender_key)?;
+        if !message.hlc.is_unset() {
+            clock::LOCAL.observe(message.hlc);
+        }
+        Ok(())
+    }
+
+    pub fn replay_stats(&self) -> ReplayStats {
+        self.replay_guard.stats().clone()
     }
 
     pub async fn start_server(&mut self, port: u16, protocol: Protocol) -> Result<()> {
@@ -84,16 +195,14 @@ impl NetworkManager {
         }
     }
 
-    pub async fn switch_protocol(&mut self, new_protocol: Protocol, peers: Vec<SocketAddr>) -> Result<()> {
+    pub async fn switch_protocol(&mut self, from: String, new_protocol: Protocol, peers: Vec<SocketAddr>) -> Result<()> {
         // Notify all peers about protocol change
-        let switch_message = NetworkMessage {
-            id: Uuid::new_v4(),
-            from: "self".to_string(),
-            to: None,
-            message_type: MessageType::ProtocolChange,
-            payload: serde_json::to_value(&new_protocol)?,
-            timestamp: chrono::Utc::now(),
-        };
+        let switch_message = NetworkMessage::new(
+            from,
+            None,
+            MessageType::ProtocolChange,
+            serde_json::to_value(&new_protocol)?,
+        );
 
         self.broadcast_message(switch_message).await?;
 
@@ -143,12 +252,14 @@ impl NetworkManager {
         Ok(())
     }
 
-    pub async fn broadcast_message(&self, _message: NetworkMessage) -> Result<()> {
+    pub async fn broadcast_message(&self, message: NetworkMessage) -> Result<()> {
+        let _message = self.stamp(message);
         // Broadcast to all connected peers
         Ok(())
     }
 
-    pub async fn send_to_peer(&self, _peer_id: &str, _message: NetworkMessage) -> Result<()> {
+    pub async fn send_to_peer(&self, _peer_id: &str, message: NetworkMessage) -> Result<()> {
+        let _message = self.stamp(message);
         // Send to specific peer
         Ok(())
     }
@@ -213,6 +324,7 @@ impl NetworkManager {
         // Reset state
         self.is_server = false;
         self.server_peer = None;
+        self.replay_guard = ReplayGuard::default();
         
         // TODO: Implement actual connection teardown for each protocol
         println!("🔌 Disconnected from all peers");
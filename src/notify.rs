@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use chrono::NaiveTime;
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::room::{ChatMessage, Room};
+
+const PREVIEW_CHARS: usize = 140;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationLevel {
+    #[default]
+    All,
+    Mentions,
+    None,
+}
+
+/// Local times between which nothing is raised. May wrap past midnight,
+/// e.g. 22:00 to 07:00.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// When to raise a desktop notification for an incoming message.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NotificationPolicy {
+    #[serde(default)]
+    pub level: NotificationLevel,
+    /// Per-room overrides of `level`
+    #[serde(default)]
+    pub rooms: HashMap<Uuid, NotificationLevel>,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+}
+
+/// Payload of the "notification" event.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub room_id: Uuid,
+    pub room_name: String,
+    pub channel_id: Uuid,
+    pub message_id: Uuid,
+    pub user_name: String,
+    pub body: String,
+    pub mention: bool,
+}
+
+impl NotificationPolicy {
+    pub fn level_for(&self, room_id: Uuid) -> NotificationLevel {
+        self.rooms.get(&room_id).copied().unwrap_or(self.level)
+    }
+
+    /// The notification to raise for a message `reader_id` just received,
+    /// if any. `now` is the local time of day.
+    pub fn notification_for(&self, room: &Room, message: &ChatMessage, reader_id: Uuid, now: NaiveTime) -> Option<Notification> {
+        if message.user_id == reader_id || message.is_event() || message.is_deleted() {
+            return None;
+        }
+        if self.quiet_hours.is_some_and(|quiet| quiet.contains(now)) {
+            return None;
+        }
+
+        let mention = message.mentions_user(reader_id);
+        let notify = match self.level_for(room.id) {
+            NotificationLevel::All => true,
+            NotificationLevel::Mentions => mention,
+            NotificationLevel::None => false,
+        };
+        if !notify {
+            return None;
+        }
+
+        Some(Notification {
+            room_id: room.id,
+            room_name: room.name.clone(),
+            channel_id: message.channel_id,
+            message_id: message.id,
+            user_name: message.user_name.clone(),
+            body: message.content.chars().take(PREVIEW_CHARS).collect(),
+            mention,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::Protocol;
+    use crate::user::User;
+
+    fn time(hour: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_policy_levels_and_quiet_hours() {
+        let addr = "127.0.0.1:8080".parse().unwrap();
+        let owner = User::new("Owner".to_string(), addr);
+        let owner_id = owner.id;
+        let mut room = Room::new("Test Room".to_string(), owner, Protocol::TCP);
+        let reader = User::new("Reader".to_string(), addr);
+        let reader_id = reader.id;
+        room.add_user(reader).unwrap();
+
+        room.add_message(ChatMessage::new(owner_id, "Owner".to_string(), "lobby is up".to_string()));
+        room.add_message(ChatMessage::new(owner_id, "Owner".to_string(), "@reader you in?".to_string()));
+        let (plain, mention) = (&room.messages[0], &room.messages[1]);
+
+        let mut policy = NotificationPolicy::default();
+        assert!(policy.notification_for(&room, plain, reader_id, time(12)).is_some());
+        assert!(policy.notification_for(&room, plain, owner_id, time(12)).is_none());
+
+        policy.rooms.insert(room.id, NotificationLevel::Mentions);
+        assert!(policy.notification_for(&room, plain, reader_id, time(12)).is_none());
+        assert!(policy.notification_for(&room, mention, reader_id, time(12)).unwrap().mention);
+
+        policy.quiet_hours = Some(QuietHours { start: time(22), end: time(7) });
+        assert!(policy.notification_for(&room, mention, reader_id, time(23)).is_none());
+        assert!(policy.notification_for(&room, mention, reader_id, time(3)).is_none());
+        assert!(policy.notification_for(&room, mention, reader_id, time(7)).is_some());
+
+        policy.level = NotificationLevel::None;
+        policy.rooms.clear();
+        assert!(policy.notification_for(&room, mention, reader_id, time(12)).is_none());
+    }
+}
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use base64::{Engine as _, engine::general_purpose};
+use uuid::Uuid;
+use anyhow::Result;
+use crate::clock::HlcTimestamp;
+use crate::room::ChatMessage;
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PageDirection {
+    /// Towards older messages
+    Before,
+    /// Towards newer messages
+    After,
+}
+
+/// Position in a room's history. Encodes the message's ordering key, so a
+/// cursor stays valid while new messages arrive around it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MessageCursor {
+    hlc: HlcTimestamp,
+    id: Uuid,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessagePage {
+    pub messages: Vec<ChatMessage>,
+    pub before_cursor: Option<String>,
+    pub after_cursor: Option<String>,
+    pub has_more_before: bool,
+    pub has_more_after: bool,
+}
+
+impl MessageCursor {
+    pub fn for_message(message: &ChatMessage) -> Self {
+        let (hlc, id) = message.order_key();
+        Self { hlc, id }
+    }
+
+    pub fn key(&self) -> (HlcTimestamp, Uuid) {
+        (self.hlc, self.id)
+    }
+
+    pub fn encode(&self) -> String {
+        let raw = format!("{}:{}:{}", self.hlc.physical, self.hlc.logical, self.id);
+        general_purpose::URL_SAFE_NO_PAD.encode(raw)
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self> {
+        let raw = String::from_utf8(general_purpose::URL_SAFE_NO_PAD.decode(cursor)?)?;
+        let mut parts = raw.splitn(3, ':');
+        let mut next = || parts.next().ok_or_else(|| anyhow::anyhow!("Malformed cursor"));
+
+        let physical = next()?.parse()?;
+        let logical = next()?.parse()?;
+        let id = Uuid::parse_str(next()?)?;
+
+        Ok(Self { hlc: HlcTimestamp { physical, logical }, id })
+    }
+}
+
+pub fn page_from(messages: &[&ChatMessage], start: usize, end: usize) -> MessagePage {
+    let slice = &messages[start..end];
+    MessagePage {
+        before_cursor: slice.first().map(|m| MessageCursor::for_message(m).encode()),
+        after_cursor: slice.last().map(|m| MessageCursor::for_message(m).encode()),
+        messages: slice.iter().map(|m| (*m).clone()).collect(),
+        has_more_before: start > 0,
+        has_more_after: end < messages.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room::Room;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use crate::networking::Protocol;
+    use crate::user::User;
+    use crate::channel::GENERAL_CHANNEL;
+
+    #[test]
+    fn test_cursor_pagination() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let user = User::new("User".to_string(), addr);
+        let user_id = user.id;
+        let mut room = Room::new("Test Room".to_string(), user, Protocol::TCP);
+
+        for i in 0..25 {
+            room.add_message(ChatMessage::new(user_id, "User".to_string(), format!("message {}", i)));
+        }
+
+        let latest = room.page_messages(GENERAL_CHANNEL, None, PageDirection::Before, 10);
+        assert_eq!(latest.messages.len(), 10);
+        assert_eq!(latest.messages[9].content, "message 24");
+        assert!(latest.has_more_before && !latest.has_more_after);
+
+        // Walk back to the start
+        let cursor = MessageCursor::decode(latest.before_cursor.as_deref().unwrap()).unwrap();
+        let older = room.page_messages(GENERAL_CHANNEL, Some(&cursor), PageDirection::Before, 10);
+        assert_eq!(older.messages[0].content, "message 5");
+        let cursor = MessageCursor::decode(older.before_cursor.as_deref().unwrap()).unwrap();
+        let oldest = room.page_messages(GENERAL_CHANNEL, Some(&cursor), PageDirection::Before, 10);
+        assert_eq!(oldest.messages.len(), 5);
+        assert!(!oldest.has_more_before);
+
+        // And forward again from the same cursor
+        let newer = room.page_messages(GENERAL_CHANNEL, Some(&cursor), PageDirection::After, 3);
+        assert_eq!(newer.messages[0].content, "message 6");
+
+        let target = room.messages[12].id;
+        let around = room.messages_around(target, 5).unwrap();
+        assert_eq!(around.messages.len(), 5);
+        assert_eq!(around.messages[2].id, target);
+
+        assert!(MessageCursor::decode("not a cursor").is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use base64::{Engine as _, engine::general_purpose};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use ring::pbkdf2;
+use uuid::Uuid;
+use anyhow::Result;
+use crate::identity;
+
+const DEFAULT_ITERATIONS: u32 = 100_000;
+
+/// Public verifier for a room password.
+///
+/// The password is stretched with PBKDF2 into an Ed25519 seed and only the
+/// resulting public key is kept, so neither the room file nor the wire ever
+/// carries the password or anything that can be replayed to prove it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomPassword {
+    pub salt: String,
+    pub iterations: u32,
+    pub verifier: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PasswordChallenge {
+    pub nonce: String,
+    pub salt: String,
+    pub iterations: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordProof {
+    pub nonce: String,
+    pub signature: String,
+}
+
+impl RoomPassword {
+    pub fn new(password: &str) -> Result<Self> {
+        Self::with_iterations(password, DEFAULT_ITERATIONS)
+    }
+
+    fn with_iterations(password: &str, iterations: u32) -> Result<Self> {
+        let salt = general_purpose::URL_SAFE_NO_PAD.encode(random_bytes::<16>()?);
+        let key_pair = derive_key_pair(password, &salt, iterations)?;
+
+        Ok(Self {
+            salt,
+            iterations,
+            verifier: general_purpose::URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+        })
+    }
+
+    pub fn challenge(&self) -> Result<PasswordChallenge> {
+        Ok(PasswordChallenge {
+            nonce: general_purpose::URL_SAFE_NO_PAD.encode(random_bytes::<32>()?),
+            salt: self.salt.clone(),
+            iterations: self.iterations,
+        })
+    }
+
+    pub fn verify_proof(&self, room_id: Uuid, user_id: Uuid, proof: &PasswordProof) -> bool {
+        let payload = proof_payload(room_id, user_id, &proof.nonce);
+        identity::verify_signature(&self.verifier, &payload, &proof.signature)
+    }
+}
+
+pub fn prove(password: &str, challenge: &PasswordChallenge, room_id: Uuid, user_id: Uuid) -> Result<PasswordProof> {
+    let key_pair = derive_key_pair(password, &challenge.salt, challenge.iterations)?;
+    let payload = proof_payload(room_id, user_id, &challenge.nonce);
+
+    Ok(PasswordProof {
+        nonce: challenge.nonce.clone(),
+        signature: general_purpose::URL_SAFE_NO_PAD.encode(key_pair.sign(&payload).as_ref()),
+    })
+}
+
+fn proof_payload(room_id: Uuid, user_id: Uuid, nonce: &str) -> Vec<u8> {
+    format!("room-password:{}:{}:{}", room_id, user_id, nonce).into_bytes()
+}
+
+fn derive_key_pair(password: &str, salt: &str, iterations: u32) -> Result<Ed25519KeyPair> {
+    let iterations = NonZeroU32::new(iterations)
+        .ok_or_else(|| anyhow::anyhow!("Password iterations must be non-zero"))?;
+    let salt = general_purpose::URL_SAFE_NO_PAD.decode(salt)?;
+
+    let mut seed = [0u8; 32];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut seed);
+
+    Ed25519KeyPair::from_seed_unchecked(&seed)
+        .map_err(|_| anyhow::anyhow!("Failed to derive password key"))
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| anyhow::anyhow!("Failed to generate random bytes"))?;
+    Ok(bytes)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PasswordCheck {
+    Accepted,
+    Challenge(PasswordChallenge),
+    Rejected,
+    RateLimited,
+}
+
+/// Room id and the address the join request came from. Identity keys and
+/// user ids cost nothing to make, so failures are counted per address.
+type Attempter = (Uuid, IpAddr);
+
+/// Server-side bookkeeping for password handshakes: outstanding challenges
+/// and failed attempts per room and address. Lives only in memory.
+pub struct PasswordGate {
+    challenges: HashMap<Attempter, (String, chrono::DateTime<chrono::Utc>)>,
+    failures: HashMap<Attempter, Vec<chrono::DateTime<chrono::Utc>>>,
+    max_failures: usize,
+    failure_window: chrono::Duration,
+    challenge_ttl: chrono::Duration,
+}
+
+impl PasswordGate {
+    pub fn new(max_failures: usize, failure_window: chrono::Duration) -> Self {
+        Self {
+            challenges: HashMap::new(),
+            failures: HashMap::new(),
+            max_failures,
+            failure_window,
+            challenge_ttl: chrono::Duration::minutes(2),
+        }
+    }
+
+    /// `source` must come from a join request whose signature was already
+    /// verified.
+    pub fn check(
+        &mut self,
+        password: &RoomPassword,
+        room_id: Uuid,
+        user_id: Uuid,
+        source: IpAddr,
+        proof: Option<&PasswordProof>,
+    ) -> Result<PasswordCheck> {
+        self.sweep_challenges();
+        let attempter = (room_id, source);
+        if self.is_rate_limited(&attempter) {
+            return Ok(PasswordCheck::RateLimited);
+        }
+
+        let proof = match proof {
+            Some(proof) => proof,
+            None => {
+                let challenge = password.challenge()?;
+                self.challenges.insert(attempter, (challenge.nonce.clone(), chrono::Utc::now()));
+                return Ok(PasswordCheck::Challenge(challenge));
+            }
+        };
+
+        // Challenges are single use, whatever the outcome
+        let issued = self.challenges.remove(&attempter);
+        let nonce_valid = match issued {
+            Some((nonce, issued_at)) => {
+                nonce == proof.nonce && chrono::Utc::now() - issued_at < self.challenge_ttl
+            }
+            None => false,
+        };
+
+        if nonce_valid && password.verify_proof(room_id, user_id, proof) {
+            self.failures.remove(&attempter);
+            Ok(PasswordCheck::Accepted)
+        } else {
+            self.failures.entry(attempter).or_default().push(chrono::Utc::now());
+            Ok(PasswordCheck::Rejected)
+        }
+    }
+
+    /// Drops challenges that were never answered in time.
+    fn sweep_challenges(&mut self) {
+        let cutoff = chrono::Utc::now() - self.challenge_ttl;
+        self.challenges.retain(|_, (_, issued_at)| *issued_at > cutoff);
+    }
+
+    fn is_rate_limited(&mut self, attempter: &Attempter) -> bool {
+        let cutoff = chrono::Utc::now() - self.failure_window;
+
+        if let Some(attempts) = self.failures.get_mut(attempter) {
+            attempts.retain(|attempt| *attempt > cutoff);
+            if attempts.is_empty() {
+                self.failures.remove(attempter);
+                return false;
+            }
+            return attempts.len() >= self.max_failures;
+        }
+
+        false
+    }
+}
+
+impl Default for PasswordGate {
+    fn default() -> Self {
+        Self::new(5, chrono::Duration::minutes(10))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_password_challenge_response() {
+        let password = RoomPassword::with_iterations("hunter2", 1_000).unwrap();
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+        let key = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut gate = PasswordGate::default();
+
+        // The stored verifier never contains the password itself
+        let json = serde_json::to_string(&password).unwrap();
+        assert!(!json.contains("hunter2"));
+
+        let challenge = match gate.check(&password, room_id, user_id, key, None).unwrap() {
+            PasswordCheck::Challenge(challenge) => challenge,
+            other => panic!("Expected a challenge, got {:?}", other),
+        };
+
+        let proof = prove("hunter2", &challenge, room_id, user_id).unwrap();
+        assert_eq!(gate.check(&password, room_id, user_id, key, Some(&proof)).unwrap(), PasswordCheck::Accepted);
+
+        // Replaying the same proof fails because the nonce was consumed
+        assert_eq!(gate.check(&password, room_id, user_id, key, Some(&proof)).unwrap(), PasswordCheck::Rejected);
+    }
+
+    #[test]
+    fn test_wrong_password_is_rate_limited() {
+        let password = RoomPassword::with_iterations("hunter2", 1_000).unwrap();
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+        let key = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut gate = PasswordGate::new(2, chrono::Duration::minutes(10));
+
+        for _ in 0..2 {
+            let challenge = match gate.check(&password, room_id, user_id, key, None).unwrap() {
+                PasswordCheck::Challenge(challenge) => challenge,
+                other => panic!("Expected a challenge, got {:?}", other),
+            };
+            let proof = prove("letmein", &challenge, room_id, user_id).unwrap();
+            assert_eq!(gate.check(&password, room_id, user_id, key, Some(&proof)).unwrap(), PasswordCheck::Rejected);
+        }
+
+        assert_eq!(gate.check(&password, room_id, user_id, key, None).unwrap(), PasswordCheck::RateLimited);
+
+        // A fresh user id doesn't reset the count for the same address
+        assert_eq!(gate.check(&password, room_id, Uuid::new_v4(), key, None).unwrap(), PasswordCheck::RateLimited);
+
+        // Other addresses, and the same address in other rooms, are unaffected
+        assert!(matches!(
+            gate.check(&password, room_id, user_id, IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), None).unwrap(),
+            PasswordCheck::Challenge(_)
+        ));
+        assert!(matches!(
+            gate.check(&password, Uuid::new_v4(), user_id, key, None).unwrap(),
+            PasswordCheck::Challenge(_)
+        ));
+    }
+
+    #[test]
+    fn test_unanswered_challenges_expire() {
+        let password = RoomPassword::with_iterations("hunter2", 1_000).unwrap();
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+        let mut gate = PasswordGate::default();
+
+        for last in 1..=20 {
+            gate.check(&password, room_id, user_id, IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)), None).unwrap();
+        }
+        assert_eq!(gate.challenges.len(), 20);
+
+        // Once past their lifetime they're swept on the next check
+        gate.challenge_ttl = chrono::Duration::zero();
+        let key = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let challenge = match gate.check(&password, room_id, user_id, key, None).unwrap() {
+            PasswordCheck::Challenge(challenge) => challenge,
+            other => panic!("Expected a challenge, got {:?}", other),
+        };
+        assert!(gate.challenges.len() <= 1);
+
+        let proof = prove("hunter2", &challenge, room_id, user_id).unwrap();
+        assert_eq!(gate.check(&password, room_id, user_id, key, Some(&proof)).unwrap(), PasswordCheck::Rejected);
+    }
+}
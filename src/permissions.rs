@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Room roles, ordered from least to most privileged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Role {
+    Guest,
+    #[default]
+    Member,
+    Moderator,
+    Owner,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Permission {
+    SendMessages,
+    GenerateInvite,
+    ManageJoins,
+    KickUsers,
+    BanUsers,
+    MuteUsers,
+    ManageMessages,
+    RenameRoom,
+    ChangeProtocol,
+    ManageSettings,
+    ManageRoles,
+    ManageChannels,
+}
+
+impl Role {
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::Guest => &[Permission::SendMessages],
+            Role::Member => &[Permission::SendMessages, Permission::GenerateInvite],
+            Role::Moderator => &[
+                Permission::SendMessages,
+                Permission::GenerateInvite,
+                Permission::ManageJoins,
+                Permission::KickUsers,
+                Permission::BanUsers,
+                Permission::MuteUsers,
+                Permission::ManageMessages,
+                Permission::RenameRoom,
+                Permission::ManageRoles,
+                Permission::ManageChannels,
+            ],
+            Role::Owner => &[
+                Permission::SendMessages,
+                Permission::GenerateInvite,
+                Permission::ManageJoins,
+                Permission::KickUsers,
+                Permission::BanUsers,
+                Permission::MuteUsers,
+                Permission::ManageMessages,
+                Permission::RenameRoom,
+                Permission::ChangeProtocol,
+                Permission::ManageSettings,
+                Permission::ManageRoles,
+                Permission::ManageChannels,
+            ],
+        }
+    }
+
+    pub fn has(&self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoleChange {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub role: Role,
+    pub changed_by: Uuid,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permission_table() {
+        assert!(Role::Owner.has(Permission::ChangeProtocol));
+        assert!(!Role::Moderator.has(Permission::ChangeProtocol));
+        assert!(Role::Moderator.has(Permission::KickUsers));
+        assert!(Role::Moderator.has(Permission::ManageChannels));
+        assert!(!Role::Member.has(Permission::ManageChannels));
+        assert!(Role::Member.has(Permission::GenerateInvite));
+        assert!(!Role::Guest.has(Permission::GenerateInvite));
+        assert!(Role::Guest.has(Permission::SendMessages));
+
+        assert!(Role::Owner > Role::Moderator);
+        assert!(Role::Member > Role::Guest);
+    }
+}
@@ -133,14 +133,12 @@ impl ProtocolManager {
         // Implementation would depend on the current networking layer
         // This is a placeholder for sending the preparation message
         
-        let _message = crate::networking::NetworkMessage {
-            id: Uuid::new_v4(),
-            from: "self".to_string(),
-            to: Some(peer.to_string()),
-            message_type: crate::networking::MessageType::ProtocolChange,
-            payload: serde_json::to_value(new_protocol)?,
-            timestamp: chrono::Utc::now(),
-        };
+        let _message = crate::networking::NetworkMessage::new(
+            "self".to_string(),
+            Some(peer.to_string()),
+            crate::networking::MessageType::ProtocolChange,
+            serde_json::to_value(new_protocol)?,
+        );
 
         // Send the message through the current networking layer
         // This would be implemented based on the active protocol
@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use uuid::Uuid;
+use crate::clock::HlcTimestamp;
+
+/// How far a user has read in one channel. Markers only move forward.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReadMarker {
+    pub channel_id: Uuid,
+    pub message_id: Uuid,
+    pub hlc: HlcTimestamp,
+}
+
+impl ReadMarker {
+    pub fn order_key(&self) -> (HlcTimestamp, Uuid) {
+        (self.hlc, self.message_id)
+    }
+}
+
+/// A receipt as it travels between peers. Always sent by the user it is
+/// about.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ReceiptUpdate {
+    Delivered {
+        room_id: Uuid,
+        user_id: Uuid,
+        message_ids: Vec<Uuid>,
+    },
+    Read {
+        room_id: Uuid,
+        user_id: Uuid,
+        marker: ReadMarker,
+    },
+}
+
+impl ReceiptUpdate {
+    pub fn room_id(&self) -> Uuid {
+        match self {
+            ReceiptUpdate::Delivered { room_id, .. } | ReceiptUpdate::Read { room_id, .. } => *room_id,
+        }
+    }
+
+    pub fn user_id(&self) -> Uuid {
+        match self {
+            ReceiptUpdate::Delivered { user_id, .. } | ReceiptUpdate::Read { user_id, .. } => *user_id,
+        }
+    }
+}
+
+/// Who has received which messages and how far each member has read.
+/// Merging is a union of deliveries and the furthest marker per channel,
+/// so peers converge whatever order receipts arrive in.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Receipts {
+    #[serde(default)]
+    pub delivered: HashMap<Uuid, BTreeSet<Uuid>>,
+    /// Read markers by user, then channel
+    #[serde(default)]
+    pub read: HashMap<Uuid, HashMap<Uuid, ReadMarker>>,
+    /// Deliveries recorded since the store last saw them, as
+    /// (message, user)
+    #[serde(skip)]
+    unsaved_deliveries: Vec<(Uuid, Uuid)>,
+    /// Markers moved since the store last saw them, as (user, channel)
+    #[serde(skip)]
+    unsaved_markers: HashSet<(Uuid, Uuid)>,
+}
+
+/// Receipts the store has yet to write: deliveries as (message, user)
+/// and read markers by user.
+#[derive(Debug, Default, PartialEq)]
+pub struct UnsavedReceipts {
+    pub deliveries: Vec<(Uuid, Uuid)>,
+    pub read_markers: Vec<(Uuid, ReadMarker)>,
+}
+
+/// Receipts for one message, as shown to the UI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageReceipts {
+    pub message_id: Uuid,
+    pub delivered_to: Vec<Uuid>,
+    pub read_by: Vec<Uuid>,
+}
+
+impl Receipts {
+    pub fn record_delivery(&mut self, user_id: Uuid, message_id: Uuid) -> bool {
+        let added = self.delivered.entry(message_id).or_default().insert(user_id);
+        if added {
+            self.unsaved_deliveries.push((message_id, user_id));
+        }
+        added
+    }
+
+    pub fn record_read(&mut self, user_id: Uuid, marker: ReadMarker) -> bool {
+        let markers = self.read.entry(user_id).or_default();
+        match markers.get(&marker.channel_id) {
+            Some(existing) if existing.order_key() >= marker.order_key() => false,
+            _ => {
+                markers.insert(marker.channel_id, marker);
+                self.unsaved_markers.insert((user_id, marker.channel_id));
+                true
+            }
+        }
+    }
+
+    pub fn read_marker(&self, user_id: Uuid, channel_id: Uuid) -> Option<&ReadMarker> {
+        self.read.get(&user_id)?.get(&channel_id)
+    }
+
+    /// Deliveries for the given messages, plus every read marker; what a
+    /// peer gets alongside those messages during history sync.
+    pub fn for_messages<'a>(&self, message_ids: impl IntoIterator<Item = &'a Uuid>) -> Receipts {
+        let delivered = message_ids.into_iter()
+            .filter_map(|id| Some((*id, self.delivered.get(id)?.clone())))
+            .collect();
+        Receipts { delivered, read: self.read.clone(), ..Default::default() }
+    }
+
+    /// Receipts only from the given user, the one a sync came from.
+    pub fn retain_user(&mut self, user_id: Uuid) {
+        self.delivered.retain(|_, users| {
+            users.retain(|id| *id == user_id);
+            !users.is_empty()
+        });
+        self.read.retain(|id, _| *id == user_id);
+    }
+
+    /// Deliveries and read markers the store hasn't seen, or all of them.
+    /// Deliveries are kept even if their message has since been trimmed
+    /// from memory.
+    pub fn unsaved(&self, everything: bool) -> UnsavedReceipts {
+        if everything {
+            let deliveries = self.delivered.iter()
+                .flat_map(|(message_id, users)| users.iter().map(move |user_id| (*message_id, *user_id)))
+                .chain(self.unsaved_deliveries.iter().copied())
+                .collect();
+            let read_markers = self.read.iter()
+                .flat_map(|(user_id, markers)| markers.values().map(move |marker| (*user_id, *marker)))
+                .collect();
+            return UnsavedReceipts { deliveries, read_markers };
+        }
+
+        let read_markers = self.unsaved_markers.iter()
+            .filter_map(|(user_id, channel_id)| Some((*user_id, *self.read_marker(*user_id, *channel_id)?)))
+            .collect();
+        UnsavedReceipts { deliveries: self.unsaved_deliveries.clone(), read_markers }
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.unsaved_deliveries.clear();
+        self.unsaved_markers.clear();
+    }
+
+    pub fn retain_messages(&mut self, mut keep: impl FnMut(&Uuid) -> bool) {
+        self.delivered.retain(|message_id, _| keep(message_id));
+    }
+
+    pub fn remove_channel(&mut self, channel_id: Uuid) {
+        for markers in self.read.values_mut() {
+            markers.remove(&channel_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_markers_only_move_forward() {
+        let (user_id, channel_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let marker = |physical| ReadMarker {
+            channel_id,
+            message_id: Uuid::new_v4(),
+            hlc: HlcTimestamp { physical, logical: 0 },
+        };
+
+        let mut receipts = Receipts::default();
+        assert!(receipts.record_read(user_id, marker(20)));
+        assert!(!receipts.record_read(user_id, marker(10)));
+        assert!(receipts.record_read(user_id, marker(30)));
+        assert_eq!(receipts.read_marker(user_id, channel_id).unwrap().hlc.physical, 30);
+
+        let message_id = Uuid::new_v4();
+        assert!(receipts.record_delivery(user_id, message_id));
+        assert!(!receipts.record_delivery(user_id, message_id));
+        receipts.retain_messages(|id| *id != message_id);
+        assert!(receipts.delivered.is_empty());
+
+        // What changed is still there for the store after trimming, until saved
+        let unsaved = receipts.unsaved(false);
+        assert_eq!(unsaved.deliveries, vec![(message_id, user_id)]);
+        assert_eq!(unsaved.read_markers.len(), 1);
+        assert_eq!(unsaved.read_markers[0].1.hlc.physical, 30);
+        receipts.mark_saved();
+        assert_eq!(receipts.unsaved(false), UnsavedReceipts::default());
+    }
+
+    #[test]
+    fn test_retain_user_keeps_only_their_receipts() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let (shared, bobs) = (Uuid::new_v4(), Uuid::new_v4());
+        let marker = ReadMarker { channel_id: Uuid::new_v4(), message_id: shared, hlc: HlcTimestamp::default() };
+
+        let mut receipts = Receipts::default();
+        receipts.record_delivery(alice, shared);
+        receipts.record_delivery(bob, shared);
+        receipts.record_delivery(bob, bobs);
+        receipts.record_read(alice, marker);
+        receipts.record_read(bob, marker);
+
+        receipts.retain_user(alice);
+        assert_eq!(receipts.delivered.len(), 1);
+        assert_eq!(receipts.delivered[&shared], BTreeSet::from([alice]));
+        assert!(receipts.read.contains_key(&alice) && !receipts.read.contains_key(&bob));
+    }
+}
@@ -0,0 +1,295 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use uuid::Uuid;
+use crate::clock;
+use crate::networking::NetworkMessage;
+
+// Width of the per-sender sliding window, in sequence numbers
+const WINDOW_SIZE: u64 = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ReplayRejection {
+    Duplicate,
+    Replayed,
+    TooOld,
+    Unsequenced,
+    ClockSkew,
+    Forged,
+}
+
+impl std::fmt::Display for ReplayRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayRejection::Duplicate => write!(f, "duplicate message id"),
+            ReplayRejection::Replayed => write!(f, "sequence number already seen"),
+            ReplayRejection::TooOld => write!(f, "sequence number outside replay window"),
+            ReplayRejection::Unsequenced => write!(f, "message has no sequence number"),
+            ReplayRejection::ClockSkew => write!(f, "timestamp outside accepted clock skew"),
+            ReplayRejection::Forged => write!(f, "not signed by the sender"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReplayStats {
+    pub accepted: u64,
+    pub duplicates: u64,
+    pub replayed: u64,
+    pub too_old: u64,
+    pub unsequenced: u64,
+    pub clock_skew: u64,
+    #[serde(default)]
+    pub forged: u64,
+}
+
+impl ReplayStats {
+    pub fn total_rejected(&self) -> u64 {
+        self.duplicates + self.replayed + self.too_old + self.unsequenced + self.clock_skew + self.forged
+    }
+
+    fn record(&mut self, rejection: &ReplayRejection) {
+        match rejection {
+            ReplayRejection::Duplicate => self.duplicates += 1,
+            ReplayRejection::Replayed => self.replayed += 1,
+            ReplayRejection::TooOld => self.too_old += 1,
+            ReplayRejection::Unsequenced => self.unsequenced += 1,
+            ReplayRejection::ClockSkew => self.clock_skew += 1,
+            ReplayRejection::Forged => self.forged += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct SenderWindow {
+    highest: u64,
+    // Bit N set means sequence `highest - N` has been accepted
+    bitmap: u64,
+}
+
+impl SenderWindow {
+    fn check(&self, sequence: u64) -> Result<(), ReplayRejection> {
+        if sequence > self.highest {
+            return Ok(());
+        }
+
+        let offset = self.highest - sequence;
+        if offset >= WINDOW_SIZE {
+            return Err(ReplayRejection::TooOld);
+        }
+        if self.bitmap & (1 << offset) != 0 {
+            return Err(ReplayRejection::Replayed);
+        }
+        Ok(())
+    }
+
+    fn mark(&mut self, sequence: u64) {
+        if sequence > self.highest {
+            let shift = sequence - self.highest;
+            self.bitmap = if shift >= WINDOW_SIZE { 1 } else { (self.bitmap << shift) | 1 };
+            self.highest = sequence;
+        } else {
+            self.bitmap |= 1 << (self.highest - sequence);
+        }
+    }
+}
+
+pub struct ReplayGuard {
+    windows: HashMap<String, SenderWindow>,
+    seen_ids: HashSet<Uuid>,
+    seen_order: VecDeque<Uuid>,
+    max_seen_ids: usize,
+    max_clock_skew: chrono::Duration,
+    stats: ReplayStats,
+}
+
+impl ReplayGuard {
+    pub fn new(max_seen_ids: usize, max_clock_skew: chrono::Duration) -> Self {
+        Self {
+            windows: HashMap::new(),
+            seen_ids: HashSet::new(),
+            seen_order: VecDeque::new(),
+            max_seen_ids,
+            max_clock_skew,
+            stats: ReplayStats::default(),
+        }
+    }
+
+    /// Members' messages are checked against their identity key and get
+    /// a window of their own; anything unsigned is tracked apart, so a
+    /// forged `from` can't push a member's window ahead and silence them.
+    pub fn check(&mut self, message: &NetworkMessage, sender_key: Option<&str>) -> Result<(), ReplayRejection> {
+        let window_key = match sender_key {
+            Some(_) => message.from.clone(),
+            None => format!("unverified:{}", message.from),
+        };
+        let result = self.evaluate(message, sender_key, &window_key);
+
+        match &result {
+            Ok(()) => {
+                self.windows
+                    .entry(window_key)
+                    .or_default()
+                    .mark(message.sequence);
+                self.remember_id(message.id);
+                self.stats.accepted += 1;
+            }
+            Err(rejection) => self.stats.record(rejection),
+        }
+
+        result
+    }
+
+    fn evaluate(&self, message: &NetworkMessage, sender_key: Option<&str>, window_key: &str) -> Result<(), ReplayRejection> {
+        if let Some(sender_key) = sender_key {
+            if !message.is_signed_by(sender_key) {
+                return Err(ReplayRejection::Forged);
+            }
+        }
+
+        let skew = chrono::Utc::now().signed_duration_since(message.timestamp);
+        if skew > self.max_clock_skew || -skew > self.max_clock_skew {
+            return Err(ReplayRejection::ClockSkew);
+        }
+
+        // A logical clock pushed far ahead would drag every peer's clock with it
+        let hlc_limit = (chrono::Utc::now() + self.max_clock_skew).timestamp_millis();
+        if message.hlc.physical > hlc_limit {
+            return Err(ReplayRejection::ClockSkew);
+        }
+
+        // The same message can arrive over several paths (direct and relayed)
+        if self.seen_ids.contains(&message.id) {
+            return Err(ReplayRejection::Duplicate);
+        }
+
+        if message.sequence == 0 {
+            return Err(ReplayRejection::Unsequenced);
+        }
+
+        match self.windows.get(window_key) {
+            Some(window) => window.check(message.sequence),
+            None => Ok(()),
+        }
+    }
+
+    fn remember_id(&mut self, id: Uuid) {
+        if self.seen_ids.insert(id) {
+            self.seen_order.push_back(id);
+        }
+
+        while self.seen_order.len() > self.max_seen_ids {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen_ids.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> &ReplayStats {
+        &self.stats
+    }
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self::new(4096, clock::MAX_SKEW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::LocalIdentity;
+    use crate::networking::MessageType;
+
+    fn message_with_sequence(sequence: u64) -> NetworkMessage {
+        let mut message = NetworkMessage::new(
+            "peer-a".to_string(),
+            None,
+            MessageType::ChatMessage,
+            serde_json::Value::Null,
+        );
+        message.sequence = sequence;
+        message
+    }
+
+    impl ReplayGuard {
+        fn check_unsigned(&mut self, message: &NetworkMessage) -> Result<(), ReplayRejection> {
+            self.check(message, None)
+        }
+    }
+
+    #[test]
+    fn test_rejects_duplicate_and_replayed_messages() {
+        let mut guard = ReplayGuard::default();
+
+        let first = message_with_sequence(10);
+        assert!(guard.check_unsigned(&first).is_ok());
+
+        // Exact copy arriving over a second path
+        assert_eq!(guard.check_unsigned(&first), Err(ReplayRejection::Duplicate));
+
+        // Fresh id but a sequence number that was already used
+        let replay = message_with_sequence(10);
+        assert_eq!(guard.check_unsigned(&replay), Err(ReplayRejection::Replayed));
+
+        // Out-of-order delivery inside the window is still accepted
+        assert!(guard.check_unsigned(&message_with_sequence(12)).is_ok());
+        assert!(guard.check_unsigned(&message_with_sequence(11)).is_ok());
+
+        assert_eq!(guard.stats().accepted, 3);
+        assert_eq!(guard.stats().total_rejected(), 2);
+    }
+
+    #[test]
+    fn test_rejects_old_sequence_and_clock_skew() {
+        let mut guard = ReplayGuard::default();
+
+        assert!(guard.check_unsigned(&message_with_sequence(100)).is_ok());
+        assert_eq!(
+            guard.check_unsigned(&message_with_sequence(100 - WINDOW_SIZE)),
+            Err(ReplayRejection::TooOld)
+        );
+
+        let mut stale = message_with_sequence(101);
+        stale.timestamp = chrono::Utc::now() - chrono::Duration::minutes(10);
+        assert_eq!(guard.check_unsigned(&stale), Err(ReplayRejection::ClockSkew));
+
+        assert_eq!(guard.check_unsigned(&message_with_sequence(0)), Err(ReplayRejection::Unsequenced));
+    }
+
+    #[test]
+    fn test_forged_sequence_cannot_silence_a_member() {
+        let identity = LocalIdentity::generate().unwrap();
+        let key = identity.public_key();
+        let signed = |sequence| {
+            let mut message = message_with_sequence(sequence);
+            message.signature = Some(identity.sign(&message.signing_payload()));
+            message
+        };
+        let mut guard = ReplayGuard::default();
+
+        // Someone else claims to be the member with a huge sequence number
+        let forged = message_with_sequence(u64::MAX - 1);
+        assert_eq!(guard.check(&forged, Some(&key)), Err(ReplayRejection::Forged));
+        assert!(guard.check(&message_with_sequence(u64::MAX - 2), None).is_ok());
+
+        assert!(guard.check(&signed(5), Some(&key)).is_ok());
+        let mut tampered = signed(6);
+        tampered.sequence = 7;
+        assert_eq!(guard.check(&tampered, Some(&key)), Err(ReplayRejection::Forged));
+        assert!(guard.check(&signed(6), Some(&key)).is_ok());
+        assert_eq!(guard.stats().forged, 2);
+    }
+
+    #[test]
+    fn test_seen_set_is_bounded() {
+        let mut guard = ReplayGuard::new(2, chrono::Duration::minutes(2));
+
+        for sequence in 1..=5 {
+            assert!(guard.check_unsigned(&message_with_sequence(sequence)).is_ok());
+        }
+
+        assert_eq!(guard.seen_ids.len(), 2);
+        assert_eq!(guard.seen_order.len(), 2);
+    }
+}
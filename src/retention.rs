@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// How much of a room's history its peers keep. It's a room setting, so
+/// every peer applies the same policy, and it travels with the sync
+/// summaries so others know not to expect history beyond it. Pinned
+/// messages are always kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "limit")]
+pub enum RetentionPolicy {
+    #[default]
+    Unlimited,
+    MaxCount(usize),
+    MaxAgeDays(u32),
+    MaxBytes(u64),
+}
+
+/// What a compaction pass decided to drop.
+#[derive(Debug, Default)]
+pub struct Compaction {
+    pub expired: Vec<Uuid>,
+    /// Newest physical time among the expired messages; history at or
+    /// before it may be incomplete from now on
+    pub horizon: Option<i64>,
+}
+
+impl RetentionPolicy {
+    /// Limits of zero would drop everything, so they're refused.
+    pub fn is_valid(&self) -> bool {
+        !matches!(self, RetentionPolicy::MaxCount(0) | RetentionPolicy::MaxAgeDays(0) | RetentionPolicy::MaxBytes(0))
+    }
+
+    /// Messages older than this (in milliseconds) are past the policy.
+    pub fn age_cutoff(&self, now: chrono::DateTime<chrono::Utc>) -> Option<i64> {
+        match self {
+            RetentionPolicy::MaxAgeDays(days) => {
+                Some((now - chrono::Duration::days(*days as i64)).timestamp_millis())
+            }
+            _ => None,
+        }
+    }
+
+    /// Picks the messages to drop from `(id, physical time, stored size)`
+    /// entries of unpinned messages, newest first.
+    pub fn select_expired(
+        &self,
+        newest_first: impl IntoIterator<Item = (Uuid, i64, u64)>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Compaction {
+        let mut compaction = Compaction::default();
+        if *self == RetentionPolicy::Unlimited {
+            return compaction;
+        }
+
+        let cutoff = self.age_cutoff(now);
+        let mut kept = 0usize;
+        let mut kept_bytes = 0u64;
+        let mut dropping = false;
+
+        for (id, physical, size) in newest_first {
+            // Once something is dropped, everything older goes too
+            let keep = !dropping && match self {
+                RetentionPolicy::Unlimited => true,
+                RetentionPolicy::MaxCount(max) => kept < *max,
+                RetentionPolicy::MaxAgeDays(_) => cutoff.is_some_and(|cutoff| physical >= cutoff),
+                RetentionPolicy::MaxBytes(max) => kept_bytes + size <= *max,
+            };
+
+            if keep {
+                kept += 1;
+                kept_bytes += size;
+            } else {
+                dropping = true;
+                compaction.horizon = compaction.horizon.max(Some(physical));
+                compaction.expired.push(id);
+            }
+        }
+
+        compaction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policies_drop_oldest_first() {
+        let now = chrono::Utc::now();
+        let day = 24 * 3600 * 1000;
+        let entries: Vec<(Uuid, i64, u64)> = (0..5)
+            .map(|i| (Uuid::new_v4(), now.timestamp_millis() - i * day, 100))
+            .collect();
+
+        assert!(RetentionPolicy::Unlimited.select_expired(entries.clone(), now).expired.is_empty());
+
+        let by_count = RetentionPolicy::MaxCount(2).select_expired(entries.clone(), now);
+        assert_eq!(by_count.expired, vec![entries[2].0, entries[3].0, entries[4].0]);
+        assert_eq!(by_count.horizon, Some(entries[2].1));
+
+        let by_age = RetentionPolicy::MaxAgeDays(3).select_expired(entries.clone(), now);
+        assert_eq!(by_age.expired, vec![entries[4].0]);
+
+        let by_size = RetentionPolicy::MaxBytes(250).select_expired(entries.clone(), now);
+        assert_eq!(by_size.expired.len(), 3);
+    }
+}
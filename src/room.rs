@@ -1,19 +1,359 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::net::SocketAddr;
 use crate::user::User;
 use crate::networking::Protocol;
+use crate::clock::{self, HlcTimestamp};
+use crate::pagination::{self, MessageCursor, MessagePage, PageDirection};
+use crate::search::{self, SearchFilter, SearchIndex, SearchResult};
+use crate::storage::PingSample;
+use crate::retention::{Compaction, RetentionPolicy};
+use crate::event::RoomEvent;
+use crate::unread::{ChannelUnread, RoomUnread, UnreadCount};
+use crate::mention;
+use crate::transfer::{self, FileManifest};
+use crate::receipts::{MessageReceipts, ReadMarker, ReceiptUpdate, Receipts, UnsavedReceipts};
+use crate::channel::{self, Channel, ChannelAction, ChannelState, ChannelSummary, ChannelUpdate, GENERAL_CHANNEL};
+use crate::join::{JoinDecision, JoinRequest};
+use crate::password::RoomPassword;
+use crate::permissions::{Permission, Role};
+use crate::moderation::{Ban, ModerationAction, ModerationCommand, ModerationLogEntry};
 use anyhow::Result;
 // use std::path::Path;
 
+pub const MAX_MESSAGES: usize = 1000;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub id: Uuid,
     pub user_id: Uuid,
     pub user_name: String,
     pub content: String,
+    /// Wall-clock send time, for display only; ordering uses `hlc`
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub hlc: HlcTimestamp,
+    #[serde(default)]
+    pub edited_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub edit_history: Vec<MessageRevision>,
+    #[serde(default)]
+    pub deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub reactions: BTreeMap<String, BTreeSet<Uuid>>,
+    /// Latest change to each user's reaction, per emoji; `reactions` is
+    /// what's left added
+    #[serde(default)]
+    pub reaction_marks: BTreeMap<String, BTreeMap<Uuid, ReactionMark>>,
+    /// Root of the thread this message replies to. Replies to replies are
+    /// attached to the same root, so threads stay one level deep.
+    #[serde(default)]
+    pub parent_id: Option<Uuid>,
+    #[serde(default)]
+    pub reply_count: u32,
+    #[serde(default)]
+    pub last_reply_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Pinned messages are exempt from the room's retention policy
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub channel_id: Uuid,
+    /// Set on timeline entries that record a room event rather than
+    /// something a user said
+    #[serde(default)]
+    pub event: Option<RoomEvent>,
+    /// Members mentioned by name. Each peer resolves these itself against
+    /// the room's members when the message arrives or is edited.
+    #[serde(default)]
+    pub mentions: Vec<Uuid>,
+    #[serde(default)]
+    pub mentions_everyone: bool,
+    /// A file offered to the room; the author serves its chunks
+    #[serde(default)]
+    pub attachment: Option<FileManifest>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageThread {
+    pub root: ChatMessage,
+    pub replies: Vec<ChatMessage>,
+}
+
+/// Content a message carried before an edit replaced it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageRevision {
+    pub content: String,
+    pub replaced_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageEdit {
+    pub room_id: Uuid,
+    pub message_id: Uuid,
+    pub content: String,
+    pub edited_by: Uuid,
+    pub edited_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A user adding or removing one of their own reactions. Applying the same
+/// update twice is a no-op, so peers converge whatever order updates from
+/// different users arrive in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionUpdate {
+    pub room_id: Uuid,
+    pub message_id: Uuid,
+    pub emoji: String,
+    pub user_id: Uuid,
+    pub added: bool,
+    #[serde(default)]
+    pub hlc: HlcTimestamp,
+}
+
+/// A user's last add or remove of one reaction. The later one wins, so a
+/// removal isn't undone by a copy of the message from before it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReactionMark {
+    pub added: bool,
+    pub hlc: HlcTimestamp,
+}
+
+/// Record that a message was deleted. Kept after the message itself is gone
+/// so that copies arriving later from other peers stay deleted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageTombstone {
+    pub room_id: Uuid,
+    pub message_id: Uuid,
+    pub deleted_by: Uuid,
+    pub deleted_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinUpdate {
+    pub room_id: Uuid,
+    pub message_id: Uuid,
+    pub pinned: bool,
+    pub pinned_by: Uuid,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomRename {
+    pub room_id: Uuid,
+    pub name: String,
+    pub renamed_by: Uuid,
+}
+
+/// A member joining or leaving the voice call; only they can send it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CallUpdate {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub joined: bool,
+}
+
+impl ChatMessage {
+    pub fn new(user_id: Uuid, user_name: String, content: String) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id,
+            user_name,
+            content,
+            timestamp: chrono::Utc::now(),
+            hlc: clock::LOCAL.now(),
+            edited_at: None,
+            edit_history: Vec::new(),
+            deleted_at: None,
+            reactions: BTreeMap::new(),
+            reaction_marks: BTreeMap::new(),
+            parent_id: None,
+            reply_count: 0,
+            last_reply_at: None,
+            pinned: false,
+            channel_id: GENERAL_CHANNEL,
+            event: None,
+            mentions: Vec::new(),
+            mentions_everyone: false,
+            attachment: None,
+        }
+    }
+
+    /// Events carry no author or text; the UI renders them from `event`.
+    pub fn event(event: RoomEvent) -> Self {
+        Self {
+            event: Some(event),
+            ..Self::new(Uuid::nil(), String::new(), String::new())
+        }
+    }
+
+    pub fn is_event(&self) -> bool {
+        self.event.is_some()
+    }
+
+    pub fn mentions_user(&self, user_id: Uuid) -> bool {
+        self.mentions_everyone || self.mentions.contains(&user_id)
+    }
+
+    pub fn reply(user_id: Uuid, user_name: String, content: String, parent_id: Uuid) -> Self {
+        Self {
+            parent_id: Some(parent_id),
+            ..Self::new(user_id, user_name, content)
+        }
+    }
+
+    pub fn order_key(&self) -> (HlcTimestamp, Uuid) {
+        // Messages saved before clocks were stamped fall back to wall time
+        if self.hlc.is_unset() {
+            (HlcTimestamp::from_wall(self.timestamp), self.id)
+        } else {
+            (self.hlc, self.id)
+        }
+    }
+
+    /// Monotonic marker of how far this copy has been edited, used to
+    /// spot stale copies during history sync.
+    pub fn version(&self) -> i64 {
+        match (self.deleted_at, self.edited_at) {
+            (Some(_), _) => i64::MAX,
+            (None, Some(edited_at)) => edited_at.timestamp_millis(),
+            (None, None) => 0,
+        }
+    }
+
+    fn merge_from(&mut self, mut other: ChatMessage) -> bool {
+        if self.is_deleted() || other.is_deleted() {
+            return false;
+        }
+
+        other.adopt_legacy_reactions();
+        let mut changed = false;
+        if other.version() > self.version() {
+            self.content = other.content;
+            self.edit_history = other.edit_history;
+            self.edited_at = other.edited_at;
+            changed = true;
+        }
+
+        for (emoji, marks) in other.reaction_marks {
+            for (user_id, mark) in marks {
+                changed |= self.mark_reaction(&emoji, user_id, mark);
+            }
+        }
+
+        changed
+    }
+
+    /// Records a reaction change unless a later one from the same user is
+    /// already known. Returns whether the visible reactions changed.
+    fn mark_reaction(&mut self, emoji: &str, user_id: Uuid, mark: ReactionMark) -> bool {
+        self.adopt_legacy_reactions();
+        let marks = self.reaction_marks.entry(emoji.to_string()).or_default();
+        if marks.get(&user_id).is_some_and(|known| known.hlc >= mark.hlc) {
+            return false;
+        }
+        marks.insert(user_id, mark);
+
+        if mark.added {
+            self.reactions.entry(emoji.to_string()).or_default().insert(user_id)
+        } else {
+            let removed = self.reactions.get_mut(emoji).is_some_and(|users| users.remove(&user_id));
+            if self.reactions.get(emoji).is_some_and(|users| users.is_empty()) {
+                self.reactions.remove(emoji);
+            }
+            removed
+        }
+    }
+
+    /// Messages stored before marks were kept only have the reaction sets;
+    /// those count as the oldest possible changes.
+    fn adopt_legacy_reactions(&mut self) {
+        if !self.reaction_marks.is_empty() {
+            return;
+        }
+        for (emoji, users) in &self.reactions {
+            let marks = self.reaction_marks.entry(emoji.clone()).or_default();
+            for user_id in users {
+                marks.insert(*user_id, ReactionMark { added: true, hlc: HlcTimestamp::default() });
+            }
+        }
+    }
+
+    fn keep_reactions_by(&mut self, user_id: Uuid) {
+        self.adopt_legacy_reactions();
+        for marks in self.reaction_marks.values_mut() {
+            marks.retain(|marker, _| *marker == user_id);
+        }
+        self.reaction_marks.retain(|_, marks| !marks.is_empty());
+        self.reactions = self.reaction_marks.iter()
+            .filter_map(|(emoji, marks)| {
+                let users: BTreeSet<Uuid> = marks.iter()
+                    .filter(|(_, mark)| mark.added)
+                    .map(|(user_id, _)| *user_id)
+                    .collect();
+                (!users.is_empty()).then(|| (emoji.clone(), users))
+            })
+            .collect();
+    }
+
+    fn clear_reactions(&mut self) {
+        self.reactions.clear();
+        self.reaction_marks.clear();
+    }
+
+    /// Drops what a peer sent along with a new message that only counts
+    /// when it arrives through its own checked path: reactions, pins,
+    /// edits and deletions.
+    pub fn strip_peer_state(&mut self) {
+        self.clear_reactions();
+        self.pinned = false;
+        self.deleted_at = None;
+        self.edited_at = None;
+        self.edit_history.clear();
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    fn scrub(&mut self, deleted_at: chrono::DateTime<chrono::Utc>) {
+        self.content.clear();
+        self.edit_history.clear();
+        self.clear_reactions();
+        self.mentions.clear();
+        self.mentions_everyone = false;
+        self.attachment = None;
+        self.pinned = false;
+        self.deleted_at = Some(deleted_at);
+    }
+}
+
+/// Fills in who the message mentions, going by the members we know of.
+fn resolve_mentions(users: &HashMap<Uuid, User>, message: &mut ChatMessage) {
+    let mentions = if message.is_event() || message.is_deleted() {
+        mention::Mentions::default()
+    } else {
+        mention::resolve(&message.content, users.values().map(|user| (user.id, user.name.as_str())))
+    };
+    message.mentions = mentions.user_ids;
+    message.mentions_everyone = mentions.everyone;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RoomSetting {
+    PasswordRequired(bool),
+    /// For one channel, or the whole room when `channel_id` is None
+    Retention {
+        channel_id: Option<Uuid>,
+        policy: RetentionPolicy,
+    },
+    /// None goes back to the default limit
+    MaxFileSize(Option<u64>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SettingsUpdate {
+    pub room_id: Uuid,
+    pub setting: RoomSetting,
+    pub changed_by: Uuid,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +371,61 @@ pub struct Room {
     pub is_voice_enabled: bool,
     pub call_server_id: Option<Uuid>,
     pub is_call_active: bool,
+    #[serde(default)]
+    pub require_approval: bool,
+    #[serde(default)]
+    pub pending_joins: HashMap<Uuid, JoinRequest>,
+    #[serde(default)]
+    pub awaiting_approval: bool,
+    #[serde(default)]
+    pub password: Option<RoomPassword>,
+    /// Set even where we don't hold the verifier, so a host without it
+    /// turns joiners away instead of letting them in unchecked
+    #[serde(default)]
+    pub password_required: bool,
+    #[serde(default)]
+    pub roles: HashMap<Uuid, Role>,
+    #[serde(default)]
+    pub bans: Vec<Ban>,
+    #[serde(default)]
+    pub mutes: HashMap<Uuid, Option<chrono::DateTime<chrono::Utc>>>,
+    #[serde(default)]
+    pub moderation_log: Vec<ModerationLogEntry>,
+    #[serde(default)]
+    pub tombstones: HashMap<Uuid, MessageTombstone>,
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    /// Physical time (ms) up to which history has been compacted away in
+    /// every channel; older messages offered by peers are not taken back in
+    #[serde(default)]
+    pub retention_horizon: Option<i64>,
+    /// Largest file we share or fetch in this room, if not the default
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+    #[serde(default = "channel::default_channels")]
+    pub channels: Vec<Channel>,
+    #[serde(default)]
+    pub deleted_channels: HashSet<Uuid>,
+    #[serde(default)]
+    pub channel_state: HashMap<Uuid, ChannelState>,
+    #[serde(default)]
+    pub receipts: Receipts,
+    /// Loaded or built on first search, then kept current as messages change
+    #[serde(skip)]
+    search_index: Option<SearchIndex>,
+    /// Whether the store already holds this room's history, so that only
+    /// the messages touched since can be written
+    #[serde(skip)]
+    stored: bool,
+    #[serde(skip)]
+    unsaved_messages: HashSet<Uuid>,
+    /// Trimmed from memory before the store saw them; written on next save
+    #[serde(skip)]
+    trimmed_unsaved: Vec<ChatMessage>,
+    #[serde(skip)]
+    unsaved_pings: Vec<PingSample>,
+    #[serde(skip)]
+    removed_channels: Vec<Uuid>,
 }
 
 impl Room {
@@ -44,6 +439,10 @@ impl Room {
         
         users.insert(creator_id, creator);
 
+        let mut roles = HashMap::new();
+        roles.insert(creator_id, Role::Owner);
+        let created_at = chrono::Utc::now();
+
         Self {
             id: room_id,
             name,
@@ -54,10 +453,33 @@ impl Room {
             protocol,
             peer_addresses,
             ping_measurements: HashMap::new(),
-            created_at: chrono::Utc::now(),
+            created_at,
             is_voice_enabled: false,
             call_server_id: Some(creator_id), // Creator starts as call server
             is_call_active: false,
+            require_approval: false,
+            pending_joins: HashMap::new(),
+            awaiting_approval: false,
+            password: None,
+            password_required: false,
+            roles,
+            bans: Vec::new(),
+            mutes: HashMap::new(),
+            moderation_log: Vec::new(),
+            tombstones: HashMap::new(),
+            retention: RetentionPolicy::default(),
+            retention_horizon: None,
+            max_file_size: None,
+            channels: vec![Channel::general(creator_id, created_at)],
+            deleted_channels: HashSet::new(),
+            channel_state: HashMap::new(),
+            receipts: Receipts::default(),
+            search_index: None,
+            stored: false,
+            unsaved_messages: HashSet::new(),
+            trimmed_unsaved: Vec::new(),
+            unsaved_pings: Vec::new(),
+            removed_channels: Vec::new(),
         }
     }
 
@@ -77,6 +499,7 @@ impl Room {
         }
         
         println!("✅ Added user '{}' (ID: {}) to room '{}'", user.name, user.id, self.name);
+        self.roles.entry(user.id).or_default();
         self.users.insert(user.id, user);
         Ok(())
     }
@@ -109,6 +532,8 @@ impl Room {
 
     pub fn remove_user(&mut self, user_id: Uuid) -> Result<()> {
         if let Some(user) = self.users.remove(&user_id) {
+            self.roles.remove(&user_id);
+
             // Remove from peer addresses
             self.peer_addresses.retain(|&addr| addr != user.address);
             
@@ -121,16 +546,788 @@ impl Room {
     }
 
     pub fn add_message(&mut self, message: ChatMessage) {
-        self.messages.push(message);
-        
-        // Keep only last 1000 messages
-        if self.messages.len() > 1000 {
-            self.messages.drain(0..self.messages.len() - 1000);
+        if self.insert_message(message) {
+            self.trim_messages();
         }
     }
 
+    /// Inserts in (hlc, id) order so every peer ends up with the same
+    /// sequence regardless of arrival order. Returns false for duplicates.
+    fn insert_message(&mut self, mut message: ChatMessage) -> bool {
+        if self.messages.iter().any(|existing| existing.id == message.id)
+            || self.deleted_channels.contains(&message.channel_id)
+        {
+            return false;
+        }
+
+        if let Some(tombstone) = self.tombstones.get(&message.id) {
+            let authorized = tombstone.deleted_by == message.user_id
+                || self.has_permission(tombstone.deleted_by, Permission::ManageMessages);
+            if authorized {
+                message.scrub(tombstone.deleted_at);
+            }
+        }
+
+        // Thread counters come from the replies we actually hold
+        message.reply_count = 0;
+        message.last_reply_at = None;
+        for reply in self.messages.iter().filter(|m| m.parent_id == Some(message.id)) {
+            message.reply_count += 1;
+            message.last_reply_at = message.last_reply_at.max(Some(reply.timestamp));
+        }
+
+        if let Some(parent_id) = message.parent_id {
+            if let Some(root_id) = self.thread_root(parent_id) {
+                message.parent_id = Some(root_id);
+                if let Some(root) = self.messages.iter_mut().find(|m| m.id == root_id) {
+                    message.channel_id = root.channel_id;
+                    root.reply_count += 1;
+                    root.last_reply_at = root.last_reply_at.max(Some(message.timestamp));
+                    self.unsaved_messages.insert(root_id);
+                }
+            }
+        }
+
+        resolve_mentions(&self.users, &mut message);
+        if let Some(index) = self.search_index.as_mut() {
+            index.index_message(&message);
+        }
+        self.unsaved_messages.insert(message.id);
+
+        let key = message.order_key();
+        let position = self.messages.partition_point(|m| m.order_key() <= key);
+        self.messages.insert(position, message);
+        true
+    }
+
+    fn trim_messages(&mut self) {
+        // Keep only last 1000 messages, plus the roots of threads that
+        // still have replies among them
+        if self.messages.len() > MAX_MESSAGES {
+            let cutoff = self.messages.len() - MAX_MESSAGES;
+            let live_roots: HashSet<Uuid> = self.messages[cutoff..]
+                .iter()
+                .filter_map(|m| m.parent_id)
+                .collect();
+
+            let mut dropped = Vec::new();
+            for (index, message) in std::mem::take(&mut self.messages).into_iter().enumerate() {
+                if index >= cutoff || live_roots.contains(&message.id) {
+                    self.messages.push(message);
+                    continue;
+                }
+                dropped.push(message.id);
+                if !self.stored || self.unsaved_messages.contains(&message.id) {
+                    self.trimmed_unsaved.push(message);
+                }
+            }
+
+            if let Some(search_index) = self.search_index.as_mut() {
+                for message_id in dropped {
+                    search_index.remove_message(message_id);
+                }
+            }
+            self.prune_receipts();
+        }
+    }
+
+    /// Receipts are only kept for messages still in memory.
+    fn prune_receipts(&mut self) {
+        let live: HashSet<Uuid> = self.messages.iter().map(|m| m.id).collect();
+        self.receipts.retain_messages(|message_id| live.contains(message_id));
+    }
+
+    /// Folds history fetched from another peer into this room. Deletions
+    /// only arrive as tombstones and go through the usual permission
+    /// checks; edits are taken from whichever copy is newer, and events
+    /// are dropped. Returns the number of messages that were added or
+    /// changed.
+    pub fn merge_history(&mut self, messages: Vec<ChatMessage>, tombstones: Vec<MessageTombstone>) -> usize {
+        let mut changed = 0;
+
+        for tombstone in tombstones {
+            let message_id = tombstone.message_id;
+            let was_known = self.tombstones.contains_key(&message_id);
+            if self.delete_message(tombstone).is_ok() && !was_known
+                && self.messages.iter().any(|m| m.id == message_id)
+            {
+                changed += 1;
+            }
+        }
+
+        for incoming in messages {
+            // Events are only ever recorded locally, and a message stamped
+            // far in the future would stay at the end of the history
+            if incoming.is_event() || !incoming.hlc.is_plausible() {
+                continue;
+            }
+            if !incoming.pinned && self.is_past_horizon(&incoming)
+                && !self.messages.iter().any(|m| m.id == incoming.id)
+            {
+                continue;
+            }
+
+            match self.messages.iter_mut().find(|m| m.id == incoming.id) {
+                Some(local) => {
+                    if local.merge_from(incoming) {
+                        resolve_mentions(&self.users, local);
+                        if let Some(index) = self.search_index.as_mut() {
+                            index.index_message(local);
+                        }
+                        self.unsaved_messages.insert(local.id);
+                        changed += 1;
+                    }
+                }
+                None => {
+                    if self.insert_message(incoming) {
+                        changed += 1;
+                    }
+                }
+            }
+        }
+
+        // Rooms saved before ordering was deterministic may be out of order
+        self.messages.sort_by_key(|m| m.order_key());
+        self.trim_messages();
+        changed
+    }
+
+    /// Merges history a member sent us through sync. Members only vouch
+    /// for their own messages, edits and deletions; those who may manage
+    /// messages can pass on anyone's.
+    pub fn merge_synced(&mut self, sender_id: Uuid, messages: Vec<ChatMessage>, tombstones: Vec<MessageTombstone>) -> usize {
+        let tombstones = tombstones.into_iter()
+            .filter(|tombstone| self.can_modify_message(sender_id, tombstone.deleted_by))
+            .collect();
+        let messages = messages.into_iter()
+            .filter(|incoming| {
+                let author_id = self.messages.iter().find(|m| m.id == incoming.id)
+                    .map_or(incoming.user_id, |local| local.user_id);
+                self.can_modify_message(sender_id, author_id)
+            })
+            .map(|mut incoming| {
+                // Reactions likewise only count from whoever made them,
+                // pins only through the pin command and deletions only
+                // as tombstones
+                incoming.keep_reactions_by(sender_id);
+                incoming.pinned = false;
+                incoming.deleted_at = None;
+                incoming
+            })
+            .collect();
+        self.merge_history(messages, tombstones)
+    }
+
+    pub fn is_past_horizon(&self, message: &ChatMessage) -> bool {
+        let (hlc, _) = message.order_key();
+        let horizon = self.channel_state.get(&message.channel_id)
+            .and_then(|state| state.retention_horizon)
+            .or(self.retention_horizon);
+        horizon.is_some_and(|horizon| hlc.physical <= horizon)
+    }
+
+    /// The channel's own retention policy, or the room's if it has none.
+    pub fn retention_for(&self, channel_id: Uuid) -> RetentionPolicy {
+        self.channel_state.get(&channel_id)
+            .and_then(|state| state.retention)
+            .unwrap_or(self.retention)
+    }
+
+    /// Raises a channel's horizon after a compaction pass; the room's
+    /// horizon follows whichever channel's is furthest behind.
+    pub fn record_compaction(&mut self, channel_id: Uuid, compaction: &Compaction) {
+        let state = self.channel_state.entry(channel_id).or_default();
+        state.retention_horizon = state.retention_horizon.max(compaction.horizon);
+
+        self.retention_horizon = self.channels.iter()
+            .map(|channel| self.channel_state.get(&channel.id).and_then(|state| state.retention_horizon))
+            .min()
+            .flatten();
+    }
+
+    /// Drops loaded messages that fall outside each channel's retention
+    /// policy. Returns how many were removed.
+    pub fn apply_retention(&mut self, now: chrono::DateTime<chrono::Utc>) -> usize {
+        let mut expired = HashSet::new();
+        let channel_ids: Vec<Uuid> = self.channels.iter().map(|channel| channel.id).collect();
+        for channel_id in channel_ids {
+            let entries = self.messages.iter()
+                .rev()
+                .filter(|m| m.channel_id == channel_id && !m.pinned)
+                .map(|m| {
+                    let size = serde_json::to_string(m).map(|json| json.len() as u64).unwrap_or(0);
+                    (m.id, m.order_key().0.physical, size)
+                });
+            let compaction = self.retention_for(channel_id).select_expired(entries, now);
+            if !compaction.expired.is_empty() {
+                expired.extend(compaction.expired.iter().copied());
+                self.record_compaction(channel_id, &compaction);
+            }
+        }
+        if expired.is_empty() {
+            return 0;
+        }
+
+        self.messages.retain(|m| !expired.contains(&m.id));
+        for message_id in &expired {
+            self.unsaved_messages.remove(message_id);
+            if let Some(search_index) = self.search_index.as_mut() {
+                search_index.remove_message(*message_id);
+            }
+        }
+        self.prune_receipts();
+
+        expired.len()
+    }
+
+    pub fn set_pinned(&mut self, update: &PinUpdate) -> Result<bool> {
+        if update.room_id != self.id {
+            return Err(anyhow::anyhow!("Pin is for a different room"));
+        }
+        if !self.has_permission(update.pinned_by, Permission::ManageMessages) {
+            return Err(anyhow::anyhow!("Not allowed to pin messages"));
+        }
+
+        let message = self.messages.iter_mut().find(|m| m.id == update.message_id)
+            .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+        if message.is_deleted() {
+            return Err(anyhow::anyhow!("Message has been deleted"));
+        }
+        if message.pinned == update.pinned {
+            return Ok(false);
+        }
+
+        message.pinned = update.pinned;
+        self.unsaved_messages.insert(update.message_id);
+        Ok(true)
+    }
+
+    /// History is kept sorted by ordering key, which doubles as the index:
+    /// cursors resolve with a binary search over the channel's messages and
+    /// only the page is cloned.
+    pub fn page_messages(&self, channel_id: Uuid, cursor: Option<&MessageCursor>, direction: PageDirection, limit: usize) -> MessagePage {
+        let limit = limit.clamp(1, pagination::MAX_PAGE_SIZE);
+        let messages = self.channel_messages(channel_id);
+
+        let (start, end) = match (direction, cursor) {
+            (PageDirection::Before, None) => (messages.len().saturating_sub(limit), messages.len()),
+            (PageDirection::After, None) => (0, limit.min(messages.len())),
+            (PageDirection::Before, Some(cursor)) => {
+                let end = messages.partition_point(|m| m.order_key() < cursor.key());
+                (end.saturating_sub(limit), end)
+            }
+            (PageDirection::After, Some(cursor)) => {
+                let start = messages.partition_point(|m| m.order_key() <= cursor.key());
+                (start, (start + limit).min(messages.len()))
+            }
+        };
+
+        pagination::page_from(&messages, start, end)
+    }
+
+    /// Page centred on a message within its channel, for jumping to it from
+    /// a search result or reply. `None` when the message isn't in the local
+    /// history.
+    pub fn messages_around(&self, message_id: Uuid, limit: usize) -> Option<MessagePage> {
+        let limit = limit.clamp(1, pagination::MAX_PAGE_SIZE);
+        let channel_id = self.messages.iter().find(|m| m.id == message_id)?.channel_id;
+        let messages = self.channel_messages(channel_id);
+        let position = messages.iter().position(|m| m.id == message_id)?;
+
+        let start = position.saturating_sub(limit / 2);
+        let end = (start + limit).min(messages.len());
+        let start = end.saturating_sub(limit);
+
+        Some(pagination::page_from(&messages, start, end))
+    }
+
+    fn channel_messages(&self, channel_id: Uuid) -> Vec<&ChatMessage> {
+        self.messages.iter().filter(|m| m.channel_id == channel_id).collect()
+    }
+
+    /// Copy of the room carrying only the newest `limit` messages of each
+    /// channel, without cloning the rest of the history.
+    pub fn clone_with_latest(&mut self, limit: usize) -> Room {
+        let mut latest: Vec<ChatMessage> = self.channels.iter()
+            .flat_map(|channel| self.page_messages(channel.id, None, PageDirection::Before, limit).messages)
+            .collect();
+        latest.sort_by_key(|m| m.order_key());
+        let history = std::mem::take(&mut self.messages);
+        let mut copy = self.clone();
+        self.messages = history;
+        copy.messages = latest;
+        copy
+    }
+
+    fn search_index(&mut self) -> &SearchIndex {
+        if self.search_index.is_none() {
+            // A saved index that has drifted from the history, including
+            // through edits, gets rebuilt
+            let fingerprint = search::fingerprint(&self.messages);
+            let index = SearchIndex::load(self.id)
+                .ok()
+                .filter(|index| index.fingerprint() == fingerprint)
+                .unwrap_or_else(|| SearchIndex::build(&self.messages));
+            self.search_index = Some(index);
+        }
+
+        self.search_index.get_or_insert_with(SearchIndex::default)
+    }
+
+    pub fn search_messages(&mut self, query: &str, filter: &SearchFilter) -> Vec<SearchResult> {
+        let hits = self.search_index().search(query, filter);
+
+        hits.into_iter()
+            .filter_map(|(message_id, score)| {
+                let message = self.messages.iter().find(|m| m.id == message_id)?;
+                Some(SearchResult {
+                    room_id: self.id,
+                    room_name: self.name.clone(),
+                    message: message.clone(),
+                    score,
+                })
+            })
+            .collect()
+    }
+
+    fn thread_root(&self, message_id: Uuid) -> Option<Uuid> {
+        let message = self.messages.iter().find(|m| m.id == message_id)?;
+        Some(message.parent_id.unwrap_or(message.id))
+    }
+
+    pub fn get_thread(&self, message_id: Uuid) -> Option<MessageThread> {
+        let root_id = self.thread_root(message_id)?;
+        let root = self.messages.iter().find(|m| m.id == root_id)?.clone();
+        let replies = self.messages.iter()
+            .filter(|m| m.parent_id == Some(root_id))
+            .cloned()
+            .collect();
+
+        Some(MessageThread { root, replies })
+    }
+
+    pub fn record_event(&mut self, event: RoomEvent) {
+        self.add_message(ChatMessage::event(event));
+    }
+
+    fn can_modify_message(&self, user_id: Uuid, author_id: Uuid) -> bool {
+        if self.role_of(user_id).is_none() {
+            return false;
+        }
+        user_id == author_id || self.has_permission(user_id, Permission::ManageMessages)
+    }
+
+    pub fn edit_message(&mut self, edit: &MessageEdit) -> Result<&ChatMessage> {
+        if edit.room_id != self.id {
+            return Err(anyhow::anyhow!("Edit is for a different room"));
+        }
+        if self.tombstones.contains_key(&edit.message_id) {
+            return Err(anyhow::anyhow!("Message has been deleted"));
+        }
+        if edit.content.trim().is_empty() {
+            return Err(anyhow::anyhow!("Message content cannot be empty"));
+        }
+
+        let index = self.messages.iter().position(|m| m.id == edit.message_id)
+            .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+        if !self.can_modify_message(edit.edited_by, self.messages[index].user_id) {
+            return Err(anyhow::anyhow!("Not allowed to edit this message"));
+        }
+
+        self.unsaved_messages.insert(edit.message_id);
+        let message = &mut self.messages[index];
+        if message.edited_at.is_some_and(|edited_at| edited_at >= edit.edited_at) {
+            // An older edit arriving late only fills in the history
+            if !message.edit_history.iter().any(|r| r.replaced_at == edit.edited_at) {
+                message.edit_history.push(MessageRevision {
+                    content: edit.content.clone(),
+                    replaced_at: edit.edited_at,
+                });
+                message.edit_history.sort_by_key(|r| r.replaced_at);
+            }
+            return Ok(message);
+        }
+
+        let previous = std::mem::replace(&mut message.content, edit.content.clone());
+        message.edit_history.push(MessageRevision {
+            content: previous,
+            replaced_at: edit.edited_at,
+        });
+        message.edited_at = Some(edit.edited_at);
+        resolve_mentions(&self.users, message);
+
+        if let Some(index) = self.search_index.as_mut() {
+            index.index_message(message);
+        }
+        Ok(message)
+    }
+
+    /// Returns whether the update changed anything.
+    pub fn apply_reaction(&mut self, update: &ReactionUpdate) -> Result<bool> {
+        if update.room_id != self.id {
+            return Err(anyhow::anyhow!("Reaction is for a different room"));
+        }
+        let emoji = update.emoji.trim();
+        if emoji.is_empty() || emoji.len() > 32 || emoji.chars().any(char::is_whitespace) {
+            return Err(anyhow::anyhow!("Invalid reaction"));
+        }
+        if !self.has_permission(update.user_id, Permission::SendMessages) {
+            return Err(anyhow::anyhow!("Not allowed to react in this room"));
+        }
+        if !update.hlc.is_plausible() {
+            return Err(anyhow::anyhow!("Reaction is stamped too far in the future"));
+        }
+
+        let message = self.messages.iter_mut().find(|m| m.id == update.message_id)
+            .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+        if message.is_deleted() {
+            return Err(anyhow::anyhow!("Message has been deleted"));
+        }
+
+        // Updates from peers that don't stamp them are ordered by arrival
+        let hlc = if update.hlc.is_unset() { clock::LOCAL.now() } else { update.hlc };
+        let changed = message.mark_reaction(emoji, update.user_id, ReactionMark { added: update.added, hlc });
+        if changed {
+            self.unsaved_messages.insert(message.id);
+        }
+        Ok(changed)
+    }
+
+    pub fn delete_message(&mut self, tombstone: MessageTombstone) -> Result<()> {
+        if tombstone.room_id != self.id {
+            return Err(anyhow::anyhow!("Delete is for a different room"));
+        }
+        if self.tombstones.contains_key(&tombstone.message_id) {
+            return Ok(());
+        }
+
+        match self.messages.iter().position(|m| m.id == tombstone.message_id) {
+            Some(index) => {
+                if !self.can_modify_message(tombstone.deleted_by, self.messages[index].user_id) {
+                    return Err(anyhow::anyhow!("Not allowed to delete this message"));
+                }
+                self.messages[index].scrub(tombstone.deleted_at);
+                self.unsaved_messages.insert(tombstone.message_id);
+                if let Some(search_index) = self.search_index.as_mut() {
+                    search_index.remove_message(tombstone.message_id);
+                }
+            }
+            // We haven't seen the message yet; add_message checks the
+            // deleter against the author once it shows up
+            None if self.role_of(tombstone.deleted_by).is_none() => {
+                return Err(anyhow::anyhow!("Not allowed to delete this message"));
+            }
+            None => {}
+        }
+
+        self.tombstones.insert(tombstone.message_id, tombstone);
+        Ok(())
+    }
+
+    pub fn role_of(&self, user_id: Uuid) -> Option<Role> {
+        if !self.users.contains_key(&user_id) {
+            return None;
+        }
+
+        Some(self.roles.get(&user_id).copied().unwrap_or(if user_id == self.creator_id {
+            Role::Owner
+        } else {
+            Role::Member
+        }))
+    }
+
+    pub fn has_permission(&self, user_id: Uuid, permission: Permission) -> bool {
+        self.role_of(user_id)
+            .map(|role| role.has(permission))
+            .unwrap_or(false)
+    }
+
+    pub fn set_role(&mut self, changed_by: Uuid, user_id: Uuid, role: Role) -> Result<()> {
+        let actor_role = self.role_of(changed_by)
+            .ok_or_else(|| anyhow::anyhow!("Only room members can change roles"))?;
+        if !actor_role.has(Permission::ManageRoles) {
+            return Err(anyhow::anyhow!("Not allowed to change roles"));
+        }
+
+        let target_role = self.role_of(user_id)
+            .ok_or_else(|| anyhow::anyhow!("User is not in this room"))?;
+
+        if user_id == changed_by {
+            return Err(anyhow::anyhow!("Cannot change your own role"));
+        }
+        if role == Role::Owner || target_role == Role::Owner {
+            return Err(anyhow::anyhow!("Room ownership cannot be reassigned"));
+        }
+        // Everyone below the owner can only manage roles strictly beneath their own
+        if actor_role != Role::Owner && (target_role >= actor_role || role >= actor_role) {
+            return Err(anyhow::anyhow!("Cannot change a role at or above your own"));
+        }
+
+        self.roles.insert(user_id, role);
+        println!("🎖️ Role of user {} in room '{}' changed to {:?}", user_id, self.name, role);
+        self.record_event(RoomEvent::RoleChanged { user_id, role, changed_by });
+        Ok(())
+    }
+
+    pub fn find_ban(&self, user: &User) -> Option<&Ban> {
+        self.bans.iter().find(|ban| {
+            ban.is_active() && ban.matches(user.id, user.identity_key.as_deref(), user.address.ip())
+        })
+    }
+
+    pub fn file_size_limit(&self) -> u64 {
+        self.max_file_size.unwrap_or(transfer::DEFAULT_MAX_FILE_SIZE)
+    }
+
+    /// The message a file was offered with, if it's still around.
+    pub fn file_offer(&self, file_id: Uuid) -> Option<&ChatMessage> {
+        self.messages.iter()
+            .find(|m| m.attachment.as_ref().is_some_and(|file| file.id == file_id))
+    }
+
+    pub fn is_muted(&self, user_id: Uuid) -> bool {
+        match self.mutes.get(&user_id) {
+            Some(Some(until)) => *until > chrono::Utc::now(),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    pub fn apply_moderation(&mut self, command: &ModerationCommand) -> Result<()> {
+        if command.room_id != self.id {
+            return Err(anyhow::anyhow!("Moderation command is for a different room"));
+        }
+
+        // The command must be signed by the key the issuer joined with
+        let issuer = self.users.get(&command.issued_by)
+            .ok_or_else(|| anyhow::anyhow!("Issuer is not in this room"))?;
+        if issuer.identity_key.as_deref() != Some(command.issuer_identity.as_str()) || !command.verify() {
+            return Err(anyhow::anyhow!("Moderation command signature is invalid"));
+        }
+
+        let required = match command.action {
+            ModerationAction::Kick => Permission::KickUsers,
+            ModerationAction::Ban { .. } | ModerationAction::Unban => Permission::BanUsers,
+            ModerationAction::Mute { .. } | ModerationAction::Unmute => Permission::MuteUsers,
+        };
+        if !self.has_permission(command.issued_by, required) {
+            return Err(anyhow::anyhow!("Not allowed to {:?} users", required));
+        }
+
+        if command.target_user_id == command.issued_by {
+            return Err(anyhow::anyhow!("Cannot moderate yourself"));
+        }
+        if let (Some(issuer_role), Some(target_role)) = (self.role_of(command.issued_by), self.role_of(command.target_user_id)) {
+            if target_role >= issuer_role {
+                return Err(anyhow::anyhow!("Cannot moderate a user with an equal or higher role"));
+            }
+        }
+
+        let target_name = self.display_name(command.target_user_id);
+        let target = self.users.get(&command.target_user_id).cloned();
+
+        match &command.action {
+            ModerationAction::Kick => {
+                if target.is_none() {
+                    return Err(anyhow::anyhow!("User is not in this room"));
+                }
+                self.remove_user(command.target_user_id)?;
+            }
+            ModerationAction::Ban { until } => {
+                self.bans.retain(|ban| ban.user_id != command.target_user_id);
+                self.bans.push(Ban {
+                    user_id: command.target_user_id,
+                    identity_key: target.as_ref().and_then(|user| user.identity_key.clone()),
+                    address: target.as_ref().map(|user| user.address.ip()),
+                    until: *until,
+                    reason: command.reason.clone(),
+                    banned_by: command.issued_by,
+                    banned_at: command.issued_at,
+                });
+                self.pending_joins.remove(&command.target_user_id);
+                self.remove_user(command.target_user_id)?;
+            }
+            ModerationAction::Unban => {
+                self.bans.retain(|ban| ban.user_id != command.target_user_id);
+            }
+            ModerationAction::Mute { until } => {
+                if target.is_none() {
+                    return Err(anyhow::anyhow!("User is not in this room"));
+                }
+                self.mutes.insert(command.target_user_id, *until);
+            }
+            ModerationAction::Unmute => {
+                self.mutes.remove(&command.target_user_id);
+            }
+        }
+
+        println!("🛡️ {:?} applied to '{}' in room '{}'", command.action, target_name, self.name);
+
+        let issuer_name = self.display_name(command.issued_by);
+        self.moderation_log.push(ModerationLogEntry {
+            command_id: command.id,
+            action: command.action.clone(),
+            target_user_id: command.target_user_id,
+            target_name: target_name.clone(),
+            issued_by: command.issued_by,
+            issuer_name,
+            reason: command.reason.clone(),
+            timestamp: command.issued_at,
+        });
+        self.record_event(RoomEvent::Moderated {
+            target_user_id: command.target_user_id,
+            target_name,
+            action: command.action.clone(),
+            issued_by: command.issued_by,
+            reason: command.reason.clone(),
+        });
+
+        Ok(())
+    }
+
+    pub fn rename(&mut self, renamed_by: Uuid, new_name: String) -> Result<()> {
+        if !self.has_permission(renamed_by, Permission::RenameRoom) {
+            return Err(anyhow::anyhow!("Not allowed to rename this room"));
+        }
+
+        let new_name = new_name.trim().to_string();
+        if new_name.is_empty() {
+            return Err(anyhow::anyhow!("Room name cannot be empty"));
+        }
+
+        self.name = new_name.clone();
+        self.record_event(RoomEvent::RoomRenamed { name: new_name, renamed_by });
+        Ok(())
+    }
+
+    /// Applies a settings change, ours or a peer's. Password changes are
+    /// made locally by whoever holds the verifier, so only peers' arrive here.
+    pub fn apply_setting(&mut self, changed_by: Uuid, setting: &RoomSetting) -> Result<()> {
+        if !self.has_permission(changed_by, Permission::ManageSettings) {
+            return Err(anyhow::anyhow!("Not allowed to change room settings"));
+        }
+
+        match setting {
+            RoomSetting::PasswordRequired(required) => {
+                self.password_required = *required;
+                // Whatever verifier we held is for the password before this change
+                self.password = None;
+            }
+            RoomSetting::Retention { channel_id, policy } => {
+                if !policy.is_valid() {
+                    return Err(anyhow::anyhow!("Retention limit must be greater than zero"));
+                }
+                match channel_id {
+                    Some(channel_id) => {
+                        if self.channel(*channel_id).is_none() {
+                            return Err(anyhow::anyhow!("Channel not found"));
+                        }
+                        self.channel_state.entry(*channel_id).or_default().retention = Some(*policy);
+                    }
+                    None => self.retention = *policy,
+                }
+            }
+            RoomSetting::MaxFileSize(bytes) => {
+                if *bytes == Some(0) {
+                    return Err(anyhow::anyhow!("Maximum file size must be greater than zero"));
+                }
+                self.max_file_size = *bytes;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn requires_password(&self) -> bool {
+        self.password_required || self.password.is_some()
+    }
+
+    pub fn handle_join_request(&mut self, request: JoinRequest) -> Result<JoinDecision> {
+        if request.room_id != self.id {
+            return Err(anyhow::anyhow!("Join request is for a different room"));
+        }
+        if !request.verify() {
+            return Err(anyhow::anyhow!("Join request signature is invalid"));
+        }
+        if self.find_ban(&request.user).is_some() {
+            return Err(anyhow::anyhow!("You are banned from this room"));
+        }
+
+        // Members reconnecting after a drop or a restart don't need to knock
+        // again, as long as they come back with the key they joined with
+        if let Some(member) = self.users.get_mut(&request.user.id) {
+            if member.identity_key != request.user.identity_key {
+                return Err(anyhow::anyhow!("Identity key does not match this member"));
+            }
+            member.address = request.user.address;
+            if request.user.dm_key.is_some() {
+                member.dm_key = request.user.dm_key.clone();
+                member.dm_key_signature = request.user.dm_key_signature.clone();
+            }
+            member.is_online = true;
+            member.update_last_seen();
+            if !self.peer_addresses.contains(&request.user.address) {
+                self.peer_addresses.push(request.user.address);
+            }
+            return Ok(JoinDecision::Approved);
+        }
+
+        if self.require_approval {
+            println!("🚪 '{}' is waiting for approval to join room '{}'", request.user.name, self.name);
+            self.pending_joins.insert(request.user.id, request);
+            return Ok(JoinDecision::Pending);
+        }
+
+        let user_id = request.user.id;
+        self.add_user(request.user)?;
+        let name = self.display_name(user_id);
+        self.record_event(RoomEvent::UserJoined { user_id, name, approved_by: None });
+        Ok(JoinDecision::Approved)
+    }
+
+    pub fn approve_join(&mut self, user_id: Uuid, decided_by: Uuid) -> Result<User> {
+        let request = self.pending_joins.remove(&user_id)
+            .ok_or_else(|| anyhow::anyhow!("No pending join request for this user"))?;
+
+        self.add_user(request.user)?;
+
+        let user = self.users.get(&user_id).cloned()
+            .ok_or_else(|| anyhow::anyhow!("Approved user missing from room"))?;
+        self.record_event(RoomEvent::UserJoined {
+            user_id,
+            name: user.name.clone(),
+            approved_by: Some(decided_by),
+        });
+
+        Ok(user)
+    }
+
+    pub fn deny_join(&mut self, user_id: Uuid, decided_by: Uuid, reason: Option<String>) -> Result<JoinRequest> {
+        let request = self.pending_joins.remove(&user_id)
+            .ok_or_else(|| anyhow::anyhow!("No pending join request for this user"))?;
+
+        self.record_event(RoomEvent::JoinDenied {
+            user_id,
+            name: request.user.name.clone(),
+            denied_by: decided_by,
+            reason,
+        });
+
+        Ok(request)
+    }
+
+    fn display_name(&self, user_id: Uuid) -> String {
+        self.users.get(&user_id)
+            .map(|user| user.name.clone())
+            .unwrap_or_else(|| "Unknown".to_string())
+    }
+
     pub fn update_ping(&mut self, user_id: Uuid, ping_ms: u64) {
         self.ping_measurements.insert(user_id, ping_ms);
+        self.unsaved_pings.push(PingSample {
+            user_id,
+            ping_ms,
+            measured_at: chrono::Utc::now(),
+        });
         
         // Update user's last_seen timestamp and online status
         if let Some(user) = self.users.get_mut(&user_id) {
@@ -150,6 +1347,7 @@ impl Room {
     }
 
     pub fn elect_new_server(&mut self) {
+        let previous = self.server_user_id;
         // Find online users with valid ping measurements
         let best_candidate = self.ping_measurements
             .iter()
@@ -160,108 +1358,443 @@ impl Room {
                 } else {
                     false
                 }
-            })
-            .min_by_key(|(_, ping)| *ping)
-            .map(|(user_id, _)| *user_id);
+            })
+            .min_by_key(|(_, ping)| *ping)
+            .map(|(user_id, _)| *user_id);
+
+        // If no candidate with ping data, fall back to any online user
+        let fallback_candidate = if best_candidate.is_none() {
+            self.users
+                .iter()
+                .find(|(_, user)| user.is_online)
+                .map(|(user_id, _)| *user_id)
+        } else {
+            None
+        };
+
+        self.server_user_id = best_candidate.or(fallback_candidate);
+        
+        if let Some(new_server_id) = self.server_user_id {
+            if let Some(new_server) = self.users.get(&new_server_id) {
+                println!("🏆 Elected new server: '{}' (ID: {})", new_server.name, new_server_id);
+            }
+        } else {
+            println!("⚠️ No online users available to elect as server");
+        }
+        if self.server_user_id != previous {
+            self.record_event(RoomEvent::ServerElected { user_id: self.server_user_id });
+        }
+    }
+
+    pub fn get_ordered_peer_list(&self) -> Vec<SocketAddr> {
+        let mut peers: Vec<(SocketAddr, u64)> = self.users
+            .values()
+            .filter_map(|user| {
+                let ping = self.ping_measurements.get(&user.id).copied().unwrap_or(u64::MAX);
+                Some((user.address, ping))
+            })
+            .collect();
+
+        // Sort by ping (lowest first)
+        peers.sort_by_key(|(_, ping)| *ping);
+        peers.into_iter().map(|(addr, _)| addr).collect()
+    }
+
+    pub fn switch_protocol(&mut self, changed_by: Uuid, new_protocol: Protocol) {
+        if self.protocol != new_protocol {
+            self.protocol = new_protocol.clone();
+            self.record_event(RoomEvent::ProtocolChanged { protocol: new_protocol, changed_by });
+        }
+    }
+
+    /// A member leaving on their own; they stay listed so they can rejoin.
+    pub fn user_left(&mut self, user_id: Uuid) -> Result<()> {
+        let name = self.users.get(&user_id)
+            .map(|user| user.name.clone())
+            .ok_or_else(|| anyhow::anyhow!("User is not in this room"))?;
+        if let Some(user) = self.users.get_mut(&user_id) {
+            user.leave_call();
+        }
+        self.end_call_if_empty();
+        self.mark_user_offline(user_id)?;
+        self.record_event(RoomEvent::UserLeft { user_id, name });
+        Ok(())
+    }
+
+    pub fn join_call(&mut self, user_id: Uuid) -> Result<()> {
+        let user = self.users.get_mut(&user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found in party"))?;
+        if user.is_in_call {
+            return Err(anyhow::anyhow!("User is already in call"));
+        }
+        user.join_call();
+        let name = user.name.clone();
+
+        // Start call server if this is the first user joining
+        if !self.is_call_active {
+            self.is_call_active = true;
+            self.call_server_id = Some(user_id);
+            self.record_event(RoomEvent::CallStarted { started_by: user_id });
+        }
+        self.record_event(RoomEvent::CallJoined { user_id, name });
+        Ok(())
+    }
+
+    pub fn leave_call(&mut self, user_id: Uuid) -> Result<()> {
+        let user = self.users.get_mut(&user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found in party"))?;
+        if !user.is_in_call {
+            return Err(anyhow::anyhow!("User is not in call"));
+        }
+        user.leave_call();
+        let name = user.name.clone();
+
+        self.record_event(RoomEvent::CallLeft { user_id, name });
+        self.end_call_if_empty();
+        Ok(())
+    }
+
+    fn end_call_if_empty(&mut self) {
+        if self.is_call_active && !self.users.values().any(|u| u.is_in_call) {
+            self.is_call_active = false;
+            self.call_server_id = None;
+            self.record_event(RoomEvent::CallEnded);
+        }
+    }
+
+    pub fn channel(&self, channel_id: Uuid) -> Option<&Channel> {
+        self.channels.iter().find(|channel| channel.id == channel_id)
+    }
+
+    pub fn channel_named(&self, name: &str) -> Option<&Channel> {
+        self.channels.iter().find(|channel| channel.name == name)
+    }
+
+    /// Applies a channel create, rename or delete. Returns false when it
+    /// changed nothing, e.g. a create we've already seen.
+    pub fn apply_channel_update(&mut self, update: &ChannelUpdate) -> Result<bool> {
+        if update.room_id != self.id {
+            return Err(anyhow::anyhow!("Channel update is for a different room"));
+        }
+        if !self.has_permission(update.updated_by, Permission::ManageChannels) {
+            return Err(anyhow::anyhow!("Not allowed to manage channels"));
+        }
+        if self.deleted_channels.contains(&update.channel_id) {
+            return Err(anyhow::anyhow!("Channel has been deleted"));
+        }
+
+        match &update.action {
+            ChannelAction::Create { name } => {
+                if self.channel(update.channel_id).is_some() {
+                    return Ok(false);
+                }
+                self.channels.push(Channel {
+                    id: update.channel_id,
+                    name: channel::normalize_name(name)?,
+                    created_by: update.updated_by,
+                    created_at: update.updated_at,
+                });
+            }
+            ChannelAction::Rename { name } => {
+                let name = channel::normalize_name(name)?;
+                let channel = self.channels.iter_mut().find(|channel| channel.id == update.channel_id)
+                    .ok_or_else(|| anyhow::anyhow!("Channel not found"))?;
+                if channel.name == name {
+                    return Ok(false);
+                }
+                channel.name = name;
+            }
+            ChannelAction::Delete => {
+                if update.channel_id == GENERAL_CHANNEL {
+                    return Err(anyhow::anyhow!("The general channel cannot be deleted"));
+                }
+
+                // Recorded even for a channel we never saw, so its create
+                // and messages are refused if they turn up later
+                self.channels.retain(|channel| channel.id != update.channel_id);
+                self.channel_state.remove(&update.channel_id);
+                self.receipts.remove_channel(update.channel_id);
+                self.deleted_channels.insert(update.channel_id);
+                self.removed_channels.push(update.channel_id);
+
+                let mut removed = Vec::new();
+                self.messages.retain(|m| {
+                    let keep = m.channel_id != update.channel_id;
+                    if !keep {
+                        removed.push(m.id);
+                    }
+                    keep
+                });
+                for message_id in removed {
+                    self.unsaved_messages.remove(&message_id);
+                    if let Some(search_index) = self.search_index.as_mut() {
+                        search_index.remove_message(message_id);
+                    }
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Messages from others since we last read the channel, and how many
+    /// of them mention us.
+    pub fn unread_in(&self, channel_id: Uuid, reader_id: Uuid) -> UnreadCount {
+        let last_read = self.channel_state.get(&channel_id).and_then(|state| state.last_read);
+
+        let mut count = UnreadCount::default();
+        for message in self.messages.iter().rev() {
+            if Some(message.order_key()) <= last_read {
+                break;
+            }
+            if message.channel_id != channel_id || message.user_id == reader_id
+                || message.is_deleted() || message.is_event()
+            {
+                continue;
+            }
+            count.unread += 1;
+            if message.mentions_user(reader_id) {
+                count.mentions += 1;
+            }
+        }
+        count
+    }
+
+    pub fn unread_summary(&self, reader_id: Uuid) -> RoomUnread {
+        let mut total = UnreadCount::default();
+        let channels = self.channels.iter()
+            .map(|channel| {
+                let count = self.unread_in(channel.id, reader_id);
+                total += count;
+                ChannelUnread {
+                    channel_id: channel.id,
+                    count,
+                    last_read: self.channel_state.get(&channel.id)
+                        .and_then(|state| state.last_read)
+                        .map(|(_, message_id)| message_id),
+                }
+            })
+            .collect();
+
+        RoomUnread { room_id: self.id, room_name: self.name.clone(), total, channels }
+    }
+
+    pub fn mark_all_read(&mut self) {
+        let channel_ids: Vec<Uuid> = self.channels.iter().map(|channel| channel.id).collect();
+        for channel_id in channel_ids {
+            self.mark_channel_read(channel_id);
+        }
+    }
+
+    pub fn mark_channel_read(&mut self, channel_id: Uuid) {
+        let latest = self.messages.iter().rev()
+            .find(|m| m.channel_id == channel_id)
+            .map(|m| m.order_key());
+        let state = self.channel_state.entry(channel_id).or_default();
+        state.last_read = state.last_read.max(latest);
+    }
+
+    /// Applies a receipt from the member it is about. Receipts for messages
+    /// we don't have are dropped, and read positions come from our own copy
+    /// of the message.
+    pub fn apply_receipt(&mut self, update: &ReceiptUpdate) -> Result<bool> {
+        if update.room_id() != self.id {
+            return Err(anyhow::anyhow!("Receipt is for a different room"));
+        }
+        if self.role_of(update.user_id()).is_none() {
+            return Err(anyhow::anyhow!("Only room members can send receipts"));
+        }
+
+        match update {
+            ReceiptUpdate::Delivered { user_id, message_ids, .. } => {
+                let mut changed = false;
+                for message_id in message_ids {
+                    if self.messages.iter().any(|m| m.id == *message_id && !m.is_event()) {
+                        changed |= self.receipts.record_delivery(*user_id, *message_id);
+                    }
+                }
+                Ok(changed)
+            }
+            ReceiptUpdate::Read { user_id, marker, .. } => {
+                let message = self.messages.iter()
+                    .find(|m| m.id == marker.message_id && !m.is_event())
+                    .ok_or_else(|| anyhow::anyhow!("Read marker points at an unknown message"))?;
+                let (hlc, message_id) = message.order_key();
+                let marker = ReadMarker { channel_id: message.channel_id, message_id, hlc };
+                Ok(self.receipts.record_read(*user_id, marker))
+            }
+        }
+    }
 
-        // If no candidate with ping data, fall back to any online user
-        let fallback_candidate = if best_candidate.is_none() {
-            self.users
-                .iter()
-                .find(|(_, user)| user.is_online)
-                .map(|(user_id, _)| *user_id)
-        } else {
-            None
-        };
+    /// Folds in receipts passed along with history synced from `sender_id`.
+    /// Only the sender's own receipts are taken, as with live ones, since
+    /// nobody can vouch for anyone else's.
+    pub fn merge_receipts(&mut self, sender_id: Uuid, mut receipts: Receipts) -> bool {
+        receipts.retain_user(sender_id);
+        self.fold_receipts(receipts)
+    }
 
-        self.server_user_id = best_candidate.or(fallback_candidate);
-        
-        if let Some(new_server_id) = self.server_user_id {
-            if let Some(new_server) = self.users.get(&new_server_id) {
-                println!("🏆 Elected new server: '{}' (ID: {})", new_server.name, new_server_id);
+    fn fold_receipts(&mut self, receipts: Receipts) -> bool {
+        let mut changed = false;
+        for (message_id, users) in receipts.delivered {
+            for user_id in users {
+                let update = ReceiptUpdate::Delivered { room_id: self.id, user_id, message_ids: vec![message_id] };
+                changed |= self.apply_receipt(&update).unwrap_or(false);
             }
-        } else {
-            println!("⚠️ No online users available to elect as server");
         }
+        for (user_id, markers) in receipts.read {
+            for marker in markers.into_values() {
+                let update = ReceiptUpdate::Read { room_id: self.id, user_id, marker };
+                changed |= self.apply_receipt(&update).unwrap_or(false);
+            }
+        }
+        changed
     }
 
-    pub fn get_ordered_peer_list(&self) -> Vec<SocketAddr> {
-        let mut peers: Vec<(SocketAddr, u64)> = self.users
-            .values()
-            .filter_map(|user| {
-                let ping = self.ping_measurements.get(&user.id).copied().unwrap_or(u64::MAX);
-                Some((user.address, ping))
+    /// Moves our own read marker to the latest message in the channel,
+    /// returning the update to send if it moved.
+    pub fn record_own_read(&mut self, user_id: Uuid, channel_id: Uuid) -> Option<ReceiptUpdate> {
+        let latest = self.messages.iter().rev().find(|m| m.channel_id == channel_id && !m.is_event())?;
+        let (hlc, message_id) = latest.order_key();
+        let marker = ReadMarker { channel_id, message_id, hlc };
+        let update = ReceiptUpdate::Read { room_id: self.id, user_id, marker };
+        match self.apply_receipt(&update) {
+            Ok(true) => Some(update),
+            _ => None,
+        }
+    }
+
+    pub fn message_receipts(&self, message_id: Uuid) -> Option<MessageReceipts> {
+        let message = self.messages.iter().find(|m| m.id == message_id && !m.is_event())?;
+        let key = message.order_key();
+
+        let mut read_by: Vec<Uuid> = self.receipts.read.iter()
+            .filter(|(user_id, markers)| {
+                **user_id != message.user_id
+                    && markers.get(&message.channel_id).is_some_and(|marker| marker.order_key() >= key)
             })
+            .map(|(user_id, _)| *user_id)
             .collect();
+        read_by.sort();
 
-        // Sort by ping (lowest first)
-        peers.sort_by_key(|(_, ping)| *ping);
-        peers.into_iter().map(|(addr, _)| addr).collect()
-    }
+        // Having read it means it arrived
+        let mut delivered_to = self.receipts.delivered.get(&message_id).cloned().unwrap_or_default();
+        delivered_to.extend(read_by.iter().copied());
+        delivered_to.remove(&message.user_id);
 
-    pub fn switch_protocol(&mut self, new_protocol: Protocol) {
-        self.protocol = new_protocol;
+        Some(MessageReceipts {
+            message_id,
+            delivered_to: delivered_to.into_iter().collect(),
+            read_by,
+        })
     }
 
-    pub fn save_to_file(&self) -> Result<()> {
-        let app_data_dir = dirs::data_dir()
-            .ok_or_else(|| anyhow::anyhow!("Could not find app data directory"))?
-            .join("shortgap")
-            .join("rooms");
+    pub fn channel_summaries(&self, reader_id: Uuid) -> Vec<ChannelSummary> {
+        self.channels.iter()
+            .map(|channel| ChannelSummary {
+                channel: channel.clone(),
+                unread: self.unread_in(channel.id, reader_id),
+                retention: self.channel_state.get(&channel.id).and_then(|state| state.retention),
+            })
+            .collect()
+    }
 
-        std::fs::create_dir_all(&app_data_dir)?;
-        
-        let file_path = app_data_dir.join(format!("{}.json", self.id));
-        let json_data = serde_json::to_string_pretty(self)?;
-        std::fs::write(file_path, json_data)?;
-        
-        Ok(())
+    /// Channels deleted since the last save, whose stored history has to go.
+    pub fn take_removed_channels(&mut self) -> Vec<Uuid> {
+        std::mem::take(&mut self.removed_channels)
     }
 
-    pub fn load_from_file(room_id: Uuid) -> Result<Self> {
-        let app_data_dir = dirs::data_dir()
-            .ok_or_else(|| anyhow::anyhow!("Could not find app data directory"))?
-            .join("shortgap")
-            .join("rooms");
+    /// Takes on the server's view of the room after being let in, folding in
+    /// whatever history we already had. Read state stays ours, and a party
+    /// rejoined from the store also keeps how far it has compacted.
+    pub fn adopt_snapshot(&mut self, mut snapshot: Room) {
+        if self.stored {
+            snapshot.retention_horizon = self.retention_horizon;
+            // Snapshots leave the verifier out, so keep ours if still needed
+            snapshot.password = self.password.take().filter(|_| snapshot.password_required);
+        }
+        let shared = std::mem::replace(&mut snapshot.channel_state, std::mem::take(&mut self.channel_state));
+        for state in snapshot.channel_state.values_mut() {
+            state.retention = None;
+        }
+        for (channel_id, state) in shared {
+            snapshot.channel_state.entry(channel_id).or_default().retention = state.retention;
+        }
+        snapshot.channel_state.retain(|channel_id, _| !snapshot.deleted_channels.contains(channel_id));
 
-        let file_path = app_data_dir.join(format!("{}.json", room_id));
-        
-        if !file_path.exists() {
-            return Err(anyhow::anyhow!("Room file does not exist"));
+        snapshot.messages.retain(|m| !m.is_event() && m.hlc.is_plausible());
+        let (events, messages): (Vec<_>, Vec<_>) = std::mem::take(&mut self.messages)
+            .into_iter()
+            .partition(|m| m.is_event());
+        let tombstones = std::mem::take(&mut self.tombstones).into_values().collect();
+        snapshot.merge_history(messages, tombstones);
+        snapshot.fold_receipts(std::mem::take(&mut self.receipts));
+        for event in events {
+            snapshot.insert_message(event);
         }
+        snapshot.trim_messages();
+        *self = snapshot;
+    }
 
-        let json_data = std::fs::read_to_string(file_path)?;
-        let room: Room = serde_json::from_str(&json_data)?;
-        
-        Ok(room)
+    /// Copy of the room handed to a newly admitted peer.
+    pub fn snapshot_for_peer(&self) -> Room {
+        let mut snapshot = self.clone();
+        snapshot.messages.retain(|m| !m.is_event());
+        snapshot.pending_joins.clear();
+        // Of each channel's state only its retention policy is shared
+        snapshot.channel_state = self.channel_state.iter()
+            .filter(|(_, state)| state.retention.is_some())
+            .map(|(channel_id, state)| (*channel_id, ChannelState { retention: state.retention, ..Default::default() }))
+            .collect();
+        snapshot.awaiting_approval = false;
+        snapshot.password_required = self.requires_password();
+        // Any member can be elected host, so bans and the moderation log go
+        // along; the password verifier stays with whoever set it
+        snapshot.password = None;
+        snapshot
     }
 
-    pub fn list_saved_rooms() -> Result<Vec<Uuid>> {
-        let app_data_dir = dirs::data_dir()
-            .ok_or_else(|| anyhow::anyhow!("Could not find app data directory"))?
-            .join("shortgap")
-            .join("rooms");
+    /// Room settings and moderation state, without the users and message
+    /// history that the store keeps in their own tables.
+    pub fn metadata_json(&mut self) -> Result<String> {
+        let users = std::mem::take(&mut self.users);
+        let messages = std::mem::take(&mut self.messages);
+        let receipts = std::mem::take(&mut self.receipts);
+        let json = serde_json::to_string(self);
+        self.users = users;
+        self.messages = messages;
+        self.receipts = receipts;
+        Ok(json?)
+    }
 
-        if !app_data_dir.exists() {
-            return Ok(Vec::new());
-        }
+    /// Messages the store hasn't seen in their current form.
+    pub fn unsaved_messages(&self) -> Vec<&ChatMessage> {
+        self.trimmed_unsaved.iter()
+            .chain(self.messages.iter().filter(|m| !self.stored || self.unsaved_messages.contains(&m.id)))
+            .collect()
+    }
 
-        let mut room_ids = Vec::new();
-        
-        for entry in std::fs::read_dir(app_data_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            
-            if let Some(file_name) = path.file_stem() {
-                if let Some(file_name_str) = file_name.to_str() {
-                    if let Ok(room_id) = Uuid::parse_str(file_name_str) {
-                        room_ids.push(room_id);
-                    }
-                }
-            }
+    pub fn unsaved_receipts(&self) -> UnsavedReceipts {
+        self.receipts.unsaved(!self.stored)
+    }
+
+    pub fn take_ping_samples(&mut self) -> Vec<PingSample> {
+        std::mem::take(&mut self.unsaved_pings)
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.stored = true;
+        self.unsaved_messages.clear();
+        self.trimmed_unsaved.clear();
+        self.receipts.mark_saved();
+    }
+
+    pub fn save_search_index(&mut self) -> Result<()> {
+        let room_id = self.id;
+        match self.search_index.as_mut() {
+            Some(index) => index.save(room_id),
+            None => Ok(()),
         }
-        
-        Ok(room_ids)
     }
 
     pub fn get_server_user(&self) -> Option<&User> {
@@ -474,4 +2007,708 @@ mod tests {
         // Server should have been re-elected to user2
         assert_eq!(room.server_user_id, Some(user2_id));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_join_approval_flow() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+        let addr3 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8082);
+
+        let host = User::new("Host".to_string(), addr1);
+        let host_id = host.id;
+
+        let mut room = Room::new("Test Room".to_string(), host, Protocol::TCP);
+        room.require_approval = true;
+
+        let make_request = |name: &str, addr: SocketAddr| {
+            let identity = crate::identity::LocalIdentity::generate().unwrap();
+            let mut user = User::new(name.to_string(), addr);
+            user.identity_key = Some(identity.public_key());
+            JoinRequest::new(room.id, user, &identity)
+        };
+        let guest_request = make_request("Guest", addr2);
+        let guest_id = guest_request.user.id;
+        let intruder_request = make_request("Intruder", addr3);
+        let intruder_id = intruder_request.user.id;
+
+        // Both joiners wait in the lobby until a decision is made
+        assert_eq!(room.handle_join_request(guest_request).unwrap(), JoinDecision::Pending);
+        assert_eq!(room.handle_join_request(intruder_request).unwrap(), JoinDecision::Pending);
+        assert_eq!(room.users.len(), 1);
+        assert_eq!(room.pending_joins.len(), 2);
+
+        room.approve_join(guest_id, host_id).unwrap();
+        room.deny_join(intruder_id, host_id, Some("not on the team".to_string())).unwrap();
+
+        assert!(room.users.contains_key(&guest_id));
+        assert!(!room.users.contains_key(&intruder_id));
+        assert!(room.pending_joins.is_empty());
+
+        // Each decision leaves an event in the history
+        let events: Vec<_> = room.messages.iter().filter_map(|m| m.event.as_ref()).collect();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], RoomEvent::UserJoined { approved_by: Some(id), .. } if *id == host_id));
+        assert!(matches!(events[1], RoomEvent::JoinDenied { reason: Some(reason), .. } if reason == "not on the team"));
+
+        // Deciding twice on the same request fails
+        assert!(room.approve_join(intruder_id, host_id).is_err());
+    }
+
+    #[test]
+    fn test_role_changes_respect_hierarchy() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+        let addr3 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8082);
+
+        let owner = User::new("Owner".to_string(), addr1);
+        let moderator = User::new("Moderator".to_string(), addr2);
+        let member = User::new("Member".to_string(), addr3);
+        let owner_id = owner.id;
+        let moderator_id = moderator.id;
+        let member_id = member.id;
+
+        let mut room = Room::new("Test Room".to_string(), owner, Protocol::TCP);
+        room.add_user(moderator).unwrap();
+        room.add_user(member).unwrap();
+
+        assert_eq!(room.role_of(owner_id), Some(Role::Owner));
+        assert_eq!(room.role_of(member_id), Some(Role::Member));
+        assert_eq!(room.role_of(Uuid::new_v4()), None);
+
+        // Members can't promote anyone
+        assert!(room.set_role(member_id, moderator_id, Role::Guest).is_err());
+
+        room.set_role(owner_id, moderator_id, Role::Moderator).unwrap();
+        assert!(room.has_permission(moderator_id, Permission::KickUsers));
+        assert!(!room.has_permission(moderator_id, Permission::ChangeProtocol));
+
+        // Moderators manage roles beneath their own only
+        room.set_role(moderator_id, member_id, Role::Guest).unwrap();
+        assert!(!room.has_permission(member_id, Permission::GenerateInvite));
+        assert!(room.set_role(moderator_id, member_id, Role::Moderator).is_err());
+        assert!(room.set_role(moderator_id, owner_id, Role::Member).is_err());
+
+        assert!(room.rename(member_id, "Hijacked".to_string()).is_err());
+        room.rename(moderator_id, "Renamed".to_string()).unwrap();
+        assert_eq!(room.name, "Renamed");
+
+        // Leaving the room drops the role entry
+        room.remove_user(member_id).unwrap();
+        assert!(!room.roles.contains_key(&member_id));
+    }
+
+    #[test]
+    fn test_password_verifier_stays_with_its_setter() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+
+        let owner = User::new("Owner".to_string(), addr1);
+        let member = User::new("Member".to_string(), addr2);
+        let owner_id = owner.id;
+        let member_id = member.id;
+
+        let mut room = Room::new("Test Room".to_string(), owner, Protocol::TCP);
+        room.add_user(member).unwrap();
+        room.password = Some(RoomPassword::new("hunter2").unwrap());
+
+        // Joiners learn that a password is needed, but get nothing to test
+        // passwords against
+        let mut snapshot = room.snapshot_for_peer();
+        assert!(snapshot.password.is_none() && snapshot.requires_password());
+
+        assert!(snapshot.apply_setting(member_id, &RoomSetting::PasswordRequired(false)).is_err());
+        snapshot.apply_setting(owner_id, &RoomSetting::PasswordRequired(false)).unwrap();
+        assert!(!snapshot.requires_password());
+    }
+
+    #[test]
+    fn test_moderation_actions() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), 8080);
+        let addr3 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 3)), 8080);
+
+        let owner_identity = crate::identity::LocalIdentity::generate().unwrap();
+        let troll_identity = crate::identity::LocalIdentity::generate().unwrap();
+
+        let mut owner = User::new("Owner".to_string(), addr1);
+        owner.identity_key = Some(owner_identity.public_key());
+        let mut troll = User::new("Troll".to_string(), addr2);
+        troll.identity_key = Some(troll_identity.public_key());
+        let chatty = User::new("Chatty".to_string(), addr3);
+
+        let owner_id = owner.id;
+        let troll_id = troll.id;
+        let chatty_id = chatty.id;
+
+        let mut room = Room::new("Test Room".to_string(), owner, Protocol::TCP);
+        room.add_user(troll.clone()).unwrap();
+        room.add_user(chatty).unwrap();
+
+        let command = |target: Uuid, action: ModerationAction| {
+            ModerationCommand::new(room.id, target, action, Some("rules".to_string()), owner_id, &owner_identity).unwrap()
+        };
+        let mute = command(chatty_id, ModerationAction::Mute { until: None });
+        let ban = command(troll_id, ModerationAction::Ban { until: Some(chrono::Utc::now() + chrono::Duration::hours(1)) });
+
+        room.apply_moderation(&mute).unwrap();
+        assert!(room.is_muted(chatty_id));
+
+        room.apply_moderation(&ban).unwrap();
+        assert!(!room.users.contains_key(&troll_id));
+
+        // The ban follows the identity key even with a fresh user id and address
+        let mut returning = User::new("Troll".to_string(), SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 9)), 8080));
+        returning.identity_key = troll.identity_key.clone();
+        assert!(room.find_ban(&returning).is_some());
+
+        let request = JoinRequest::new(room.id, returning.clone(), &troll_identity);
+        assert!(room.handle_join_request(request).is_err());
+
+        // Commands signed by a key the issuer didn't join with are rejected
+        let forged = ModerationCommand::new(
+            room.id, chatty_id, ModerationAction::Kick, None, owner_id, &troll_identity,
+        ).unwrap();
+        assert!(room.apply_moderation(&forged).is_err());
+        assert!(room.users.contains_key(&chatty_id));
+
+        assert_eq!(room.moderation_log.len(), 2);
+        assert_eq!(room.moderation_log[1].target_name, "Troll");
+
+        // Whoever takes over as host still enforces the bans
+        let snapshot = room.snapshot_for_peer();
+        assert!(snapshot.find_ban(&returning).is_some());
+        assert_eq!(snapshot.moderation_log.len(), 2);
+    }
+
+    #[test]
+    fn test_message_edit_and_delete() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+        let addr3 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8082);
+
+        let owner = User::new("Owner".to_string(), addr1);
+        let author = User::new("Author".to_string(), addr2);
+        let bystander = User::new("Bystander".to_string(), addr3);
+        let owner_id = owner.id;
+        let author_id = author.id;
+        let bystander_id = bystander.id;
+
+        let mut room = Room::new("Test Room".to_string(), owner, Protocol::TCP);
+        room.add_user(author).unwrap();
+        room.add_user(bystander).unwrap();
+
+        let message = ChatMessage::new(author_id, "Author".to_string(), "helo".to_string());
+        let message_id = message.id;
+        room.add_message(message.clone());
+        let room_id = room.id;
+
+        let edit = |edited_by: Uuid, content: &str, edited_at| MessageEdit {
+            room_id,
+            message_id,
+            content: content.to_string(),
+            edited_by,
+            edited_at,
+        };
+        let first = chrono::Utc::now();
+        let second = first + chrono::Duration::seconds(5);
+
+        assert!(room.edit_message(&edit(bystander_id, "pwned", first)).is_err());
+        room.edit_message(&edit(author_id, "hello!", second)).unwrap();
+
+        // A late, older edit lands in the history without overwriting
+        let current = room.edit_message(&edit(author_id, "hello", first)).unwrap();
+        assert_eq!(current.content, "hello!");
+        assert_eq!(current.edit_history.len(), 2);
+
+        let tombstone = |deleted_by| MessageTombstone {
+            room_id,
+            message_id,
+            deleted_by,
+            deleted_at: chrono::Utc::now(),
+        };
+        assert!(room.delete_message(tombstone(bystander_id)).is_err());
+        room.delete_message(tombstone(owner_id)).unwrap();
+
+        let deleted = room.messages.iter().find(|m| m.id == message_id).unwrap();
+        assert!(deleted.is_deleted());
+        assert!(deleted.content.is_empty() && deleted.edit_history.is_empty());
+        assert!(room.edit_message(&edit(author_id, "back", chrono::Utc::now())).is_err());
+
+        // A copy replayed from another peer after a reconnect stays deleted
+        room.messages.clear();
+        room.add_message(message);
+        assert!(room.messages[0].is_deleted());
+    }
+
+    #[test]
+    fn test_reactions_converge() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+
+        let alice = User::new("Alice".to_string(), addr1);
+        let bob = User::new("Bob".to_string(), addr2);
+        let alice_id = alice.id;
+        let bob_id = bob.id;
+
+        let mut room = Room::new("Test Room".to_string(), alice, Protocol::TCP);
+        room.add_user(bob).unwrap();
+        let message = ChatMessage::new(alice_id, "Alice".to_string(), "gg".to_string());
+        room.add_message(message.clone());
+
+        let update = |user_id, emoji: &str, added| ReactionUpdate {
+            room_id: room.id,
+            message_id: message.id,
+            emoji: emoji.to_string(),
+            user_id,
+            added,
+            hlc: clock::LOCAL.now(),
+        };
+        let updates = vec![
+            update(alice_id, "👍", true),
+            update(bob_id, "👍", true),
+            update(bob_id, "🎉", true),
+            update(bob_id, "🎉", false),
+        ];
+
+        // Two peers see other users' updates in different orders
+        let mut peer_a = room.clone();
+        let mut peer_b = room.clone();
+        for u in &updates {
+            peer_a.apply_reaction(u).unwrap();
+        }
+        for u in [&updates[1], &updates[0], &updates[2], &updates[3]] {
+            peer_b.apply_reaction(u).unwrap();
+        }
+        // Re-delivery changes nothing
+        assert!(!peer_a.apply_reaction(&updates[0]).unwrap());
+        // Non-members can neither add nor take away reactions
+        let stranger = Uuid::new_v4();
+        assert!(peer_a.apply_reaction(&update(stranger, "👍", true)).is_err());
+        assert!(peer_a.apply_reaction(&update(stranger, "👍", false)).is_err());
+
+        let reactions_a = &peer_a.messages[0].reactions;
+        assert_eq!(reactions_a, &peer_b.messages[0].reactions);
+        assert_eq!(reactions_a.len(), 1);
+        assert_eq!(reactions_a["👍"].len(), 2);
+    }
+
+    #[test]
+    fn test_reaction_removals_survive_sync() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+
+        let alice = User::new("Alice".to_string(), addr1);
+        let bob = User::new("Bob".to_string(), addr2);
+        let alice_id = alice.id;
+        let bob_id = bob.id;
+
+        let mut room = Room::new("Test Room".to_string(), alice, Protocol::TCP);
+        room.add_user(bob).unwrap();
+        let message = ChatMessage::new(bob_id, "Bob".to_string(), "gg".to_string());
+        room.add_message(message.clone());
+
+        let update = |added| ReactionUpdate {
+            room_id: room.id,
+            message_id: message.id,
+            emoji: "👍".to_string(),
+            user_id: bob_id,
+            added,
+            hlc: clock::LOCAL.now(),
+        };
+        let (add, remove) = (update(true), update(false));
+        room.apply_reaction(&add).unwrap();
+        let mut stale = room.clone();
+        room.apply_reaction(&remove).unwrap();
+
+        // A copy from before the removal doesn't bring the reaction back
+        assert_eq!(room.merge_synced(bob_id, stale.messages.clone(), Vec::new()), 0);
+        assert!(room.messages[0].reactions.is_empty());
+
+        // and the peer that missed the removal picks it up
+        assert_eq!(stale.merge_synced(bob_id, room.messages.clone(), Vec::new()), 1);
+        assert!(stale.messages[0].reactions.is_empty());
+
+        // Reactions only come from whoever made them
+        let mut forged = room.messages[0].clone();
+        forged.reactions.insert("👍".to_string(), BTreeSet::from([alice_id]));
+        forged.reaction_marks.insert("👍".to_string(), BTreeMap::from([(alice_id, ReactionMark { added: true, hlc: clock::LOCAL.now() })]));
+        room.merge_synced(bob_id, vec![forged], Vec::new());
+        assert!(room.messages[0].reactions.is_empty());
+    }
+
+    #[test]
+    fn test_copies_cannot_pin_or_delete() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+
+        let alice = User::new("Alice".to_string(), addr1);
+        let bob = User::new("Bob".to_string(), addr2);
+        let bob_id = bob.id;
+
+        let mut room = Room::new("Test Room".to_string(), alice, Protocol::TCP);
+        room.add_user(bob).unwrap();
+
+        let mut live = ChatMessage::new(bob_id, "Bob".to_string(), "pin me".to_string());
+        live.pinned = true;
+        live.edit_history.push(MessageRevision { content: "made up".to_string(), replaced_at: chrono::Utc::now() });
+        live.strip_peer_state();
+        assert!(!live.pinned && live.edit_history.is_empty());
+        room.add_message(live.clone());
+
+        // Neither a new message nor a copy of a known one arrives pinned
+        // or deleted through sync
+        let mut synced = ChatMessage::new(bob_id, "Bob".to_string(), "me too".to_string());
+        synced.pinned = true;
+        let mut copy = live.clone();
+        copy.pinned = true;
+        copy.deleted_at = Some(chrono::Utc::now());
+        room.merge_synced(bob_id, vec![synced, copy], Vec::new());
+
+        assert_eq!(room.messages.len(), 2);
+        assert!(room.messages.iter().all(|m| !m.pinned && !m.is_deleted()));
+    }
+
+    #[test]
+    fn test_threads_survive_trim() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let user = User::new("User".to_string(), addr);
+        let user_id = user.id;
+        let mut room = Room::new("Test Room".to_string(), user, Protocol::TCP);
+
+        let root = ChatMessage::new(user_id, "User".to_string(), "thread root".to_string());
+        let root_id = root.id;
+        room.add_message(root);
+
+        let first_reply = ChatMessage::reply(user_id, "User".to_string(), "first".to_string(), root_id);
+        let first_reply_id = first_reply.id;
+        room.add_message(first_reply);
+
+        // Replying to a reply joins the same thread
+        room.add_message(ChatMessage::reply(user_id, "User".to_string(), "second".to_string(), first_reply_id));
+        let thread = room.get_thread(first_reply_id).unwrap();
+        assert_eq!(thread.root.id, root_id);
+        assert_eq!(thread.root.reply_count, 2);
+        assert_eq!(thread.replies.len(), 2);
+
+        // Push the thread out of the window, then revive it with a late reply
+        for i in 0..MAX_MESSAGES {
+            room.add_message(ChatMessage::new(user_id, "User".to_string(), format!("filler {}", i)));
+        }
+        assert!(room.get_thread(root_id).is_none());
+
+        let mut room = Room::new("Test Room".to_string(), User::new("User".to_string(), addr), Protocol::TCP);
+        let root = ChatMessage::new(user_id, "User".to_string(), "thread root".to_string());
+        let root_id = root.id;
+        room.add_message(root);
+        for i in 0..MAX_MESSAGES {
+            room.add_message(ChatMessage::new(user_id, "User".to_string(), format!("filler {}", i)));
+            if i == MAX_MESSAGES / 2 {
+                room.add_message(ChatMessage::reply(user_id, "User".to_string(), "still here".to_string(), root_id));
+            }
+        }
+
+        assert_eq!(room.messages[0].id, root_id);
+        assert_eq!(room.messages.len(), MAX_MESSAGES + 1);
+        assert_eq!(room.get_thread(root_id).unwrap().replies.len(), 1);
+    }
+
+    #[test]
+    fn test_reply_counts_are_counted_locally() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let user = User::new("User".to_string(), addr);
+        let user_id = user.id;
+        let mut room = Room::new("Test Room".to_string(), user, Protocol::TCP);
+
+        let mut root = ChatMessage::new(user_id, "User".to_string(), "thread root".to_string());
+        root.reply_count = 99;
+        root.last_reply_at = Some(chrono::Utc::now() + chrono::Duration::days(1));
+        let reply = ChatMessage::reply(user_id, "User".to_string(), "first".to_string(), root.id);
+
+        // The reply turns up before its root, and the root's counters are made up
+        room.add_message(reply.clone());
+        room.add_message(root.clone());
+        let thread = room.get_thread(root.id).unwrap();
+        assert_eq!(thread.root.reply_count, 1);
+        assert_eq!(thread.root.last_reply_at, Some(reply.timestamp));
+    }
+
+    #[test]
+    fn test_rejoin_keeps_local_history() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), 8080);
+
+        let host = User::new("Host".to_string(), addr1);
+        let host_id = host.id;
+        let identity = crate::identity::LocalIdentity::generate().unwrap();
+        let mut guest = User::new("Guest".to_string(), addr2);
+        guest.identity_key = Some(identity.public_key());
+        let guest_id = guest.id;
+
+        let mut server = Room::new("Test Room".to_string(), host, Protocol::TCP);
+        server.add_user(guest.clone()).unwrap();
+        let mut saved = server.clone();
+        saved.mark_saved();
+
+        // We went offline after seeing one message the server never got
+        let only_ours = ChatMessage::new(guest_id, "Guest".to_string(), "sent while the host was down".to_string());
+        saved.add_message(only_ours.clone());
+        server.add_message(ChatMessage::new(host_id, "Host".to_string(), "missed while away".to_string()));
+
+        // Coming back from a new address with the same key is let straight in
+        guest.address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 3)), 8080);
+        let request = JoinRequest::new(server.id, guest.clone(), &identity);
+        assert_eq!(server.handle_join_request(request).unwrap(), JoinDecision::Approved);
+        assert_eq!(server.users[&guest_id].address, guest.address);
+
+        // Someone else claiming the member id is turned away
+        let impostor = crate::identity::LocalIdentity::generate().unwrap();
+        let mut spoofed = guest.clone();
+        spoofed.identity_key = Some(impostor.public_key());
+        assert!(server.handle_join_request(JoinRequest::new(server.id, spoofed, &impostor)).is_err());
+
+        saved.adopt_snapshot(server.snapshot_for_peer());
+        assert_eq!(saved.messages.len(), 2);
+        assert!(saved.messages.iter().any(|m| m.id == only_ours.id));
+    }
+
+    #[test]
+    fn test_messages_ordered_by_logical_clock() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let user = User::new("User".to_string(), addr);
+        let user_id = user.id;
+        let mut room = Room::new("Test Room".to_string(), user, Protocol::TCP);
+
+        let clock = crate::clock::HybridClock::new();
+        let mut question = ChatMessage::new(user_id, "User".to_string(), "question?".to_string());
+        question.hlc = clock.observe(HlcTimestamp::from_wall(chrono::Utc::now() + chrono::Duration::minutes(5)));
+
+        // The answer comes from a laptop whose wall clock is behind
+        let mut answer = ChatMessage::new(user_id, "User".to_string(), "answer".to_string());
+        answer.timestamp = chrono::Utc::now() - chrono::Duration::minutes(5);
+        answer.hlc = clock.now();
+
+        room.add_message(answer.clone());
+        room.add_message(question.clone());
+
+        assert_eq!(room.messages[0].id, question.id);
+        assert_eq!(room.messages[1].id, answer.id);
+
+        // One stamped well past the allowed skew isn't taken from a peer
+        let mut runaway = ChatMessage::new(user_id, "User".to_string(), "from the future".to_string());
+        runaway.hlc = HlcTimestamp::from_wall(chrono::Utc::now() + chrono::Duration::hours(1));
+        assert_eq!(room.merge_synced(user_id, vec![runaway], Vec::new()), 0);
+        assert_eq!(room.messages.len(), 2);
+    }
+
+    #[test]
+    fn test_retention_is_shared_as_a_room_setting() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let owner = User::new("Owner".to_string(), addr);
+        let owner_id = owner.id;
+        let mut room = Room::new("Test Room".to_string(), owner, Protocol::TCP);
+        let member = User::new("Member".to_string(), addr);
+        let member_id = member.id;
+        room.add_user(member).unwrap();
+
+        let keep_ten = RoomSetting::Retention { channel_id: Some(GENERAL_CHANNEL), policy: RetentionPolicy::MaxCount(10) };
+        assert!(room.apply_setting(member_id, &keep_ten).is_err());
+        let keep_none = RoomSetting::Retention { channel_id: None, policy: RetentionPolicy::MaxCount(0) };
+        assert!(room.apply_setting(owner_id, &keep_none).is_err());
+        room.apply_setting(owner_id, &keep_ten).unwrap();
+        assert_eq!(room.retention_for(GENERAL_CHANNEL), RetentionPolicy::MaxCount(10));
+
+        // Joiners get the channel's policy, but not our read position
+        room.channel_state.get_mut(&GENERAL_CHANNEL).unwrap().last_read = Some((clock::LOCAL.now(), Uuid::new_v4()));
+        let mut joiner = Room::new("Test Room".to_string(), User::new("Joiner".to_string(), addr), Protocol::TCP);
+        joiner.adopt_snapshot(room.snapshot_for_peer());
+        assert_eq!(joiner.retention_for(GENERAL_CHANNEL), RetentionPolicy::MaxCount(10));
+        assert!(joiner.channel_state[&GENERAL_CHANNEL].last_read.is_none());
+    }
+
+    #[test]
+    fn test_file_size_limit_is_a_room_setting() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let owner = User::new("Owner".to_string(), addr);
+        let owner_id = owner.id;
+        let mut room = Room::new("Test Room".to_string(), owner, Protocol::TCP);
+        let member = User::new("Member".to_string(), addr);
+        let member_id = member.id;
+        room.add_user(member).unwrap();
+
+        assert!(room.apply_setting(member_id, &RoomSetting::MaxFileSize(Some(1024))).is_err());
+        assert!(room.apply_setting(owner_id, &RoomSetting::MaxFileSize(Some(0))).is_err());
+        assert_eq!(room.file_size_limit(), transfer::DEFAULT_MAX_FILE_SIZE);
+
+        room.apply_setting(owner_id, &RoomSetting::MaxFileSize(Some(1024))).unwrap();
+        assert_eq!(room.snapshot_for_peer().file_size_limit(), 1024);
+    }
+
+    #[test]
+    fn test_channels_keep_separate_history() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let owner = User::new("Owner".to_string(), addr);
+        let owner_id = owner.id;
+        let mut room = Room::new("Test Room".to_string(), owner, Protocol::TCP);
+        let member = User::new("Member".to_string(), addr);
+        let member_id = member.id;
+        room.add_user(member).unwrap();
+
+        let room_id = room.id;
+        let create = |channel_id, updated_by, name: &str| ChannelUpdate {
+            room_id,
+            channel_id,
+            action: ChannelAction::Create { name: name.to_string() },
+            updated_by,
+            updated_at: chrono::Utc::now(),
+        };
+
+        // Only roles with ManageChannels may add channels
+        let builds_id = Uuid::new_v4();
+        assert!(room.apply_channel_update(&create(builds_id, member_id, "builds")).is_err());
+        let update = create(builds_id, owner_id, "Builds");
+        assert!(room.apply_channel_update(&update).unwrap());
+        assert!(!room.apply_channel_update(&update).unwrap());
+        assert_eq!(room.channel(builds_id).unwrap().name, "builds");
+
+        for i in 0..3 {
+            room.add_message(ChatMessage::new(owner_id, "Owner".to_string(), format!("general {}", i)));
+            let mut message = ChatMessage::new(owner_id, "Owner".to_string(), format!("builds {}", i));
+            message.channel_id = builds_id;
+            room.add_message(message);
+        }
+
+        let page = room.page_messages(builds_id, None, PageDirection::Before, 10);
+        assert_eq!(page.messages.len(), 3);
+        assert!(page.messages.iter().all(|m| m.channel_id == builds_id));
+
+        // Read state and retention are tracked per channel
+        room.mark_channel_read(GENERAL_CHANNEL);
+        assert_eq!(room.unread_in(GENERAL_CHANNEL, member_id).unread, 0);
+        assert_eq!(room.unread_in(builds_id, member_id).unread, 3);
+        assert_eq!(room.unread_in(builds_id, owner_id).unread, 0);
+        room.channel_state.entry(builds_id).or_default().retention = Some(RetentionPolicy::MaxCount(1));
+        assert_eq!(room.apply_retention(chrono::Utc::now()), 2);
+        assert_eq!(room.page_messages(GENERAL_CHANNEL, None, PageDirection::Before, 10).messages.len(), 3);
+
+        // Deleting a channel drops its history and keeps it from coming back
+        let delete = ChannelUpdate { action: ChannelAction::Delete, ..update.clone() };
+        assert!(room.apply_channel_update(&delete).unwrap());
+        assert!(room.channel(builds_id).is_none());
+        assert!(room.messages.iter().all(|m| m.channel_id == GENERAL_CHANNEL));
+        assert!(room.apply_channel_update(&update).is_err());
+        let mut late = ChatMessage::new(owner_id, "Owner".to_string(), "late".to_string());
+        late.channel_id = builds_id;
+        room.add_message(late);
+        assert_eq!(room.messages.len(), 3);
+        assert_eq!(room.take_removed_channels(), vec![builds_id]);
+
+        let general_delete = ChannelUpdate { channel_id: GENERAL_CHANNEL, ..delete };
+        assert!(room.apply_channel_update(&general_delete).is_err());
+    }
+
+    #[test]
+    fn test_unread_counts_mentions_but_not_own_messages() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let owner = User::new("Owner".to_string(), addr);
+        let owner_id = owner.id;
+        let mut room = Room::new("Test Room".to_string(), owner, Protocol::TCP);
+        let member = User::new("Member".to_string(), addr);
+        let member_id = member.id;
+        room.add_user(member).unwrap();
+
+        room.add_message(ChatMessage::new(owner_id, "Owner".to_string(), "morning".to_string()));
+        room.add_message(ChatMessage::new(owner_id, "Owner".to_string(), "@member take a look".to_string()));
+        room.add_message(ChatMessage::new(member_id, "Member".to_string(), "on it".to_string()));
+        room.record_event(RoomEvent::CallEnded);
+
+        let summary = room.unread_summary(member_id);
+        assert_eq!((summary.total.unread, summary.total.mentions), (2, 1));
+        assert_eq!(summary.channels[0].last_read, None);
+
+        room.mark_all_read();
+        let summary = room.unread_summary(member_id);
+        assert_eq!(summary.total, UnreadCount::default());
+        assert!(summary.channels[0].last_read.is_some());
+    }
+
+    #[test]
+    fn test_receipts_track_delivery_and_reads() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let author = User::new("Author".to_string(), addr);
+        let author_id = author.id;
+        let mut room = Room::new("Test Room".to_string(), author, Protocol::TCP);
+        let reader = User::new("Reader".to_string(), addr);
+        let reader_id = reader.id;
+        room.add_user(reader).unwrap();
+        let lurker = User::new("Lurker".to_string(), addr);
+        let lurker_id = lurker.id;
+        room.add_user(lurker).unwrap();
+
+        let first = ChatMessage::new(author_id, "Author".to_string(), "first".to_string());
+        let second = ChatMessage::new(author_id, "Author".to_string(), "second".to_string());
+        room.add_message(first.clone());
+        room.add_message(second.clone());
+
+        let room_id = room.id;
+        let delivered = |user_id, message_ids| ReceiptUpdate::Delivered { room_id, user_id, message_ids };
+        assert!(room.apply_receipt(&delivered(lurker_id, vec![first.id, second.id, Uuid::new_v4()])).unwrap());
+        assert!(room.apply_receipt(&delivered(Uuid::new_v4(), vec![first.id])).is_err());
+
+        // Reading up to the latest message covers everything before it,
+        // and read implies delivered
+        assert!(room.record_own_read(reader_id, GENERAL_CHANNEL).is_some());
+        assert!(room.record_own_read(reader_id, GENERAL_CHANNEL).is_none());
+        let receipts = room.message_receipts(first.id).unwrap();
+        assert_eq!(receipts.read_by, vec![reader_id]);
+        let mut expected = vec![reader_id, lurker_id];
+        expected.sort();
+        assert_eq!(receipts.delivered_to, expected);
+
+        // History synced from the reader only vouches for the reader's own
+        // receipts, not the lurker's
+        let mut peer = room.snapshot_for_peer();
+        peer.receipts = Receipts::default();
+        assert!(peer.merge_receipts(reader_id, room.receipts.clone()));
+        let receipts = peer.message_receipts(second.id).unwrap();
+        assert_eq!(receipts.read_by, vec![reader_id]);
+        assert_eq!(receipts.delivered_to, vec![reader_id]);
+        assert!(!peer.merge_receipts(author_id, room.receipts.clone()));
+    }
+
+    #[test]
+    fn test_events_are_only_recorded_locally() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let owner = User::new("Owner".to_string(), addr);
+        let owner_id = owner.id;
+        let mut room = Room::new("Test Room".to_string(), owner, Protocol::TCP);
+
+        room.join_call(owner_id).unwrap();
+        assert!(room.join_call(owner_id).is_err());
+        room.leave_call(owner_id).unwrap();
+        room.switch_protocol(owner_id, Protocol::TCP);
+        room.switch_protocol(owner_id, Protocol::WebSocket);
+
+        let events: Vec<_> = room.messages.iter().filter_map(|m| m.event.clone()).collect();
+        assert_eq!(events, vec![
+            RoomEvent::CallStarted { started_by: owner_id },
+            RoomEvent::CallJoined { user_id: owner_id, name: "Owner".to_string() },
+            RoomEvent::CallLeft { user_id: owner_id, name: "Owner".to_string() },
+            RoomEvent::CallEnded,
+            RoomEvent::ProtocolChanged { protocol: Protocol::WebSocket, changed_by: owner_id },
+        ]);
+        assert_eq!(room.unread_in(GENERAL_CHANNEL, Uuid::new_v4()).unread, 0);
+
+        // Peers get our history without our events, and can't slip theirs in
+        let mut peer_copy = room.snapshot_for_peer();
+        assert!(peer_copy.messages.is_empty());
+        let forged = ChatMessage::event(RoomEvent::RoomRenamed { name: "pwned".to_string(), renamed_by: owner_id });
+        assert_eq!(peer_copy.merge_history(vec![forged.clone()], Vec::new()), 0);
+
+        // Our own events survive adopting the server's snapshot
+        peer_copy.record_event(RoomEvent::CallEnded);
+        let mut snapshot = room.snapshot_for_peer();
+        snapshot.messages.push(forged);
+        peer_copy.adopt_snapshot(snapshot);
+        assert_eq!(peer_copy.messages.iter().filter(|m| m.is_event()).count(), 1);
+    }
+}
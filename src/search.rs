@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use uuid::Uuid;
+use anyhow::Result;
+use ring::digest;
+use crate::room::ChatMessage;
+
+const MAX_TOKEN_LEN: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexedMessage {
+    user_id: Uuid,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    terms: HashMap<String, u32>,
+    length: u32,
+    #[serde(default)]
+    digest: u64,
+}
+
+/// Inverted index over the content of one room's messages. Kept up to date
+/// by `Room` as messages arrive, change or go away, and saved alongside the
+/// room file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchIndex {
+    postings: HashMap<String, HashSet<Uuid>>,
+    documents: HashMap<Uuid, IndexedMessage>,
+    /// Digests of every indexed message combined, to tell whether a saved
+    /// index still matches the history it's loaded with
+    #[serde(default)]
+    fingerprint: u64,
+    /// Changed since it was last loaded or saved
+    #[serde(skip)]
+    dirty: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchFilter {
+    pub author: Option<Uuid>,
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub room_id: Uuid,
+    pub room_name: String,
+    pub message: ChatMessage,
+    pub score: f64,
+}
+
+/// Lowercased alphanumeric runs; everything else separates tokens.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase().chars().take(MAX_TOKEN_LEN).collect())
+        .collect()
+}
+
+/// Stands for a message's id and content. Combined with xor, so it can be
+/// added and taken out again in any order.
+fn content_digest(message: &ChatMessage) -> u64 {
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(message.id.as_bytes());
+    context.update(message.content.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&context.finish().as_ref()[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+fn is_indexable(message: &ChatMessage) -> bool {
+    !message.is_deleted() && !tokenize(&message.content).is_empty()
+}
+
+/// What the fingerprint of an index over exactly these messages would be.
+pub fn fingerprint<'a>(messages: impl IntoIterator<Item = &'a ChatMessage>) -> u64 {
+    messages.into_iter()
+        .filter(|message| is_indexable(message))
+        .fold(0, |fingerprint, message| fingerprint ^ content_digest(message))
+}
+
+impl SearchIndex {
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    pub fn build<'a>(messages: impl IntoIterator<Item = &'a ChatMessage>) -> Self {
+        let mut index = Self::default();
+        for message in messages {
+            index.index_message(message);
+        }
+        index
+    }
+
+    /// Adds or re-indexes a message. Deleted messages drop out.
+    pub fn index_message(&mut self, message: &ChatMessage) {
+        self.remove_message(message.id);
+        if !is_indexable(message) {
+            return;
+        }
+
+        let tokens = tokenize(&message.content);
+
+        let mut terms: HashMap<String, u32> = HashMap::new();
+        for token in &tokens {
+            *terms.entry(token.clone()).or_default() += 1;
+        }
+        for term in terms.keys() {
+            self.postings.entry(term.clone()).or_default().insert(message.id);
+        }
+
+        self.documents.insert(message.id, IndexedMessage {
+            user_id: message.user_id,
+            timestamp: message.timestamp,
+            terms,
+            length: tokens.len() as u32,
+            digest: content_digest(message),
+        });
+        self.fingerprint ^= content_digest(message);
+        self.dirty = true;
+    }
+
+    pub fn remove_message(&mut self, message_id: Uuid) {
+        let document = match self.documents.remove(&message_id) {
+            Some(document) => document,
+            None => return,
+        };
+        self.fingerprint ^= document.digest;
+        self.dirty = true;
+
+        for term in document.terms.keys() {
+            if let Some(ids) = self.postings.get_mut(term) {
+                ids.remove(&message_id);
+                if ids.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+    }
+
+    /// Ids of messages containing every query term (the last one may be a
+    /// prefix, for search-as-you-type), best match first.
+    pub fn search(&self, query: &str, filter: &SearchFilter) -> Vec<(Uuid, f64)> {
+        let terms = tokenize(query);
+        let (last, exact) = match terms.split_last() {
+            Some(split) => split,
+            None => return Vec::new(),
+        };
+
+        // Each query term expands to the indexed terms it matches
+        let mut expanded: Vec<Vec<&String>> = exact
+            .iter()
+            .map(|term| self.postings.get_key_value(term).map(|(t, _)| t).into_iter().collect())
+            .collect();
+        expanded.push(self.postings.keys().filter(|t| t.starts_with(last.as_str())).collect());
+        if expanded.iter().any(|matches| matches.is_empty()) {
+            return Vec::new();
+        }
+
+        let matching = |matches: &Vec<&String>| -> HashSet<Uuid> {
+            matches.iter().flat_map(|t| self.postings[*t].iter().copied()).collect()
+        };
+        let mut candidates = matching(&expanded[0]);
+        for matches in &expanded[1..] {
+            let ids = matching(matches);
+            candidates.retain(|id| ids.contains(id));
+        }
+
+        let total = self.documents.len().max(1) as f64;
+        let average_length = self.documents.values().map(|d| d.length as f64).sum::<f64>() / total;
+
+        let mut hits: Vec<(Uuid, f64, chrono::DateTime<chrono::Utc>)> = candidates
+            .into_iter()
+            .filter_map(|id| {
+                let document = &self.documents[&id];
+                if filter.author.is_some_and(|author| author != document.user_id)
+                    || filter.from.is_some_and(|from| document.timestamp < from)
+                    || filter.to.is_some_and(|to| document.timestamp > to)
+                {
+                    return None;
+                }
+
+                // BM25 over the expanded terms
+                let mut score = 0.0;
+                for term in expanded.iter().flatten() {
+                    let tf = document.terms.get(*term).copied().unwrap_or(0) as f64;
+                    if tf == 0.0 {
+                        continue;
+                    }
+                    let df = self.postings[*term].len() as f64;
+                    let idf = ((total - df + 0.5) / (df + 0.5) + 1.0).ln();
+                    let norm = 1.2 * (0.25 + 0.75 * document.length as f64 / average_length.max(1.0));
+                    score += idf * tf * 2.2 / (tf + norm);
+                }
+                Some((id, score, document.timestamp))
+            })
+            .collect();
+
+        // Equal scores favour newer messages
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1).then(b.2.cmp(&a.2)));
+        hits.into_iter().map(|(id, score, _)| (id, score)).collect()
+    }
+
+    fn file_path(room_id: Uuid) -> Result<PathBuf> {
+        let app_data_dir = dirs::data_dir()
+            .ok_or_else(|| anyhow::anyhow!("Could not find app data directory"))?
+            .join("shortgap")
+            .join("rooms");
+        Ok(app_data_dir.join(format!("{}.search.json", room_id)))
+    }
+
+    /// Writes the index if it changed. Goes through a temporary file so a
+    /// crash mid-write leaves the previous index in place.
+    pub fn save(&mut self, room_id: Uuid) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let file_path = Self::file_path(room_id)?;
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp_path = file_path.with_extension("json.tmp");
+        std::fs::write(&temp_path, serde_json::to_string(self)?)?;
+        std::fs::rename(temp_path, file_path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn load(room_id: Uuid) -> Result<Self> {
+        let json_data = std::fs::read_to_string(Self::file_path(room_id)?)?;
+        Ok(serde_json::from_str(&json_data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_ranking_and_updates() {
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+
+        let mut raid = ChatMessage::new(alice, "Alice".to_string(), "Raid tonight at 9, bring potions".to_string());
+        let chatter = ChatMessage::new(bob, "Bob".to_string(), "raid raid RAID!".to_string());
+        let other = ChatMessage::new(bob, "Bob".to_string(), "anyone seen my headphones?".to_string());
+
+        let mut index = SearchIndex::build([&raid, &chatter, &other]);
+        let everything = SearchFilter::default();
+
+        // The message that repeats the term ranks first
+        let hits = index.search("raid", &everything);
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].0, chatter.id);
+
+        // Prefix matching on the last term, and every term has to match
+        assert_eq!(index.search("raid pot", &everything)[0].0, raid.id);
+        assert!(index.search("raid headphones", &everything).is_empty());
+
+        let only_alice = SearchFilter { author: Some(alice), ..Default::default() };
+        assert_eq!(index.search("raid", &only_alice).len(), 1);
+
+        // Edits and deletions update the index in place
+        raid.content = "Dungeon tonight instead".to_string();
+        index.index_message(&raid);
+        assert_eq!(index.search("raid", &everything).len(), 1);
+        assert_eq!(index.search("dungeon", &everything)[0].0, raid.id);
+
+        index.remove_message(chatter.id);
+        assert!(index.search("raid", &everything).is_empty());
+
+        // The fingerprint follows content, so an edit shows up even though
+        // the number of messages is the same
+        assert_eq!(index.fingerprint(), fingerprint([&raid, &other]));
+        raid.content = "Raid after all".to_string();
+        assert_ne!(index.fingerprint(), fingerprint([&raid, &other]));
+    }
+
+    #[test]
+    fn test_only_changes_mark_index_dirty() {
+        let message = ChatMessage::new(Uuid::new_v4(), "Alice".to_string(), "gg".to_string());
+        let index = SearchIndex::build([&message]);
+        assert!(index.dirty);
+
+        // What comes back from disk has nothing left to write
+        let mut loaded: SearchIndex = serde_json::from_str(&serde_json::to_string(&index).unwrap()).unwrap();
+        assert!(!loaded.dirty);
+        loaded.remove_message(Uuid::new_v4());
+        assert!(!loaded.dirty);
+        loaded.remove_message(message.id);
+        assert!(loaded.dirty);
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use crate::join::PendingJoin;
+use crate::networking::NetworkManager;
+use crate::room::Room;
+use crate::transfer::Download;
+use crate::typing::TypingTracker;
+use crate::user::User;
+
+/// Port the first hosted party listens on; each further one takes the
+/// next free port above it.
+pub const BASE_PORT: u16 = 8080;
+
+/// One party this client is in: the room, the networking session carrying
+/// its traffic and who we are in it. Member ids and addresses differ from
+/// party to party, so commands act as `local_user` rather than the profile.
+pub struct PartySession {
+    pub room: Room,
+    pub networking: NetworkManager,
+    pub local_user: User,
+    pub pending_join: Option<PendingJoin>,
+    pub port: Option<u16>,
+    pub typing: TypingTracker,
+    /// Files being fetched, by file id
+    pub downloads: HashMap<Uuid, Download>,
+}
+
+pub type SessionHandle = Arc<Mutex<PartySession>>;
+
+impl PartySession {
+    pub fn new(room: Room, local_user: User) -> Self {
+        Self {
+            room,
+            networking: NetworkManager::new(),
+            local_user,
+            pending_join: None,
+            port: None,
+            typing: TypingTracker::default(),
+            downloads: HashMap::new(),
+        }
+    }
+}
+
+/// Every active party, plus which one the UI is showing. Only handles are
+/// handed out, so the registry lock is never held while a session is.
+#[derive(Default)]
+pub struct Parties {
+    sessions: HashMap<Uuid, SessionHandle>,
+    ports: HashMap<Uuid, u16>,
+    focused: Option<Uuid>,
+}
+
+impl Parties {
+    pub fn get(&self, room_id: Uuid) -> Option<SessionHandle> {
+        self.sessions.get(&room_id).cloned()
+    }
+
+    pub fn contains(&self, room_id: Uuid) -> bool {
+        self.sessions.contains_key(&room_id)
+    }
+
+    pub fn handles(&self) -> Vec<SessionHandle> {
+        self.sessions.values().cloned().collect()
+    }
+
+    pub fn room_ids(&self) -> Vec<Uuid> {
+        self.sessions.keys().copied().collect()
+    }
+
+    /// Registers a session, focusing it if nothing else is.
+    pub fn insert(&mut self, session: PartySession) -> SessionHandle {
+        let room_id = session.room.id;
+        if let Some(port) = session.port {
+            self.ports.insert(room_id, port);
+        }
+        let handle = Arc::new(Mutex::new(session));
+        self.sessions.insert(room_id, handle.clone());
+        self.focused.get_or_insert(room_id);
+        handle
+    }
+
+    pub fn remove(&mut self, room_id: Uuid) -> Option<SessionHandle> {
+        self.ports.remove(&room_id);
+        let removed = self.sessions.remove(&room_id);
+        if self.focused == Some(room_id) {
+            self.focused = self.sessions.keys().next().copied();
+        }
+        removed
+    }
+
+    /// Lowest port at or above `BASE_PORT` no other session listens on.
+    pub fn free_port(&self) -> u16 {
+        (BASE_PORT..=u16::MAX)
+            .find(|port| !self.ports.values().any(|used| used == port))
+            .unwrap_or(BASE_PORT)
+    }
+
+    pub fn focused(&self) -> Option<Uuid> {
+        self.focused
+    }
+
+    pub fn focus(&mut self, room_id: Uuid) -> bool {
+        if !self.sessions.contains_key(&room_id) {
+            return false;
+        }
+        self.focused = Some(room_id);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use crate::networking::Protocol;
+
+    #[test]
+    fn test_parties_track_focus_and_ports() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), BASE_PORT);
+        let user = User::new("User".to_string(), addr);
+        let mut parties = Parties::default();
+
+        let mut work = PartySession::new(Room::new("Work".to_string(), user.clone(), Protocol::TCP), user.clone());
+        work.port = Some(parties.free_port());
+        let work_id = work.room.id;
+        parties.insert(work);
+
+        let mut gaming = PartySession::new(Room::new("Gaming".to_string(), user.clone(), Protocol::TCP), user);
+        gaming.port = Some(parties.free_port());
+        assert_eq!(gaming.port, Some(BASE_PORT + 1));
+        let gaming_id = gaming.room.id;
+        parties.insert(gaming);
+
+        // The first party stays focused until the UI switches
+        assert_eq!(parties.focused(), Some(work_id));
+        assert!(parties.focus(gaming_id));
+        assert!(!parties.focus(Uuid::new_v4()));
+
+        // Leaving the focused party moves focus to one still active, and
+        // frees its port
+        parties.remove(gaming_id);
+        assert_eq!(parties.focused(), Some(work_id));
+        assert_eq!(parties.free_port(), BASE_PORT + 1);
+    }
+}
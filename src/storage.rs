@@ -0,0 +1,1151 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+use anyhow::Result;
+use crate::clock::HlcTimestamp;
+use crate::direct::DirectMessage;
+use crate::notify::NotificationPolicy;
+use crate::pagination::{MessageCursor, PageDirection};
+use crate::receipts::ReadMarker;
+use crate::retention::{Compaction, RetentionPolicy};
+use crate::room::{ChatMessage, Room};
+use crate::search::{self, SearchFilter, SearchResult};
+use crate::user::User;
+
+const SCHEMA_VERSION: i64 = 7;
+
+/// How many messages are loaded into memory with a room; older history
+/// stays in the database and is paged in on demand.
+const LOADED_MESSAGES: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PingSample {
+    pub user_id: Uuid,
+    pub ping_ms: u64,
+    pub measured_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A room in the store, as listed under recent parties.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedRoom {
+    pub id: Uuid,
+    pub name: String,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    pub member_count: usize,
+    pub message_count: usize,
+}
+
+/// SQLite database holding rooms, their members, message history and ping
+/// history. Messages are inserted one row at a time, so saving a room only
+/// writes what changed since the last save, inside a single transaction.
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+fn app_data_dir() -> Result<PathBuf> {
+    Ok(dirs::data_dir()
+        .ok_or_else(|| anyhow::anyhow!("Could not find app data directory"))?
+        .join("shortgap"))
+}
+
+impl Store {
+    pub fn open_default() -> Result<Self> {
+        let app_data_dir = app_data_dir()?;
+        std::fs::create_dir_all(&app_data_dir)?;
+        Self::open(&app_data_dir.join("shortgap.db"))
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::initialize(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self> {
+        Self::initialize(Connection::open_in_memory()?)
+    }
+
+    fn initialize(conn: Connection) -> Result<Self> {
+        // WAL keeps the database consistent if we die mid-write
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+
+        let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version < 1 {
+            conn.execute_batch(
+                "BEGIN;
+                CREATE TABLE IF NOT EXISTS rooms (
+                    id TEXT PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    data TEXT NOT NULL,
+                    updated_at TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS users (
+                    room_id TEXT NOT NULL,
+                    user_id TEXT NOT NULL,
+                    data TEXT NOT NULL,
+                    PRIMARY KEY (room_id, user_id)
+                );
+                CREATE TABLE IF NOT EXISTS messages (
+                    room_id TEXT NOT NULL,
+                    id TEXT NOT NULL,
+                    user_id TEXT NOT NULL,
+                    hlc_physical INTEGER NOT NULL,
+                    hlc_logical INTEGER NOT NULL,
+                    timestamp TEXT NOT NULL,
+                    data TEXT NOT NULL,
+                    PRIMARY KEY (room_id, id)
+                );
+                CREATE INDEX IF NOT EXISTS messages_by_order
+                    ON messages (room_id, hlc_physical, hlc_logical, id);
+                CREATE INDEX IF NOT EXISTS messages_by_author
+                    ON messages (room_id, user_id, timestamp);
+                CREATE TABLE IF NOT EXISTS ping_history (
+                    room_id TEXT NOT NULL,
+                    user_id TEXT NOT NULL,
+                    ping_ms INTEGER NOT NULL,
+                    measured_at TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS ping_history_by_user
+                    ON ping_history (room_id, user_id, measured_at);
+                CREATE TABLE IF NOT EXISTS meta (
+                    key TEXT PRIMARY KEY,
+                    value TEXT NOT NULL
+                );
+                -- Full-text index over stored history. Each searchable
+                -- message gets a row id of its own in search_documents,
+                -- which is also its row id in the FTS table.
+                CREATE TABLE IF NOT EXISTS search_documents (
+                    id INTEGER PRIMARY KEY,
+                    room_id TEXT NOT NULL,
+                    message_id TEXT NOT NULL,
+                    UNIQUE (room_id, message_id)
+                );
+                CREATE VIRTUAL TABLE IF NOT EXISTS message_search USING fts5(content);
+                PRAGMA user_version = 1;
+                COMMIT;",
+            )?;
+        }
+        if version < 2 {
+            // Pinned messages are skipped by retention compaction
+            conn.execute_batch(
+                "BEGIN;
+                ALTER TABLE messages ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+                PRAGMA user_version = 2;
+                COMMIT;",
+            )?;
+        }
+        if version < 3 {
+            // Existing history belongs to the general channel, the nil id
+            conn.execute_batch(
+                "BEGIN;
+                ALTER TABLE messages ADD COLUMN channel_id TEXT NOT NULL DEFAULT '00000000-0000-0000-0000-000000000000';
+                CREATE INDEX IF NOT EXISTS messages_by_channel
+                    ON messages (room_id, channel_id, hlc_physical, hlc_logical, id);
+                PRAGMA user_version = 3;
+                COMMIT;",
+            )?;
+        }
+        if version < 4 {
+            // Direct messages are kept apart from room history, keyed by
+            // the other person in the conversation
+            conn.execute_batch(
+                "BEGIN;
+                CREATE TABLE IF NOT EXISTS direct_messages (
+                    room_id TEXT NOT NULL,
+                    id TEXT NOT NULL,
+                    peer_id TEXT NOT NULL,
+                    hlc_physical INTEGER NOT NULL,
+                    hlc_logical INTEGER NOT NULL,
+                    timestamp TEXT NOT NULL,
+                    data TEXT NOT NULL,
+                    PRIMARY KEY (room_id, id)
+                );
+                CREATE INDEX IF NOT EXISTS direct_messages_by_peer
+                    ON direct_messages (room_id, peer_id, hlc_physical, hlc_logical, id);
+                PRAGMA user_version = 4;
+                COMMIT;",
+            )?;
+        }
+        if version < 5 {
+            // The identity key each member had when we first saw them, so
+            // a host can't swap in its own later
+            conn.execute_batch(
+                "BEGIN;
+                CREATE TABLE IF NOT EXISTS identity_keys (
+                    room_id TEXT NOT NULL,
+                    user_id TEXT NOT NULL,
+                    identity_key TEXT NOT NULL,
+                    PRIMARY KEY (room_id, user_id)
+                );
+                PRAGMA user_version = 5;
+                COMMIT;",
+            )?;
+        }
+        if version < 6 {
+            // Receipts get tables of their own rather than riding along in
+            // the room data, which is rewritten on every save
+            conn.execute_batch(
+                "BEGIN;
+                CREATE TABLE IF NOT EXISTS deliveries (
+                    room_id TEXT NOT NULL,
+                    message_id TEXT NOT NULL,
+                    user_id TEXT NOT NULL,
+                    PRIMARY KEY (room_id, message_id, user_id)
+                );
+                CREATE TABLE IF NOT EXISTS read_markers (
+                    room_id TEXT NOT NULL,
+                    user_id TEXT NOT NULL,
+                    channel_id TEXT NOT NULL,
+                    message_id TEXT NOT NULL,
+                    hlc_physical INTEGER NOT NULL,
+                    hlc_logical INTEGER NOT NULL,
+                    PRIMARY KEY (room_id, user_id, channel_id)
+                );
+                PRAGMA user_version = 6;
+                COMMIT;",
+            )?;
+        }
+        if version < SCHEMA_VERSION {
+            // Where the files we can serve chunks of live on disk
+            conn.execute_batch(
+                "BEGIN;
+                CREATE TABLE IF NOT EXISTS shared_files (
+                    file_id TEXT PRIMARY KEY,
+                    room_id TEXT NOT NULL,
+                    path TEXT NOT NULL
+                );
+                PRAGMA user_version = 7;
+                COMMIT;",
+            )?;
+        }
+
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn save_room(&self, room: &mut Room) -> Result<()> {
+        let metadata = room.metadata_json()?;
+        let pings = room.take_ping_samples();
+        let removed_channels = room.take_removed_channels();
+        let receipts = room.unsaved_receipts();
+        let room_id = room.id.to_string();
+
+        let mut conn = self.connection();
+        let tx = conn.transaction()?;
+
+        tx.execute(
+            "INSERT INTO rooms (id, name, data, updated_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (id) DO UPDATE SET name = excluded.name, data = excluded.data, updated_at = excluded.updated_at",
+            params![room_id, room.name, metadata, chrono::Utc::now().to_rfc3339()],
+        )?;
+
+        tx.execute("DELETE FROM users WHERE room_id = ?1", params![room_id])?;
+        for user in room.users.values() {
+            tx.execute(
+                "INSERT INTO users (room_id, user_id, data) VALUES (?1, ?2, ?3)",
+                params![room_id, user.id.to_string(), serde_json::to_string(user)?],
+            )?;
+            if let Some(identity_key) = &user.identity_key {
+                tx.execute(
+                    "INSERT INTO identity_keys (room_id, user_id, identity_key) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (room_id, user_id) DO NOTHING",
+                    params![room_id, user.id.to_string(), identity_key],
+                )?;
+            }
+        }
+
+        for channel_id in &removed_channels {
+            let messages: Vec<String> = tx
+                .prepare("SELECT id FROM messages WHERE room_id = ?1 AND channel_id = ?2")?
+                .query_map(params![room_id, channel_id.to_string()], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            for message_id in &messages {
+                unindex_message(&tx, &room_id, message_id)?;
+                tx.execute(
+                    "DELETE FROM deliveries WHERE room_id = ?1 AND message_id = ?2",
+                    params![room_id, message_id],
+                )?;
+            }
+            tx.execute(
+                "DELETE FROM messages WHERE room_id = ?1 AND channel_id = ?2",
+                params![room_id, channel_id.to_string()],
+            )?;
+            tx.execute(
+                "DELETE FROM read_markers WHERE room_id = ?1 AND channel_id = ?2",
+                params![room_id, channel_id.to_string()],
+            )?;
+        }
+
+        for message in room.unsaved_messages() {
+            let (hlc, _) = message.order_key();
+            tx.execute(
+                "INSERT INTO messages (room_id, id, user_id, hlc_physical, hlc_logical, timestamp, data, pinned, channel_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 ON CONFLICT (room_id, id) DO UPDATE SET data = excluded.data, pinned = excluded.pinned",
+                params![
+                    room_id,
+                    message.id.to_string(),
+                    message.user_id.to_string(),
+                    hlc.physical,
+                    hlc.logical,
+                    message.timestamp.to_rfc3339(),
+                    serde_json::to_string(message)?,
+                    message.pinned,
+                    message.channel_id.to_string(),
+                ],
+            )?;
+            index_message(&tx, &room_id, message)?;
+        }
+
+        for (message_id, user_id) in &receipts.deliveries {
+            tx.execute(
+                "INSERT INTO deliveries (room_id, message_id, user_id) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (room_id, message_id, user_id) DO NOTHING",
+                params![room_id, message_id.to_string(), user_id.to_string()],
+            )?;
+        }
+        for (user_id, marker) in &receipts.read_markers {
+            tx.execute(
+                "INSERT INTO read_markers (room_id, user_id, channel_id, message_id, hlc_physical, hlc_logical)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT (room_id, user_id, channel_id) DO UPDATE SET
+                     message_id = excluded.message_id, hlc_physical = excluded.hlc_physical, hlc_logical = excluded.hlc_logical",
+                params![
+                    room_id,
+                    user_id.to_string(),
+                    marker.channel_id.to_string(),
+                    marker.message_id.to_string(),
+                    marker.hlc.physical,
+                    marker.hlc.logical,
+                ],
+            )?;
+        }
+
+        for ping in &pings {
+            tx.execute(
+                "INSERT INTO ping_history (room_id, user_id, ping_ms, measured_at) VALUES (?1, ?2, ?3, ?4)",
+                params![room_id, ping.user_id.to_string(), ping.ping_ms as i64, ping.measured_at.to_rfc3339()],
+            )?;
+        }
+
+        tx.commit()?;
+        drop(conn);
+
+        room.mark_saved();
+        if let Err(e) = room.save_search_index() {
+            eprintln!("Failed to save search index for room '{}': {}", room.name, e);
+        }
+        Ok(())
+    }
+
+    pub fn load_room(&self, room_id: Uuid) -> Result<Room> {
+        let conn = self.connection();
+        let id = room_id.to_string();
+
+        let metadata: String = conn
+            .query_row("SELECT data FROM rooms WHERE id = ?1", params![id], |row| row.get(0))
+            .optional()?
+            .ok_or_else(|| anyhow::anyhow!("Room {} is not in the database", room_id))?;
+        let mut room: Room = serde_json::from_str(&metadata)?;
+
+        let mut statement = conn.prepare("SELECT data FROM users WHERE room_id = ?1")?;
+        let users = statement.query_map(params![id], |row| row.get::<_, String>(0))?;
+        for data in users {
+            let user: User = serde_json::from_str(&data?)?;
+            room.users.insert(user.id, user);
+        }
+
+        let mut statement = conn.prepare(
+            "SELECT data FROM messages WHERE room_id = ?1
+             ORDER BY hlc_physical DESC, hlc_logical DESC, id DESC LIMIT ?2",
+        )?;
+        let rows = statement.query_map(params![id, LOADED_MESSAGES as i64], |row| row.get::<_, String>(0))?;
+        let mut messages = rows
+            .map(|data| Ok(serde_json::from_str::<ChatMessage>(&data?)?))
+            .collect::<Result<Vec<_>>>()?;
+        messages.reverse();
+        room.messages = messages;
+
+        // Deliveries are only kept for the messages in memory
+        let loaded: HashSet<String> = room.messages.iter().map(|m| m.id.to_string()).collect();
+        let mut statement = conn.prepare("SELECT message_id, user_id FROM deliveries WHERE room_id = ?1")?;
+        let rows = statement.query_map(params![id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (message_id, user_id) = row?;
+            if loaded.contains(&message_id) {
+                room.receipts.record_delivery(Uuid::parse_str(&user_id)?, Uuid::parse_str(&message_id)?);
+            }
+        }
+
+        let mut statement = conn.prepare(
+            "SELECT user_id, channel_id, message_id, hlc_physical, hlc_logical FROM read_markers WHERE room_id = ?1",
+        )?;
+        let rows = statement.query_map(params![id], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, u32>(4)?,
+            ))
+        })?;
+        for row in rows {
+            let (user_id, channel_id, message_id, physical, logical) = row?;
+            room.receipts.record_read(Uuid::parse_str(&user_id)?, ReadMarker {
+                channel_id: Uuid::parse_str(&channel_id)?,
+                message_id: Uuid::parse_str(&message_id)?,
+                hlc: HlcTimestamp { physical, logical },
+            });
+        }
+
+        room.mark_saved();
+        Ok(room)
+    }
+
+    pub fn list_rooms(&self) -> Result<Vec<Uuid>> {
+        let conn = self.connection();
+        let mut statement = conn.prepare("SELECT id FROM rooms ORDER BY updated_at DESC")?;
+        let ids = statement.query_map([], |row| row.get::<_, String>(0))?;
+
+        let mut room_ids = Vec::new();
+        for id in ids {
+            room_ids.push(Uuid::parse_str(&id?)?);
+        }
+        Ok(room_ids)
+    }
+
+    pub fn recent_rooms(&self, limit: usize) -> Result<Vec<SavedRoom>> {
+        let conn = self.connection();
+        let mut statement = conn.prepare(
+            "SELECT r.id, r.name, r.updated_at,
+                 (SELECT COUNT(*) FROM users u WHERE u.room_id = r.id),
+                 (SELECT COUNT(*) FROM messages m WHERE m.room_id = r.id)
+             FROM rooms r ORDER BY r.updated_at DESC LIMIT ?1",
+        )?;
+        let rows = statement.query_map(params![limit as i64], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, i64>(4)?,
+            ))
+        })?;
+
+        let mut rooms = Vec::new();
+        for row in rows {
+            let (id, name, updated_at, member_count, message_count) = row?;
+            rooms.push(SavedRoom {
+                id: Uuid::parse_str(&id)?,
+                name,
+                updated_at: chrono::DateTime::parse_from_rfc3339(&updated_at)?.with_timezone(&chrono::Utc),
+                member_count: member_count as usize,
+                message_count: message_count as usize,
+            });
+        }
+        Ok(rooms)
+    }
+
+    /// Messages of a channel next to `cursor` in history order, straight
+    /// from the index. Used to page past what's loaded in memory.
+    pub fn page_messages(
+        &self,
+        room_id: Uuid,
+        channel_id: Uuid,
+        cursor: &MessageCursor,
+        direction: PageDirection,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>> {
+        let (hlc, id) = cursor.key();
+        let query = match direction {
+            PageDirection::Before => {
+                "SELECT data FROM messages WHERE room_id = ?1 AND channel_id = ?6
+                 AND (hlc_physical, hlc_logical, id) < (?2, ?3, ?4)
+                 ORDER BY hlc_physical DESC, hlc_logical DESC, id DESC LIMIT ?5"
+            }
+            PageDirection::After => {
+                "SELECT data FROM messages WHERE room_id = ?1 AND channel_id = ?6
+                 AND (hlc_physical, hlc_logical, id) > (?2, ?3, ?4)
+                 ORDER BY hlc_physical, hlc_logical, id LIMIT ?5"
+            }
+        };
+
+        let conn = self.connection();
+        let mut statement = conn.prepare(query)?;
+        let rows = statement.query_map(
+            params![room_id.to_string(), hlc.physical, hlc.logical, id.to_string(), limit as i64, channel_id.to_string()],
+            |row| row.get::<_, String>(0),
+        )?;
+        let mut messages = rows
+            .map(|data| Ok(serde_json::from_str::<ChatMessage>(&data?)?))
+            .collect::<Result<Vec<_>>>()?;
+
+        if direction == PageDirection::Before {
+            messages.reverse();
+        }
+        Ok(messages)
+    }
+
+    /// Full-text search over a room's stored history, best match first.
+    /// Like the in-memory index, every term has to match and the last one
+    /// may be a prefix.
+    pub fn search_history(&self, room_id: Uuid, query: &str, filter: &SearchFilter, limit: usize) -> Result<Vec<SearchResult>> {
+        let terms = search::tokenize(query);
+        let (last, exact) = match terms.split_last() {
+            Some(split) => split,
+            None => return Ok(Vec::new()),
+        };
+        // Tokens are alphanumeric, so quoting is all FTS needs
+        let mut phrases: Vec<String> = exact.iter().map(|term| format!("\"{}\"", term)).collect();
+        phrases.push(format!("\"{}\"*", last));
+
+        let conn = self.connection();
+        let mut statement = conn.prepare(
+            "SELECT m.data, bm25(message_search), r.name FROM message_search
+             JOIN search_documents d ON d.id = message_search.rowid
+             JOIN messages m ON m.room_id = d.room_id AND m.id = d.message_id
+             JOIN rooms r ON r.id = d.room_id
+             WHERE message_search MATCH ?1 AND d.room_id = ?2
+               AND (?3 IS NULL OR m.user_id = ?3)
+               AND (?4 IS NULL OR m.timestamp >= ?4)
+               AND (?5 IS NULL OR m.timestamp <= ?5)
+             ORDER BY bm25(message_search), m.timestamp DESC
+             LIMIT ?6",
+        )?;
+        let rows = statement.query_map(
+            params![
+                phrases.join(" "),
+                room_id.to_string(),
+                filter.author.map(|author| author.to_string()),
+                filter.from.map(|from| from.to_rfc3339()),
+                filter.to.map(|to| to.to_rfc3339()),
+                limit as i64,
+            ],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?, row.get::<_, String>(2)?)),
+        )?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            let (data, rank, room_name) = row?;
+            results.push(SearchResult {
+                room_id,
+                room_name,
+                message: serde_json::from_str(&data)?,
+                // bm25() ranks better matches lower
+                score: -rank,
+            });
+        }
+        Ok(results)
+    }
+
+    /// Applies each stored room's retention policies to its full history
+    /// on disk. Returns the number of messages removed.
+    pub fn compact_all(&self, now: chrono::DateTime<chrono::Utc>) -> Result<usize> {
+        let mut removed = 0;
+        for room_id in self.list_rooms()? {
+            removed += self.compact_room(room_id, now)?.iter()
+                .map(|(_, compaction)| compaction.expired.len())
+                .sum::<usize>();
+        }
+        Ok(removed)
+    }
+
+    /// Compacts each channel of a room under its own policy, returning what
+    /// was dropped per channel.
+    pub fn compact_room(&self, room_id: Uuid, now: chrono::DateTime<chrono::Utc>) -> Result<Vec<(Uuid, Compaction)>> {
+        let mut conn = self.connection();
+        let id = room_id.to_string();
+
+        let metadata: String = conn
+            .query_row("SELECT data FROM rooms WHERE id = ?1", params![id], |row| row.get(0))
+            .optional()?
+            .ok_or_else(|| anyhow::anyhow!("Room {} is not in the database", room_id))?;
+        let mut room: Room = serde_json::from_str(&metadata)?;
+
+        let mut compactions = Vec::new();
+        for channel in &room.channels {
+            let policy = room.retention_for(channel.id);
+            if policy == RetentionPolicy::Unlimited {
+                continue;
+            }
+
+            let mut statement = conn.prepare(
+                "SELECT id, hlc_physical, LENGTH(CAST(data AS BLOB)) FROM messages
+                 WHERE room_id = ?1 AND channel_id = ?2 AND pinned = 0
+                 ORDER BY hlc_physical DESC, hlc_logical DESC, id DESC",
+            )?;
+            let rows = statement.query_map(params![id, channel.id.to_string()], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?))
+            })?;
+            let mut entries = Vec::new();
+            for row in rows {
+                let (message_id, physical, size) = row?;
+                entries.push((Uuid::parse_str(&message_id)?, physical, size as u64));
+            }
+
+            let compaction = policy.select_expired(entries, now);
+            if !compaction.expired.is_empty() {
+                compactions.push((channel.id, compaction));
+            }
+        }
+        if compactions.is_empty() {
+            return Ok(compactions);
+        }
+
+        let tx = conn.transaction()?;
+        for (channel_id, compaction) in &compactions {
+            for message_id in &compaction.expired {
+                unindex_message(&tx, &id, &message_id.to_string())?;
+                tx.execute(
+                    "DELETE FROM messages WHERE room_id = ?1 AND id = ?2",
+                    params![id, message_id.to_string()],
+                )?;
+                tx.execute(
+                    "DELETE FROM deliveries WHERE room_id = ?1 AND message_id = ?2",
+                    params![id, message_id.to_string()],
+                )?;
+            }
+            room.record_compaction(*channel_id, compaction);
+        }
+        tx.execute(
+            "UPDATE rooms SET data = ?2 WHERE id = ?1",
+            params![id, room.metadata_json()?],
+        )?;
+        tx.commit()?;
+
+        Ok(compactions)
+    }
+
+    /// Whether our read markers are shared with the party. On unless
+    /// turned off.
+    pub fn read_receipts_enabled(&self) -> Result<bool> {
+        let value: Option<String> = self.connection()
+            .query_row("SELECT value FROM meta WHERE key = 'send_read_receipts'", [], |row| row.get(0))
+            .optional()?;
+        Ok(value.as_deref() != Some("false"))
+    }
+
+    pub fn set_read_receipts_enabled(&self, enabled: bool) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO meta (key, value) VALUES ('send_read_receipts', ?1)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![enabled.to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// Where downloaded files go: the configured directory, or a folder in
+    /// the user's downloads.
+    pub fn downloads_dir(&self) -> Result<PathBuf> {
+        let value: Option<String> = self.connection()
+            .query_row("SELECT value FROM meta WHERE key = 'downloads_dir'", [], |row| row.get(0))
+            .optional()?;
+        match value {
+            Some(value) => Ok(PathBuf::from(value)),
+            None => Ok(dirs::download_dir()
+                .map(|dir| dir.join("shortgap"))
+                .unwrap_or(app_data_dir()?.join("downloads"))),
+        }
+    }
+
+    pub fn set_downloads_dir(&self, dir: &Path) -> Result<()> {
+        let dir = dir.to_str()
+            .ok_or_else(|| anyhow::anyhow!("Downloads directory must be valid UTF-8"))?;
+        self.connection().execute(
+            "INSERT INTO meta (key, value) VALUES ('downloads_dir', ?1)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![dir],
+        )?;
+        Ok(())
+    }
+
+    /// Where cleaned copies of images we share are kept.
+    pub fn shared_copies_dir(&self) -> Result<PathBuf> {
+        Ok(app_data_dir()?.join("shared"))
+    }
+
+    /// Remembers where a file we can serve chunks of is kept.
+    pub fn save_shared_file(&self, room_id: Uuid, file_id: Uuid, path: &Path) -> Result<()> {
+        let path = path.to_str()
+            .ok_or_else(|| anyhow::anyhow!("File path must be valid UTF-8"))?;
+        self.connection().execute(
+            "INSERT INTO shared_files (file_id, room_id, path) VALUES (?1, ?2, ?3)
+             ON CONFLICT (file_id) DO UPDATE SET room_id = excluded.room_id, path = excluded.path",
+            params![file_id.to_string(), room_id.to_string(), path],
+        )?;
+        Ok(())
+    }
+
+    pub fn shared_file(&self, room_id: Uuid, file_id: Uuid) -> Result<Option<PathBuf>> {
+        let path: Option<String> = self.connection()
+            .query_row(
+                "SELECT path FROM shared_files WHERE file_id = ?1 AND room_id = ?2",
+                params![file_id.to_string(), room_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(path.map(PathBuf::from))
+    }
+
+    pub fn notification_policy(&self) -> Result<NotificationPolicy> {
+        let value: Option<String> = self.connection()
+            .query_row("SELECT value FROM meta WHERE key = 'notification_policy'", [], |row| row.get(0))
+            .optional()?;
+        Ok(match value {
+            Some(value) => serde_json::from_str(&value)?,
+            None => NotificationPolicy::default(),
+        })
+    }
+
+    pub fn set_notification_policy(&self, policy: &NotificationPolicy) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO meta (key, value) VALUES ('notification_policy', ?1)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![serde_json::to_string(policy)?],
+        )?;
+        Ok(())
+    }
+
+    /// Stores a direct message under the conversation with `peer_id`, the
+    /// other person in it from our side.
+    pub fn save_direct_message(&self, peer_id: Uuid, message: &DirectMessage) -> Result<()> {
+        self.connection().execute(
+            "INSERT OR IGNORE INTO direct_messages
+             (room_id, id, peer_id, hlc_physical, hlc_logical, timestamp, data)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                message.room_id.to_string(),
+                message.id.to_string(),
+                peer_id.to_string(),
+                message.hlc.physical,
+                message.hlc.logical,
+                message.timestamp.to_rfc3339(),
+                serde_json::to_string(message)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The latest `limit` direct messages with `peer_id`, oldest first.
+    pub fn direct_messages(&self, room_id: Uuid, peer_id: Uuid, limit: usize) -> Result<Vec<DirectMessage>> {
+        let conn = self.connection();
+        let mut statement = conn.prepare(
+            "SELECT data FROM direct_messages WHERE room_id = ?1 AND peer_id = ?2
+             ORDER BY hlc_physical DESC, hlc_logical DESC, id DESC LIMIT ?3",
+        )?;
+        let rows = statement.query_map(
+            params![room_id.to_string(), peer_id.to_string(), limit as i64],
+            |row| row.get::<_, String>(0),
+        )?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            messages.push(serde_json::from_str::<DirectMessage>(&row?)?);
+        }
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// Pins a member's identity key if we haven't seen one for them yet.
+    /// Returns false if the key we pinned earlier is a different one.
+    pub fn pin_identity_key(&self, room_id: Uuid, user_id: Uuid, identity_key: &str) -> Result<bool> {
+        let conn = self.connection();
+        conn.execute(
+            "INSERT INTO identity_keys (room_id, user_id, identity_key) VALUES (?1, ?2, ?3)
+             ON CONFLICT (room_id, user_id) DO NOTHING",
+            params![room_id.to_string(), user_id.to_string(), identity_key],
+        )?;
+        let pinned: String = conn.query_row(
+            "SELECT identity_key FROM identity_keys WHERE room_id = ?1 AND user_id = ?2",
+            params![room_id.to_string(), user_id.to_string()],
+            |row| row.get(0),
+        )?;
+        Ok(pinned == identity_key)
+    }
+
+    pub fn ping_history(&self, room_id: Uuid, user_id: Uuid, limit: usize) -> Result<Vec<PingSample>> {
+        let conn = self.connection();
+        let mut statement = conn.prepare(
+            "SELECT ping_ms, measured_at FROM ping_history WHERE room_id = ?1 AND user_id = ?2
+             ORDER BY measured_at DESC LIMIT ?3",
+        )?;
+        let rows = statement.query_map(
+            params![room_id.to_string(), user_id.to_string(), limit as i64],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )?;
+
+        let mut samples = Vec::new();
+        for row in rows {
+            let (ping_ms, measured_at) = row?;
+            samples.push(PingSample {
+                user_id,
+                ping_ms: ping_ms as u64,
+                measured_at: chrono::DateTime::parse_from_rfc3339(&measured_at)?.with_timezone(&chrono::Utc),
+            });
+        }
+        Ok(samples)
+    }
+
+    /// One-time import of the `rooms/*.json` files written by earlier
+    /// versions. Imported files are renamed rather than deleted.
+    pub fn import_legacy_rooms(&self) -> Result<usize> {
+        self.import_legacy_rooms_from(&app_data_dir()?.join("rooms"))
+    }
+
+    fn import_legacy_rooms_from(&self, rooms_dir: &Path) -> Result<usize> {
+        let done: Option<String> = self.connection()
+            .query_row("SELECT value FROM meta WHERE key = 'legacy_rooms_imported'", [], |row| row.get(0))
+            .optional()?;
+        if done.is_some() || !rooms_dir.exists() {
+            return Ok(0);
+        }
+
+        let mut imported = 0;
+        for entry in std::fs::read_dir(rooms_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let is_room_file = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|stem| Uuid::parse_str(stem).is_ok());
+            if !is_room_file {
+                continue;
+            }
+
+            let mut room: Room = match std::fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|json| Ok(serde_json::from_str(&json)?))
+            {
+                Ok(room) => room,
+                Err(e) => {
+                    eprintln!("Skipping unreadable room file {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            // Older files may not be in history order
+            room.messages.sort_by_key(|m| m.order_key());
+            self.save_room(&mut room)?;
+            std::fs::rename(&path, path.with_extension("json.migrated"))?;
+            println!("📦 Imported room '{}' ({} messages) into the database", room.name, room.messages.len());
+            imported += 1;
+        }
+
+        self.connection().execute(
+            "INSERT INTO meta (key, value) VALUES ('legacy_rooms_imported', ?1)",
+            params![chrono::Utc::now().to_rfc3339()],
+        )?;
+        Ok(imported)
+    }
+}
+
+/// Keeps the full-text index in step with a message as saved. Events and
+/// deleted messages aren't searchable.
+fn index_message(conn: &Connection, room_id: &str, message: &ChatMessage) -> Result<()> {
+    let message_id = message.id.to_string();
+    unindex_message(conn, room_id, &message_id)?;
+    if message.is_event() || message.is_deleted() {
+        return Ok(());
+    }
+
+    conn.execute(
+        "INSERT INTO search_documents (room_id, message_id) VALUES (?1, ?2)",
+        params![room_id, message_id],
+    )?;
+    conn.execute(
+        "INSERT INTO message_search (rowid, content) VALUES (?1, ?2)",
+        params![conn.last_insert_rowid(), message.content],
+    )?;
+    Ok(())
+}
+
+fn unindex_message(conn: &Connection, room_id: &str, message_id: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM message_search WHERE rowid IN
+         (SELECT id FROM search_documents WHERE room_id = ?1 AND message_id = ?2)",
+        params![room_id, message_id],
+    )?;
+    conn.execute(
+        "DELETE FROM search_documents WHERE room_id = ?1 AND message_id = ?2",
+        params![room_id, message_id],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use crate::networking::Protocol;
+    use crate::room::MessageEdit;
+    use crate::channel::GENERAL_CHANNEL;
+    use crate::notify::NotificationLevel;
+    use crate::receipts::UnsavedReceipts;
+
+    fn test_room() -> Room {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let user = User::new("User".to_string(), addr);
+        Room::new("Test Room".to_string(), user, Protocol::TCP)
+    }
+
+    #[test]
+    fn test_room_round_trip_writes_only_changes() {
+        let store = Store::open_in_memory().unwrap();
+        let mut room = test_room();
+        let user_id = room.creator_id;
+
+        for i in 0..5 {
+            room.add_message(ChatMessage::new(user_id, "User".to_string(), format!("message {}", i)));
+        }
+        room.update_ping(user_id, 42);
+        store.save_room(&mut room).unwrap();
+        assert!(room.unsaved_messages().is_empty());
+
+        // An edit only marks the edited message for writing
+        let edited_id = room.messages[2].id;
+        room.edit_message(&MessageEdit {
+            room_id: room.id,
+            message_id: edited_id,
+            content: "edited".to_string(),
+            edited_by: user_id,
+            edited_at: chrono::Utc::now(),
+        }).unwrap();
+        room.add_message(ChatMessage::new(user_id, "User".to_string(), "new".to_string()));
+        assert_eq!(room.unsaved_messages().len(), 2);
+        store.save_room(&mut room).unwrap();
+
+        let loaded = store.load_room(room.id).unwrap();
+        assert_eq!(loaded.name, room.name);
+        assert_eq!(loaded.users.len(), 1);
+        assert_eq!(loaded.messages.len(), 6);
+        assert_eq!(loaded.messages[2].content, "edited");
+        assert_eq!(loaded.messages[5].content, "new");
+        assert_eq!(store.list_rooms().unwrap(), vec![room.id]);
+        let recent = store.recent_rooms(10).unwrap();
+        assert_eq!((recent[0].member_count, recent[0].message_count), (1, 6));
+        assert_eq!(store.ping_history(room.id, user_id, 10).unwrap()[0].ping_ms, 42);
+
+        let cursor = MessageCursor::for_message(&loaded.messages[3]);
+        let older = store.page_messages(room.id, GENERAL_CHANNEL, &cursor, PageDirection::Before, 2).unwrap();
+        assert_eq!(older.len(), 2);
+        assert_eq!(older[1].content, "edited");
+    }
+
+    #[test]
+    fn test_receipts_are_stored_apart_from_room_data() {
+        let store = Store::open_in_memory().unwrap();
+        let mut room = test_room();
+        let user_id = room.creator_id;
+
+        let message = ChatMessage::new(user_id, "User".to_string(), "hello".to_string());
+        room.add_message(message.clone());
+        store.save_room(&mut room).unwrap();
+
+        room.receipts.record_delivery(user_id, message.id);
+        room.record_own_read(user_id, GENERAL_CHANNEL).unwrap();
+        assert_eq!(room.unsaved_receipts().deliveries.len(), 1);
+        store.save_room(&mut room).unwrap();
+        assert_eq!(room.unsaved_receipts(), UnsavedReceipts::default());
+
+        let metadata: String = store.connection()
+            .query_row("SELECT data FROM rooms WHERE id = ?1", params![room.id.to_string()], |row| row.get(0))
+            .unwrap();
+        assert!(!metadata.contains(&message.id.to_string()));
+
+        let loaded = store.load_room(room.id).unwrap();
+        assert_eq!(loaded.receipts.delivered[&message.id].len(), 1);
+        assert_eq!(loaded.receipts.read_marker(user_id, GENERAL_CHANNEL).unwrap().message_id, message.id);
+        assert_eq!(loaded.unsaved_receipts(), UnsavedReceipts::default());
+    }
+
+    #[test]
+    fn test_messages_trimmed_before_saving_are_still_written() {
+        let store = Store::open_in_memory().unwrap();
+        let mut room = test_room();
+        let user_id = room.creator_id;
+        store.save_room(&mut room).unwrap();
+
+        // A sync batch bigger than what's kept in memory
+        let batch: Vec<ChatMessage> = (0..crate::room::MAX_MESSAGES + 10)
+            .map(|i| ChatMessage::new(user_id, "User".to_string(), format!("synced {}", i)))
+            .collect();
+        room.merge_history(batch, Vec::new());
+        assert_eq!(room.messages.len(), crate::room::MAX_MESSAGES);
+        store.save_room(&mut room).unwrap();
+        assert!(room.unsaved_messages().is_empty());
+
+        let cursor = MessageCursor::for_message(&room.messages[0]);
+        let trimmed = store.page_messages(room.id, GENERAL_CHANNEL, &cursor, PageDirection::Before, 50).unwrap();
+        assert_eq!(trimmed.len(), 10);
+    }
+
+    #[test]
+    fn test_search_covers_history_beyond_memory() {
+        let store = Store::open_in_memory().unwrap();
+        let mut room = test_room();
+        let user_id = room.creator_id;
+
+        let oldest = ChatMessage::new(user_id, "User".to_string(), "raid tonight".to_string());
+        room.add_message(oldest.clone());
+        for i in 0..crate::room::MAX_MESSAGES {
+            room.add_message(ChatMessage::new(user_id, "User".to_string(), format!("filler {}", i)));
+        }
+        let recent = ChatMessage::new(user_id, "User".to_string(), "raid again".to_string());
+        room.add_message(recent.clone());
+        store.save_room(&mut room).unwrap();
+        assert!(!room.messages.iter().any(|m| m.id == oldest.id));
+
+        let everything = SearchFilter::default();
+        let hits = store.search_history(room.id, "rai", &everything, 10).unwrap();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].room_name, "Test Room");
+        let only_recent = SearchFilter { from: Some(recent.timestamp), ..Default::default() };
+        assert_eq!(store.search_history(room.id, "raid", &only_recent, 10).unwrap()[0].message.id, recent.id);
+
+        // Edits and deletions are picked up on save
+        room.edit_message(&MessageEdit {
+            room_id: room.id,
+            message_id: recent.id,
+            content: "dungeon again".to_string(),
+            edited_by: user_id,
+            edited_at: chrono::Utc::now(),
+        }).unwrap();
+        store.save_room(&mut room).unwrap();
+        let hits = store.search_history(room.id, "raid", &everything, 10).unwrap();
+        assert_eq!(hits.iter().map(|hit| hit.message.id).collect::<Vec<_>>(), vec![oldest.id]);
+        assert_eq!(store.search_history(room.id, "dungeon", &everything, 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_read_receipts_setting() {
+        let store = Store::open_in_memory().unwrap();
+        assert!(store.read_receipts_enabled().unwrap());
+        store.set_read_receipts_enabled(false).unwrap();
+        assert!(!store.read_receipts_enabled().unwrap());
+    }
+
+    #[test]
+    fn test_identity_keys_are_pinned_on_first_sight() {
+        let store = Store::open_in_memory().unwrap();
+        let mut room = test_room();
+        let user_id = room.creator_id;
+        room.users.get_mut(&user_id).unwrap().identity_key = Some("first-key".to_string());
+        store.save_room(&mut room).unwrap();
+
+        // A snapshot carrying a different key for the same member doesn't move the pin
+        room.users.get_mut(&user_id).unwrap().identity_key = Some("swapped-key".to_string());
+        store.save_room(&mut room).unwrap();
+        assert!(store.pin_identity_key(room.id, user_id, "first-key").unwrap());
+        assert!(!store.pin_identity_key(room.id, user_id, "swapped-key").unwrap());
+
+        // Members we haven't seen before are pinned as they are
+        let newcomer = Uuid::new_v4();
+        assert!(store.pin_identity_key(room.id, newcomer, "newcomer-key").unwrap());
+        assert!(!store.pin_identity_key(room.id, newcomer, "other-key").unwrap());
+    }
+
+    #[test]
+    fn test_shared_files_are_found_per_room() {
+        let store = Store::open_in_memory().unwrap();
+        let room_id = Uuid::new_v4();
+        let (file_id, path) = (Uuid::new_v4(), Path::new("/tmp/map.bin"));
+
+        assert_eq!(store.shared_file(room_id, file_id).unwrap(), None);
+        store.save_shared_file(room_id, file_id, path).unwrap();
+        assert_eq!(store.shared_file(room_id, file_id).unwrap().as_deref(), Some(path));
+        assert_eq!(store.shared_file(Uuid::new_v4(), file_id).unwrap(), None);
+    }
+
+    #[test]
+    fn test_notification_policy_round_trip() {
+        let store = Store::open_in_memory().unwrap();
+        let mut policy = store.notification_policy().unwrap();
+        assert_eq!(policy, NotificationPolicy::default());
+
+        policy.rooms.insert(Uuid::new_v4(), NotificationLevel::Mentions);
+        store.set_notification_policy(&policy).unwrap();
+        assert_eq!(store.notification_policy().unwrap(), policy);
+    }
+
+    #[test]
+    fn test_direct_messages_are_kept_per_conversation() {
+        let store = Store::open_in_memory().unwrap();
+        let room = test_room();
+        let (me, alice, bob) = (room.creator_id, Uuid::new_v4(), Uuid::new_v4());
+
+        for (i, peer_id) in [alice, bob, alice].into_iter().enumerate() {
+            let timestamp = chrono::Utc::now() + chrono::Duration::milliseconds(i as i64);
+            let message = DirectMessage {
+                id: Uuid::new_v4(),
+                room_id: room.id,
+                from: me,
+                to: peer_id,
+                content: format!("dm {}", i),
+                timestamp,
+                hlc: crate::clock::HlcTimestamp::from_wall(timestamp),
+            };
+            store.save_direct_message(peer_id, &message).unwrap();
+            // Saving twice, as on a redelivery, keeps one copy
+            store.save_direct_message(peer_id, &message).unwrap();
+        }
+
+        let with_alice = store.direct_messages(room.id, alice, 10).unwrap();
+        assert_eq!(with_alice.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), vec!["dm 0", "dm 2"]);
+        assert_eq!(store.direct_messages(room.id, bob, 10).unwrap().len(), 1);
+        assert_eq!(store.direct_messages(room.id, alice, 1).unwrap()[0].content, "dm 2");
+    }
+
+    #[test]
+    fn test_compaction_keeps_pinned_messages() {
+        let store = Store::open_in_memory().unwrap();
+        let mut room = test_room();
+        let user_id = room.creator_id;
+
+        for i in 0..10 {
+            room.add_message(ChatMessage::new(user_id, "User".to_string(), format!("message {}", i)));
+        }
+        room.messages[0].pinned = true;
+        room.retention = RetentionPolicy::MaxCount(3);
+        store.save_room(&mut room).unwrap();
+
+        assert_eq!(store.compact_all(chrono::Utc::now()).unwrap(), 6);
+        let loaded = store.load_room(room.id).unwrap();
+        let contents: Vec<&str> = loaded.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["message 0", "message 7", "message 8", "message 9"]);
+
+        // Compacted history offered back by a peer stays out
+        let mut loaded = loaded;
+        let stale = room.messages[3].clone();
+        assert!(loaded.is_past_horizon(&stale));
+        assert_eq!(loaded.merge_history(vec![stale], Vec::new()), 0);
+    }
+
+    #[test]
+    fn test_legacy_room_files_are_imported_once() {
+        let dir = std::env::temp_dir().join(format!("shortgap-import-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut room = test_room();
+        room.add_message(ChatMessage::new(room.creator_id, "User".to_string(), "old".to_string()));
+        let legacy_path = dir.join(format!("{}.json", room.id));
+        std::fs::write(&legacy_path, serde_json::to_string_pretty(&room).unwrap()).unwrap();
+
+        let store = Store::open_in_memory().unwrap();
+        assert_eq!(store.import_legacy_rooms_from(&dir).unwrap(), 1);
+        assert!(!legacy_path.exists());
+        assert_eq!(store.load_room(room.id).unwrap().messages[0].content, "old");
+
+        // Already imported: a second run doesn't scan again
+        std::fs::write(&legacy_path, serde_json::to_string_pretty(&room).unwrap()).unwrap();
+        assert_eq!(store.import_legacy_rooms_from(&dir).unwrap(), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use base64::{Engine as _, engine::general_purpose};
+use ring::digest;
+use uuid::Uuid;
+use crate::receipts::Receipts;
+use crate::retention::RetentionPolicy;
+use crate::room::{ChatMessage, MessageTombstone, Room};
+
+/// Width of the time buckets history summaries are grouped into.
+const BUCKET_SECONDS: i64 = 3600;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BucketDigest {
+    pub count: usize,
+    pub hash: String,
+}
+
+/// Compact description of a room's history: one digest per hour of
+/// messages, covering ids and edit/delete state. Also advertises how much
+/// history the sender keeps, since it won't hold anything past that.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncSummary {
+    pub room_id: Uuid,
+    pub buckets: BTreeMap<i64, BucketDigest>,
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    #[serde(default)]
+    pub horizon: Option<i64>,
+}
+
+/// Payload of `MessageType::RoomSync`.
+///
+/// A peer broadcasts its `Summary`; anyone whose digests differ answers
+/// with a `Request` for those buckets listing what they already hold, and
+/// the summarizing peer replies with only the missing or newer messages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SyncMessage {
+    Summary(SyncSummary),
+    Request {
+        room_id: Uuid,
+        buckets: Vec<i64>,
+        known: HashMap<Uuid, i64>,
+    },
+    Messages {
+        room_id: Uuid,
+        messages: Vec<ChatMessage>,
+        tombstones: Vec<MessageTombstone>,
+        /// Receipts for every message in the requested buckets, whether or
+        /// not the message itself had to be sent
+        #[serde(default)]
+        receipts: Receipts,
+    },
+}
+
+impl SyncMessage {
+    pub fn room_id(&self) -> Uuid {
+        match self {
+            SyncMessage::Summary(summary) => summary.room_id,
+            SyncMessage::Request { room_id, .. } => *room_id,
+            SyncMessage::Messages { room_id, .. } => *room_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistorySynced {
+    pub room_id: Uuid,
+    pub merged: usize,
+}
+
+fn bucket_of(message: &ChatMessage) -> i64 {
+    let (hlc, _) = message.order_key();
+    hlc.physical.div_euclid(BUCKET_SECONDS * 1000)
+}
+
+pub fn summarize(room: &Room) -> SyncSummary {
+    let mut grouped: BTreeMap<i64, Vec<(Uuid, i64)>> = BTreeMap::new();
+    // Events are local to each peer, so they're left out of the comparison
+    for message in room.messages.iter().filter(|m| !m.is_event()) {
+        grouped.entry(bucket_of(message)).or_default().push((message.id, message.version()));
+    }
+
+    let buckets = grouped
+        .into_iter()
+        .map(|(bucket, mut entries)| {
+            entries.sort();
+            let mut context = digest::Context::new(&digest::SHA256);
+            for (id, version) in &entries {
+                context.update(id.as_bytes());
+                context.update(&version.to_be_bytes());
+            }
+
+            let digest = BucketDigest {
+                count: entries.len(),
+                hash: general_purpose::URL_SAFE_NO_PAD.encode(context.finish().as_ref()),
+            };
+            (bucket, digest)
+        })
+        .collect();
+
+    SyncSummary {
+        room_id: room.id,
+        buckets,
+        retention: room.retention,
+        horizon: room.retention_horizon,
+    }
+}
+
+/// Builds a request for every bucket where the remote summary has something
+/// we don't. Buckets only we have are picked up when the remote peer
+/// compares our summary against theirs. Buckets our own retention has
+/// already dropped, or would drop, are left alone so peers keeping more
+/// history don't keep handing it back.
+pub fn request_missing(room: &Room, remote: &SyncSummary) -> Option<SyncMessage> {
+    let local = summarize(room);
+    let bucket_ms = BUCKET_SECONDS * 1000;
+    let oldest_wanted = room.retention_horizon
+        .max(room.retention.age_cutoff(chrono::Utc::now()))
+        .map(|ms| ms.div_euclid(bucket_ms));
+
+    let buckets: Vec<i64> = remote.buckets
+        .iter()
+        .filter(|(bucket, _)| oldest_wanted.is_none_or(|oldest| **bucket > oldest))
+        .filter(|(bucket, digest)| local.buckets.get(bucket) != Some(digest))
+        .map(|(bucket, _)| *bucket)
+        .collect();
+
+    if buckets.is_empty() {
+        return None;
+    }
+
+    let known = room.messages
+        .iter()
+        .filter(|message| !message.is_event() && buckets.contains(&bucket_of(message)))
+        .map(|message| (message.id, message.version()))
+        .collect();
+
+    Some(SyncMessage::Request { room_id: room.id, buckets, known })
+}
+
+pub fn answer_request(room: &Room, buckets: &[i64], known: &HashMap<Uuid, i64>) -> SyncMessage {
+    let in_buckets = |message: &&ChatMessage| !message.is_event() && buckets.contains(&bucket_of(message));
+
+    let messages: Vec<ChatMessage> = room.messages
+        .iter()
+        .filter(in_buckets)
+        .filter(|message| match known.get(&message.id) {
+            Some(version) => *version < message.version(),
+            None => true,
+        })
+        .cloned()
+        .collect();
+
+    let tombstones = room.messages
+        .iter()
+        .filter(in_buckets)
+        .filter_map(|message| room.tombstones.get(&message.id))
+        .filter(|tombstone| known.get(&tombstone.message_id) != Some(&i64::MAX))
+        .cloned()
+        .collect();
+
+    let receipts = room.receipts.for_messages(room.messages.iter().filter(in_buckets).map(|m| &m.id));
+
+    SyncMessage::Messages { room_id: room.id, messages, tombstones, receipts }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use crate::networking::Protocol;
+    use crate::user::User;
+
+    fn exchange(from: &Room, sender_id: Uuid, to: &mut Room) -> usize {
+        let request = match request_missing(to, &summarize(from)) {
+            Some(request) => request,
+            None => return 0,
+        };
+        let (buckets, known) = match request {
+            SyncMessage::Request { buckets, known, .. } => (buckets, known),
+            other => panic!("Expected a request, got {:?}", other),
+        };
+        match answer_request(from, &buckets, &known) {
+            SyncMessage::Messages { messages, tombstones, .. } => to.merge_synced(sender_id, messages, tombstones),
+            other => panic!("Expected messages, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_history_sync_converges() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+
+        let alice = User::new("Alice".to_string(), addr1);
+        let bob = User::new("Bob".to_string(), addr2);
+        let alice_id = alice.id;
+        let bob_id = bob.id;
+
+        let mut peer_a = Room::new("Test Room".to_string(), alice, Protocol::TCP);
+        peer_a.add_user(bob).unwrap();
+        let mut peer_b = peer_a.clone();
+
+        // Each side sees some messages the other missed
+        let shared = ChatMessage::new(alice_id, "Alice".to_string(), "both".to_string());
+        peer_a.add_message(shared.clone());
+        peer_b.add_message(shared.clone());
+        peer_a.add_message(ChatMessage::new(alice_id, "Alice".to_string(), "only a".to_string()));
+        peer_b.add_message(ChatMessage::new(bob_id, "Bob".to_string(), "only b".to_string()));
+
+        // A deletion on one side must win over the stale copy on the other
+        peer_a.delete_message(MessageTombstone {
+            room_id: peer_a.id,
+            message_id: shared.id,
+            deleted_by: alice_id,
+            deleted_at: chrono::Utc::now(),
+        }).unwrap();
+
+        assert_eq!(exchange(&peer_a, alice_id, &mut peer_b), 2);
+        assert_eq!(exchange(&peer_b, bob_id, &mut peer_a), 1);
+
+        let ids = |room: &Room| room.messages.iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(ids(&peer_a), ids(&peer_b));
+        assert!(peer_b.messages.iter().find(|m| m.id == shared.id).unwrap().is_deleted());
+
+        // Once in step, summaries match and nothing more is requested, even
+        // when each side has recorded its own events
+        peer_a.record_event(crate::event::RoomEvent::CallEnded);
+        assert!(request_missing(&peer_a, &summarize(&peer_b)).is_none());
+        assert!(request_missing(&peer_b, &summarize(&peer_a)).is_none());
+    }
+
+    #[test]
+    fn test_members_only_sync_their_own_history() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+
+        let alice = User::new("Alice".to_string(), addr1);
+        let mallory = User::new("Mallory".to_string(), addr2);
+        let alice_id = alice.id;
+        let mallory_id = mallory.id;
+
+        let mut room = Room::new("Test Room".to_string(), alice, Protocol::TCP);
+        room.add_user(mallory).unwrap();
+        let original = ChatMessage::new(alice_id, "Alice".to_string(), "see you at 8".to_string());
+        room.add_message(original.clone());
+
+        // A message put in Alice's name, a newer version of her text and
+        // a deletion signed off as her are all turned away
+        let impersonated = ChatMessage::new(alice_id, "Alice".to_string(), "I quit".to_string());
+        let mut rewritten = original.clone();
+        rewritten.content = "see you never".to_string();
+        rewritten.edited_at = Some(chrono::Utc::now());
+        let tombstone = MessageTombstone {
+            room_id: room.id,
+            message_id: original.id,
+            deleted_by: alice_id,
+            deleted_at: chrono::Utc::now(),
+        };
+        assert_eq!(room.merge_synced(mallory_id, vec![impersonated, rewritten.clone()], vec![tombstone.clone()]), 0);
+        assert_eq!(room.messages.len(), 1);
+        assert_eq!(room.messages[0].content, "see you at 8");
+        assert!(!room.messages[0].is_deleted());
+
+        // Mallory's own messages still come through
+        let own = ChatMessage::new(mallory_id, "Mallory".to_string(), "hi".to_string());
+        assert_eq!(room.merge_synced(mallory_id, vec![own], Vec::new()), 1);
+
+        // Alice, who manages messages, may pass on her own changes
+        assert_eq!(room.merge_synced(alice_id, vec![rewritten], vec![tombstone]), 1);
+        assert!(room.messages.iter().find(|m| m.id == original.id).unwrap().is_deleted());
+    }
+}
@@ -0,0 +1,421 @@
+use serde::{Deserialize, Serialize};
+use base64::{Engine as _, engine::general_purpose};
+use ring::digest::{Context, SHA256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use anyhow::Result;
+use crate::media::{self, ImagePreview};
+
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Rooms without a limit of their own accept files up to this size.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Chunks asked for at once from the peer offering a file.
+pub const PULL_WINDOW: usize = 8;
+
+/// Requests unanswered for this long are sent again, which is also how a
+/// download picks up after the sender or the host drops out for a while.
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a file offer carries: enough to fetch the file in chunks and check
+/// each one, and the whole, on arrival. Sent attached to a chat message,
+/// whose author is the peer serving the chunks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileManifest {
+    pub id: Uuid,
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    pub chunk_size: u64,
+    /// SHA-256 of each chunk, in order
+    pub chunks: Vec<String>,
+    #[serde(default)]
+    pub image: Option<ImagePreview>,
+}
+
+/// File transfer traffic between two members. Both kinds are addressed to
+/// one peer and relayed by the host when there is no direct connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TransferMessage {
+    Request {
+        room_id: Uuid,
+        file_id: Uuid,
+        index: u32,
+        requested_by: Uuid,
+    },
+    Chunk {
+        room_id: Uuid,
+        file_id: Uuid,
+        index: u32,
+        requested_by: Uuid,
+        data: String,
+    },
+}
+
+impl TransferMessage {
+    pub fn room_id(&self) -> Uuid {
+        match self {
+            TransferMessage::Request { room_id, .. } | TransferMessage::Chunk { room_id, .. } => *room_id,
+        }
+    }
+}
+
+/// Payload of the "file-progress" event.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferProgress {
+    pub room_id: Uuid,
+    pub file_id: Uuid,
+    pub name: String,
+    pub received_bytes: u64,
+    pub size: u64,
+    pub complete: bool,
+    pub path: Option<PathBuf>,
+    pub error: Option<String>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sha256(data: &[u8]) -> String {
+    to_hex(ring::digest::digest(&SHA256, data).as_ref())
+}
+
+impl FileManifest {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let name = path.file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Not a file: {}", path.display()))?
+            .to_string();
+        let mut file = File::open(path)?;
+
+        let mut whole = Context::new(&SHA256);
+        let mut chunks = Vec::new();
+        let mut size = 0;
+        let mut buffer = vec![0u8; CHUNK_SIZE as usize];
+        loop {
+            let read = read_full(&mut file, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            whole.update(&buffer[..read]);
+            chunks.push(sha256(&buffer[..read]));
+            size += read as u64;
+        }
+
+        Ok(Self {
+            id: Uuid::new_v4(),
+            name,
+            size,
+            sha256: to_hex(whole.finish().as_ref()),
+            chunk_size: CHUNK_SIZE,
+            chunks,
+            image: None,
+        })
+    }
+
+    /// Checks an offer from a peer before we fetch anything for it.
+    pub fn validate(&self, max_size: u64) -> Result<()> {
+        if self.size > max_size {
+            return Err(anyhow::anyhow!("{} is larger than this room allows ({} bytes)", self.name, max_size));
+        }
+        if self.chunk_size == 0 || self.chunk_size > 4 * CHUNK_SIZE {
+            return Err(anyhow::anyhow!("Invalid chunk size"));
+        }
+        if self.chunks.len() as u64 != self.size.div_ceil(self.chunk_size) {
+            return Err(anyhow::anyhow!("Chunk list doesn't match the file size"));
+        }
+        if self.safe_name().is_none() {
+            return Err(anyhow::anyhow!("Invalid file name"));
+        }
+        if let Some(image) = &self.image {
+            image.validate(&self.name)?;
+        }
+        Ok(())
+    }
+
+    /// The offered name with any directories stripped, so a download can't
+    /// land outside the downloads directory.
+    pub fn safe_name(&self) -> Option<&str> {
+        let name = Path::new(&self.name).file_name()?.to_str()?;
+        (!name.starts_with('.')).then_some(name)
+    }
+
+    fn chunk_range(&self, index: u32) -> Option<(u64, usize)> {
+        if index as usize >= self.chunks.len() {
+            return None;
+        }
+        let offset = index as u64 * self.chunk_size;
+        Some((offset, (self.size - offset).min(self.chunk_size) as usize))
+    }
+}
+
+fn read_full(file: &mut File, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// Reads one chunk of a file we're offering, refusing to serve it if the
+/// file has changed since the offer.
+pub fn read_chunk(path: &Path, manifest: &FileManifest, index: u32) -> Result<Vec<u8>> {
+    let (offset, len) = manifest.chunk_range(index)
+        .ok_or_else(|| anyhow::anyhow!("No chunk {} in {}", index, manifest.name))?;
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = vec![0u8; len];
+    file.read_exact(&mut data)?;
+
+    if sha256(&data) != manifest.chunks[index as usize] {
+        return Err(anyhow::anyhow!("{} has changed since it was shared", path.display()));
+    }
+    Ok(data)
+}
+
+pub fn encode_chunk(data: &[u8]) -> String {
+    general_purpose::STANDARD.encode(data)
+}
+
+/// A file being fetched. Chunks are written straight into a partial file
+/// next to where it will end up; starting again over an existing partial
+/// file keeps every chunk that still checks out.
+#[derive(Debug)]
+pub struct Download {
+    pub manifest: FileManifest,
+    pub room_id: Uuid,
+    /// Member serving the chunks
+    pub source: Uuid,
+    part_path: PathBuf,
+    received: Vec<bool>,
+    in_flight: HashMap<u32, Instant>,
+}
+
+impl Download {
+    pub fn start(room_id: Uuid, source: Uuid, manifest: FileManifest, dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let part_path = dir.join(format!(".{}.part", manifest.id));
+        let mut received = vec![false; manifest.chunks.len()];
+
+        if part_path.exists() {
+            let mut file = File::open(&part_path)?;
+            let mut buffer = vec![0u8; manifest.chunk_size as usize];
+            for (index, done) in received.iter_mut().enumerate() {
+                let (offset, len) = manifest.chunk_range(index as u32).unwrap_or_default();
+                file.seek(SeekFrom::Start(offset))?;
+                if read_full(&mut file, &mut buffer[..len])? == len {
+                    *done = sha256(&buffer[..len]) == manifest.chunks[index];
+                }
+            }
+        } else {
+            File::create(&part_path)?.set_len(manifest.size)?;
+        }
+
+        Ok(Self { manifest, room_id, source, part_path, received, in_flight: HashMap::new() })
+    }
+
+    /// Chunks to ask for now: anything missing that isn't already
+    /// requested, up to the window, with timed-out requests counted as
+    /// not requested.
+    pub fn next_requests(&mut self, now: Instant) -> Vec<u32> {
+        self.in_flight.retain(|_, sent_at| now.duration_since(*sent_at) < CHUNK_TIMEOUT);
+
+        let mut requests = Vec::new();
+        for (index, done) in self.received.iter().enumerate() {
+            if self.in_flight.len() >= PULL_WINDOW {
+                break;
+            }
+            let index = index as u32;
+            if !done && !self.in_flight.contains_key(&index) {
+                self.in_flight.insert(index, now);
+                requests.push(index);
+            }
+        }
+        requests
+    }
+
+    /// Checks a chunk against the manifest and writes it into place.
+    /// Returns false for chunks we already have.
+    pub fn accept_chunk(&mut self, index: u32, data: &str) -> Result<bool> {
+        let (offset, len) = self.manifest.chunk_range(index)
+            .ok_or_else(|| anyhow::anyhow!("No chunk {} in {}", index, self.manifest.name))?;
+        if self.received[index as usize] {
+            return Ok(false);
+        }
+        let data = general_purpose::STANDARD.decode(data)?;
+        if data.len() != len || sha256(&data) != self.manifest.chunks[index as usize] {
+            self.in_flight.remove(&index);
+            return Err(anyhow::anyhow!("Chunk {} of {} failed its integrity check", index, self.manifest.name));
+        }
+
+        let mut file = OpenOptions::new().write(true).open(&self.part_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&data)?;
+
+        self.received[index as usize] = true;
+        self.in_flight.remove(&index);
+        Ok(true)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received.iter().all(|done| *done)
+    }
+
+    pub fn received_bytes(&self) -> u64 {
+        self.received.iter().enumerate()
+            .filter(|(_, done)| **done)
+            .filter_map(|(index, _)| self.manifest.chunk_range(index as u32))
+            .map(|(_, len)| len as u64)
+            .sum()
+    }
+
+    pub fn progress(&self) -> TransferProgress {
+        TransferProgress {
+            room_id: self.room_id,
+            file_id: self.manifest.id,
+            name: self.manifest.name.clone(),
+            received_bytes: self.received_bytes(),
+            size: self.manifest.size,
+            complete: false,
+            path: None,
+            error: None,
+        }
+    }
+
+    /// Checks the whole file and moves it to its final name, which gets a
+    /// number added if something by that name is already there.
+    pub fn finish(self) -> Result<PathBuf> {
+        let mut file = File::open(&self.part_path)?;
+        let mut whole = Context::new(&SHA256);
+        let mut buffer = vec![0u8; CHUNK_SIZE as usize];
+        loop {
+            let read = read_full(&mut file, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            whole.update(&buffer[..read]);
+        }
+        if to_hex(whole.finish().as_ref()) != self.manifest.sha256 {
+            std::fs::remove_file(&self.part_path)?;
+            return Err(anyhow::anyhow!("{} failed its integrity check", self.manifest.name));
+        }
+        if let Some(image) = &self.manifest.image {
+            media::verify_kind(&self.part_path, image)?;
+        }
+
+        let name = self.manifest.safe_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid file name"))?;
+        let dir = self.part_path.parent().unwrap_or(Path::new("."));
+        let path = unique_path(dir, name);
+        std::fs::rename(&self.part_path, &path)?;
+        Ok(path)
+    }
+
+    /// Stops the download and throws away what we have of it.
+    pub fn cancel(self) -> Result<()> {
+        if self.part_path.exists() {
+            std::fs::remove_file(&self.part_path)?;
+        }
+        Ok(())
+    }
+}
+
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let stem = Path::new(name).file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+    let extension = Path::new(name).extension().and_then(|e| e.to_str());
+    (2..)
+        .map(|n| match extension {
+            Some(extension) => dir.join(format!("{} ({}).{}", stem, n, extension)),
+            None => dir.join(format!("{} ({})", stem, n)),
+        })
+        .find(|path| !path.exists())
+        .unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(label: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("shortgap-{}-{}", label, Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_chunked_transfer_checks_and_resumes() {
+        let source_dir = temp_dir("share");
+        let download_dir = temp_dir("downloads");
+        let source = source_dir.join("map.bin");
+        let content: Vec<u8> = (0..(CHUNK_SIZE * 2 + 100)).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &content).unwrap();
+
+        let manifest = FileManifest::from_file(&source).unwrap();
+        assert_eq!(manifest.chunks.len(), 3);
+        manifest.validate(DEFAULT_MAX_FILE_SIZE).unwrap();
+        assert!(manifest.validate(CHUNK_SIZE).is_err());
+
+        let (room_id, sender) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut download = Download::start(room_id, sender, manifest.clone(), &download_dir).unwrap();
+        let now = Instant::now();
+        assert_eq!(download.next_requests(now), vec![0, 1, 2]);
+        assert!(download.next_requests(now).is_empty());
+
+        // A corrupted chunk is turned away and asked for again
+        assert!(download.accept_chunk(0, &encode_chunk(&[0u8; 10])).is_err());
+        assert_eq!(download.next_requests(now), vec![0]);
+
+        let chunk = |index| encode_chunk(&read_chunk(&source, &manifest, index).unwrap());
+        assert!(download.accept_chunk(0, &chunk(0)).unwrap());
+        assert!(!download.accept_chunk(0, &chunk(0)).unwrap());
+        assert_eq!(download.received_bytes(), CHUNK_SIZE);
+
+        // Picking up again later keeps what already arrived, and re-asks
+        // for requests that timed out
+        drop(download);
+        let mut download = Download::start(room_id, sender, manifest.clone(), &download_dir).unwrap();
+        assert_eq!(download.next_requests(now), vec![1, 2]);
+        assert!(download.next_requests(now + CHUNK_TIMEOUT).contains(&1));
+        download.accept_chunk(1, &chunk(1)).unwrap();
+        download.accept_chunk(2, &chunk(2)).unwrap();
+        assert!(download.is_complete());
+
+        std::fs::write(download_dir.join("map.bin"), b"already here").unwrap();
+        let path = download.finish().unwrap();
+        assert_eq!(path, download_dir.join("map (2).bin"));
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+
+        let _ = std::fs::remove_dir_all(source_dir);
+        let _ = std::fs::remove_dir_all(download_dir);
+    }
+
+    #[test]
+    fn test_offered_names_stay_in_the_downloads_directory() {
+        let mut manifest = FileManifest {
+            id: Uuid::new_v4(),
+            name: "../../.bashrc".to_string(),
+            size: 0,
+            sha256: sha256(&[]),
+            chunk_size: CHUNK_SIZE,
+            chunks: Vec::new(),
+            image: None,
+        };
+        assert!(manifest.validate(DEFAULT_MAX_FILE_SIZE).is_err());
+        manifest.name = "../notes.txt".to_string();
+        assert_eq!(manifest.safe_name(), Some("notes.txt"));
+    }
+}
@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// While someone keeps typing we repeat the signal at most this often.
+pub const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+
+/// Receivers forget a typist they haven't heard from in this long, so a
+/// lost "stopped typing" or a crashed peer doesn't leave them stuck.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// "User is typing" signal. Only ever sent live; never stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingNotice {
+    pub room_id: Uuid,
+    pub channel_id: Uuid,
+    pub user_id: Uuid,
+    pub typing: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Typist {
+    pub user_id: Uuid,
+    pub name: String,
+    pub channel_id: Uuid,
+}
+
+/// Payload of the "typing-changed" event.
+#[derive(Debug, Clone, Serialize)]
+pub struct TypingChanged {
+    pub room_id: Uuid,
+    pub typing: Vec<Typist>,
+}
+
+/// Typing state of one party, kept in its session rather than the room so
+/// it never ends up on disk.
+#[derive(Debug, Default)]
+pub struct TypingTracker {
+    typists: HashMap<Uuid, (Uuid, Instant)>,
+    last_sent: Option<(Uuid, Instant)>,
+}
+
+impl TypingTracker {
+    /// Whether our own typing state should go out now: starting is
+    /// throttled per channel, stopping is sent once if we'd said we were
+    /// typing.
+    pub fn should_send(&mut self, channel_id: Uuid, typing: bool, now: Instant) -> bool {
+        if !typing {
+            return self.last_sent.take().is_some();
+        }
+        if let Some((last_channel, sent_at)) = self.last_sent {
+            if last_channel == channel_id && now.duration_since(sent_at) < TYPING_THROTTLE {
+                return false;
+            }
+        }
+        self.last_sent = Some((channel_id, now));
+        true
+    }
+
+    /// Forget that we said we were typing, e.g. once the message is sent.
+    pub fn reset_sent(&mut self) {
+        self.last_sent = None;
+    }
+
+    /// Applies a notice from a peer. Returns whether the list changed.
+    pub fn apply(&mut self, notice: &TypingNotice, now: Instant) -> bool {
+        if notice.typing {
+            let previous = self.typists.insert(notice.user_id, (notice.channel_id, now));
+            previous.map(|(channel_id, _)| channel_id) != Some(notice.channel_id)
+        } else {
+            self.typists.remove(&notice.user_id).is_some()
+        }
+    }
+
+    pub fn clear(&mut self, user_id: Uuid) -> bool {
+        self.typists.remove(&user_id).is_some()
+    }
+
+    /// Drops typists that have gone quiet. Returns whether any did.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let before = self.typists.len();
+        self.typists.retain(|_, (_, seen)| now.duration_since(*seen) < TYPING_TIMEOUT);
+        self.typists.len() != before
+    }
+
+    pub fn typists(&self) -> impl Iterator<Item = (Uuid, Uuid)> + '_ {
+        self.typists.iter().map(|(user_id, (channel_id, _))| (*user_id, *channel_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typing_is_throttled_and_expires() {
+        let start = Instant::now();
+        let channel_id = Uuid::new_v4();
+        let mut ours = TypingTracker::default();
+
+        assert!(ours.should_send(channel_id, true, start));
+        assert!(!ours.should_send(channel_id, true, start + Duration::from_secs(1)));
+        assert!(ours.should_send(Uuid::nil(), true, start + Duration::from_secs(1)));
+        assert!(ours.should_send(Uuid::nil(), true, start + TYPING_THROTTLE + Duration::from_secs(1)));
+        assert!(ours.should_send(Uuid::nil(), false, start + Duration::from_secs(5)));
+        assert!(!ours.should_send(Uuid::nil(), false, start + Duration::from_secs(5)));
+
+        let mut theirs = TypingTracker::default();
+        let notice = TypingNotice { room_id: Uuid::new_v4(), channel_id, user_id: Uuid::new_v4(), typing: true };
+        assert!(theirs.apply(&notice, start));
+        assert!(!theirs.apply(&notice, start + Duration::from_secs(3)));
+        assert!(!theirs.expire(start + TYPING_TIMEOUT));
+        assert!(theirs.expire(start + Duration::from_secs(3) + TYPING_TIMEOUT));
+        assert_eq!(theirs.typists().count(), 0);
+    }
+}
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct UnreadCount {
+    pub unread: usize,
+    pub mentions: usize,
+}
+
+impl std::ops::AddAssign for UnreadCount {
+    fn add_assign(&mut self, other: Self) {
+        self.unread += other.unread;
+        self.mentions += other.mentions;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelUnread {
+    pub channel_id: Uuid,
+    #[serde(flatten)]
+    pub count: UnreadCount,
+    /// Last message we've read in the channel
+    pub last_read: Option<Uuid>,
+}
+
+/// Where we left off in a room, as listed to the UI and sent with the
+/// "unread-changed" event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomUnread {
+    pub room_id: Uuid,
+    pub room_name: String,
+    #[serde(flatten)]
+    pub total: UnreadCount,
+    pub channels: Vec<ChannelUnread>,
+}
@@ -13,6 +13,14 @@ pub struct User {
     pub is_online: bool,
     pub last_seen: chrono::DateTime<chrono::Utc>,
     pub is_in_call: bool,
+    #[serde(default)]
+    pub identity_key: Option<String>,
+    /// Key direct messages to this user are encrypted to, signed with
+    /// their identity key
+    #[serde(default)]
+    pub dm_key: Option<String>,
+    #[serde(default)]
+    pub dm_key_signature: Option<String>,
 }
 
 impl User {
@@ -30,6 +38,9 @@ impl User {
             is_online: true,
             last_seen: chrono::Utc::now(),
             is_in_call: false,
+            identity_key: None,
+            dm_key: None,
+            dm_key_signature: None,
         }
     }
 